@@ -0,0 +1,301 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! Archives holding the chunks of a vault, to move them to a new machine without fetching them
+//! from the network again.
+//!
+//! An archive starts with `ARCHIVE_MAGIC` and the format version byte.  Each chunk follows as the
+//! length of its serialised `ArchiveEntry` (as a big-endian `u32`) and the entry itself.  A length
+//! of 0 marks the end of the archive, so that a truncated archive can be detected.
+
+use chunk_store::{ChunkStorage, ChunkStore};
+use config_handler::{self, StorageBackend};
+use error::InternalError;
+use maidsafe_utilities::serialisation;
+use personas::data_manager::{self, DataManager};
+use routing::{Data, DataIdentifier, XorName};
+use rust_sodium;
+use rust_sodium::crypto::hash::sha256;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use vault;
+
+/// The bytes every archive starts with.
+const ARCHIVE_MAGIC: &'static [u8] = b"SAFE_VAULT_CHUNKS";
+/// The version of the archive format.
+const ARCHIVE_VERSION: u8 = 1;
+/// The maximum length of a serialised entry.  Routing limits chunks to about 1 MiB, so longer
+/// entries can only come from a damaged archive.
+const MAX_ENTRY_LEN: usize = 2 * 1024 * 1024;
+
+/// A chunk in an archive.
+#[derive(RustcEncodable, RustcDecodable)]
+struct ArchiveEntry {
+    data_id: DataIdentifier,
+    version: u64,
+    data: Data,
+}
+
+/// The outcome of exporting or importing an archive.
+#[derive(Clone, Debug, Default)]
+pub struct ArchiveReport {
+    /// The number of chunks written to the archive or the chunk store.
+    pub chunks: u64,
+    /// The chunks which were skipped, with the reason.
+    pub rejected: Vec<(DataIdentifier, String)>,
+}
+
+/// Writes all chunks of the chunk stores configured in the vault config file to a new archive at
+/// `archive_path`.  The stores are opened read-only, so this fails if the vault is running.
+pub fn export_chunk_stores(archive_path: &Path) -> Result<ArchiveReport, InternalError> {
+    rust_sodium::init();
+    let config = config_handler::read_config_file()?;
+    let mut writer = BufWriter::new(OpenOptions::new().write(true)
+        .create_new(true)
+        .open(archive_path)?);
+    writer.write_all(ARCHIVE_MAGIC)?;
+    writer.write_all(&[ARCHIVE_VERSION])?;
+
+    let mut report = ArchiveReport::default();
+    for (root, capacity) in vault::all_chunk_store_roots(&config) {
+        let chunk_store: ChunkStore<DataIdentifier, Data> =
+            DataManager::chunk_store_builder(&config, root, capacity)?
+                .persistent(true)
+                .read_only(true)
+                .create()?;
+        write_entries(&mut writer, &chunk_store, &mut report)?;
+    }
+    write_u32(&mut writer, 0)?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    Ok(report)
+}
+
+/// Writes the chunks of `chunk_store` to the archive, recording those which can't be read as
+/// rejected.
+fn write_entries<W: Write>(writer: &mut W,
+                           chunk_store: &ChunkStorage<DataIdentifier, Data>,
+                           report: &mut ArchiveReport)
+                           -> Result<(), InternalError> {
+    for data_id in chunk_store.iter_keys() {
+        let data = match chunk_store.get(&data_id) {
+            Ok(data) => data,
+            Err(error) => {
+                report.rejected.push((data_id, format!("{}", error)));
+                continue;
+            }
+        };
+        let entry = ArchiveEntry {
+            data_id: data_id,
+            version: data_manager::id_and_version_of(&data).1,
+            data: data,
+        };
+        let serialised_entry = serialisation::serialise(&entry)?;
+        write_u32(writer, serialised_entry.len() as u32)?;
+        writer.write_all(&serialised_entry)?;
+        report.chunks += 1;
+    }
+    Ok(())
+}
+
+/// Adds the chunks in the archive at `archive_path` to the chunk stores configured in the vault
+/// config file, which must be persistent, on disk and empty.  Chunks which don't match their
+/// identifier or version are rejected.  If the archive is invalid or a chunk can't be stored, the
+/// chunks imported so far are removed again, so that the import can be retried.
+pub fn import_chunk_stores(archive_path: &Path) -> Result<ArchiveReport, InternalError> {
+    rust_sodium::init();
+    let config = config_handler::read_config_file()?;
+    if !config.persistent_chunk_store.unwrap_or(false) {
+        return Err(InternalError::ChunkStoreNotPersistent);
+    }
+    if config.chunk_store_backend.unwrap_or(StorageBackend::Disk) != StorageBackend::Disk {
+        return Err(InternalError::ChunkStoreNotOnDisk);
+    }
+    let mut chunk_store = DataManager::create_chunk_store(&config,
+                                                          vault::chunk_store_roots(&config))?;
+    if chunk_store.iter_keys().next().is_some() {
+        return Err(InternalError::ChunkStoreNotEmpty);
+    }
+
+    let mut reader = BufReader::new(File::open(archive_path)?);
+    let mut imported = Vec::new();
+    let result = read_entries(&mut reader, &mut *chunk_store, &mut imported);
+    if result.is_err() {
+        for data_id in &imported {
+            if let Err(error) = chunk_store.delete(data_id) {
+                warn!("Failed to remove imported chunk {:?}: {}", data_id, error);
+            }
+        }
+    }
+    result
+}
+
+/// Reads the archive and stores its valid chunks in `chunk_store`, adding their identifiers to
+/// `imported`.
+fn read_entries<R: Read>(reader: &mut R,
+                         chunk_store: &mut ChunkStorage<DataIdentifier, Data>,
+                         imported: &mut Vec<DataIdentifier>)
+                         -> Result<ArchiveReport, InternalError> {
+    let mut header = vec![0; ARCHIVE_MAGIC.len() + 1];
+    read_exact(reader, &mut header)?;
+    if &header[..ARCHIVE_MAGIC.len()] != ARCHIVE_MAGIC ||
+       header[ARCHIVE_MAGIC.len()] != ARCHIVE_VERSION {
+        return Err(InternalError::InvalidArchive);
+    }
+
+    let mut report = ArchiveReport::default();
+    loop {
+        let mut len = [0; 4];
+        read_exact(reader, &mut len)?;
+        let len = len.iter().fold(0, |len, byte| (len << 8) | *byte as usize);
+        if len == 0 {
+            break;
+        }
+        if len > MAX_ENTRY_LEN {
+            return Err(InternalError::InvalidArchive);
+        }
+        let mut serialised_entry = vec![0; len];
+        read_exact(reader, &mut serialised_entry)?;
+        let entry = serialisation::deserialise::<ArchiveEntry>(&serialised_entry)
+            .map_err(|_| InternalError::InvalidArchive)?;
+        if let Err(reason) = check_entry(&entry) {
+            report.rejected.push((entry.data_id, reason.to_owned()));
+            continue;
+        }
+        chunk_store.put(&entry.data_id, &entry.data)?;
+        imported.push(entry.data_id);
+        report.chunks += 1;
+    }
+    Ok(report)
+}
+
+/// Checks that the data in the entry is valid and matches the entry's identifier and version.
+fn check_entry(entry: &ArchiveEntry) -> Result<(), &'static str> {
+    if entry.data.identifier() != entry.data_id {
+        return Err("identifier doesn't match the data");
+    }
+    if data_manager::id_and_version_of(&entry.data).1 != entry.version {
+        return Err("version doesn't match the data");
+    }
+    if !entry.data.validate_size() {
+        return Err("data exceeds size limit");
+    }
+    if let Data::Immutable(ref immutable_data) = entry.data {
+        if *immutable_data.name() != XorName(sha256::hash(immutable_data.value()).0) {
+            return Err("name doesn't match the content hash");
+        }
+    }
+    Ok(())
+}
+
+fn write_u32<W: Write>(writer: &mut W, value: u32) -> io::Result<()> {
+    writer.write_all(&[(value >> 24) as u8, (value >> 16) as u8, (value >> 8) as u8, value as u8])
+}
+
+/// Reads exactly `buffer.len()` bytes, treating a premature end of the archive as invalid.
+fn read_exact<R: Read>(reader: &mut R, buffer: &mut [u8]) -> Result<(), InternalError> {
+    reader.read_exact(buffer).map_err(|error| if error.kind() == io::ErrorKind::UnexpectedEof {
+        InternalError::InvalidArchive
+    } else {
+        InternalError::Io(error)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use chunk_store::{ChunkStorage, ChunkStore};
+    use error::InternalError;
+    use maidsafe_utilities::serialisation;
+    use routing::{Data, DataIdentifier, ImmutableData};
+    use super::{ARCHIVE_MAGIC, ARCHIVE_VERSION, ArchiveEntry, ArchiveReport, check_entry,
+                read_entries, write_entries, write_u32};
+    use tempdir::TempDir;
+
+    #[test]
+    fn check_entries() {
+        let data = Data::Immutable(ImmutableData::new("hello world".bytes().collect()));
+        let entry = ArchiveEntry {
+            data_id: data.identifier(),
+            version: 0,
+            data: data.clone(),
+        };
+        let serialised_entry = unwrap!(serialisation::serialise(&entry));
+        let entry = unwrap!(serialisation::deserialise::<ArchiveEntry>(&serialised_entry));
+        assert!(check_entry(&entry).is_ok());
+
+        let wrong_version = ArchiveEntry { version: 1, ..entry };
+        assert!(check_entry(&wrong_version).is_err());
+
+        let other_data = Data::Immutable(ImmutableData::new("hello".bytes().collect()));
+        let wrong_id = ArchiveEntry {
+            data_id: DataIdentifier::Immutable(*other_data.name()),
+            version: 0,
+            data: data,
+        };
+        assert!(check_entry(&wrong_id).is_err());
+    }
+
+    #[test]
+    fn export_and_import() {
+        let tempdir = unwrap!(TempDir::new("test"));
+        let mut source = unwrap!(ChunkStore::new(tempdir.path().join("source"), 1024 * 1024));
+        let chunks = (0..10)
+            .map(|index| Data::Immutable(ImmutableData::new(vec![index; 100])))
+            .collect::<Vec<_>>();
+        for data in &chunks {
+            unwrap!(source.put(&data.identifier(), data));
+        }
+        let mut archive = ARCHIVE_MAGIC.to_vec();
+        archive.push(ARCHIVE_VERSION);
+        let mut report = ArchiveReport::default();
+        unwrap!(write_entries(&mut archive, &source, &mut report));
+        unwrap!(write_u32(&mut archive, 0));
+        assert_eq!(report.chunks, 10);
+        assert!(report.rejected.is_empty());
+
+        // Importing into a fresh store gives the same chunks.
+        let mut dest: ChunkStore<DataIdentifier, Data> =
+            unwrap!(ChunkStore::new(tempdir.path().join("dest"), 1024 * 1024));
+        let mut imported = Vec::new();
+        let report = unwrap!(read_entries(&mut &archive[..], &mut dest, &mut imported));
+        assert_eq!(report.chunks, 10);
+        assert!(report.rejected.is_empty());
+        assert_eq!(imported.len(), 10);
+        for data in &chunks {
+            assert_eq!(*data, unwrap!(dest.get(&data.identifier())));
+        }
+
+        // Truncated archives and overlong entries are invalid.
+        let mut dest: ChunkStore<DataIdentifier, Data> =
+            unwrap!(ChunkStore::new(tempdir.path().join("truncated"), 1024 * 1024));
+        let mut imported = Vec::new();
+        match read_entries(&mut &archive[..archive.len() - 4], &mut dest, &mut imported) {
+            Err(InternalError::InvalidArchive) => (),
+            result => panic!("Unexpected result {:?}", result),
+        }
+        assert_eq!(imported.len(), 10);
+        let mut overlong_archive = archive[..ARCHIVE_MAGIC.len() + 1].to_vec();
+        unwrap!(write_u32(&mut overlong_archive, u32::max_value()));
+        let mut imported = Vec::new();
+        match read_entries(&mut &overlong_archive[..], &mut dest, &mut imported) {
+            Err(InternalError::InvalidArchive) => (),
+            result => panic!("Unexpected result {:?}", result),
+        }
+        assert!(imported.is_empty());
+    }
+}
@@ -57,21 +57,54 @@ use docopt::Docopt;
 use safe_vault::Vault;
 use std::ffi::OsString;
 use std::fs;
+use std::path::Path;
+use std::process;
 
 #[cfg_attr(rustfmt, rustfmt_skip)]
 static USAGE: &'static str = "
 Usage:
   safe_vault [options]
+  safe_vault (inspect | fsck) [--delete-invalid]
+  safe_vault export <archive>
+  safe_vault import <archive>
+  safe_vault snapshot <dir>
+  safe_vault restore <dir>
+
+Commands:
+  inspect, fsck   Check the chunk stores of a vault which isn't running, and
+                  report their contents.
+  export          Write all chunks of a vault which isn't running to a new
+                  archive file.
+  import          Add the chunks in an archive file to the empty, persistent
+                  on-disk chunk stores of a vault which isn't running.
+  snapshot        Hardlink the files of the chunk stores of a vault into a new
+                  snapshot directory.  A running vault is asked to write the
+                  snapshot itself, pausing its writes meanwhile.
+  restore         Replace the contents of the persistent chunk stores of a
+                  vault which isn't running with a snapshot.
 
 Options:
-  -f, --first     Run as the first Vault of a new network.
-  -V, --version   Display version info and exit.
-  -h, --help      Display this help message and exit.
+  -f, --first         Run as the first Vault of a new network.
+  --delete-invalid    Delete the chunks and files found to be unreadable.  This
+                      opens the chunk stores for writing, which first migrates
+                      them to the current format and directory layout, like
+                      starting the vault does.
+  -V, --version       Display version info and exit.
+  -h, --help          Display this help message and exit.
 ";
 
 #[derive(PartialEq, Eq, Debug, Clone, RustcDecodable)]
 struct Args {
+    cmd_inspect: bool,
+    cmd_fsck: bool,
+    cmd_export: bool,
+    cmd_import: bool,
+    cmd_snapshot: bool,
+    cmd_restore: bool,
+    arg_archive: String,
+    arg_dir: String,
     flag_first: bool,
+    flag_delete_invalid: bool,
     flag_version: bool,
     flag_help: bool,
 }
@@ -93,6 +126,19 @@ pub fn main() {
         return;
     }
 
+    if args.cmd_inspect || args.cmd_fsck {
+        inspect(args.flag_delete_invalid);
+        return;
+    }
+    if args.cmd_export || args.cmd_import {
+        transfer(args.cmd_export, Path::new(&args.arg_archive));
+        return;
+    }
+    if args.cmd_snapshot || args.cmd_restore {
+        snapshot(args.cmd_snapshot, Path::new(&args.arg_dir));
+        return;
+    }
+
     let _ = maidsafe_utilities::log::init(false);
 
     let mut message = String::from("Running ");
@@ -101,7 +147,7 @@ pub fn main() {
     info!("\n\n{}\n{}", message, underline);
 
     loop {
-        let mut vault = match Vault::new(args.flag_first, true) {
+        let mut vault = match Vault::new(args.flag_first) {
             Ok(vault) => vault,
             Err(e) => {
                 println!("Cannot start vault due to error : {:?}", e);
@@ -113,3 +159,74 @@ pub fn main() {
         }
     }
 }
+
+/// Prints the reports on all configured chunk stores.
+#[cfg_attr(feature="clippy", allow(print_stdout))]
+fn inspect(delete_invalid: bool) {
+    match safe_vault::inspect_chunk_stores(delete_invalid) {
+        Ok(reports) => {
+            for report in reports {
+                print!("{}", report);
+            }
+        }
+        Err(error) => {
+            println!("Cannot inspect chunk stores due to error : {:?}", error);
+            process::exit(1);
+        }
+    }
+}
+
+/// Exports the chunks to, or imports them from, the given archive and prints the outcome.
+#[cfg_attr(feature="clippy", allow(print_stdout))]
+fn transfer(export: bool, archive_path: &Path) {
+    let (result, action) = if export {
+        (safe_vault::export_chunk_stores(archive_path), "Exported")
+    } else {
+        (safe_vault::import_chunk_stores(archive_path), "Imported")
+    };
+    match result {
+        Ok(report) => {
+            println!("{} {} chunks, skipped {}.",
+                     action,
+                     report.chunks,
+                     report.rejected.len());
+            for (data_id, reason) in report.rejected {
+                println!("  {:?}: {}", data_id, reason);
+            }
+        }
+        Err(error) => {
+            println!("Cannot transfer chunks due to error : {:?}", error);
+            process::exit(1);
+        }
+    }
+}
+
+/// Writes a snapshot of the chunk stores to, or restores them from, the given directory and
+/// prints the outcome.
+#[cfg_attr(feature="clippy", allow(print_stdout))]
+fn snapshot(create: bool, dir: &Path) {
+    if create {
+        match safe_vault::snapshot_chunk_stores(dir) {
+            Ok(stats) => {
+                for (index, stats) in stats.iter().enumerate() {
+                    println!("Chunk store {}: {} files, {} bytes.",
+                             index,
+                             stats.files,
+                             stats.bytes);
+                }
+            }
+            Err(error) => {
+                println!("Cannot snapshot chunk stores due to error : {:?}", error);
+                process::exit(1);
+            }
+        }
+    } else {
+        match safe_vault::restore_chunk_stores(dir) {
+            Ok(chunks) => println!("Restored {} chunks.", chunks),
+            Err(error) => {
+                println!("Cannot restore chunk stores due to error : {:?}", error);
+                process::exit(1);
+            }
+        }
+    }
+}
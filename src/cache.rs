@@ -15,68 +15,633 @@
 // Please review the Licences for the specific language governing permissions and limitations
 // relating to use of the SAFE Network Software.
 
-use lru_time_cache::LruCache;
+use chunk_store::{ChunkStorage, ChunkStore, ChunkStoreBuilder};
+use chunk_store::Error as ChunkStoreError;
+use config_handler::Config;
+use error::InternalError;
+use personas::data_manager;
 use routing::{Data, DataIdentifier, ImmutableData, Request, Response, XorName};
 use routing::Cache as RoutingCache;
-use std::cell::RefCell;
-use std::time::Duration;
+use std::cell::{Cell, RefCell};
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 
-const CACHE_CAPACITY: usize = 1000;
-const CACHE_EXPIRY_DURATION_SECS: u64 = 60 * 60;
+/// The default limit for the total size of the cached chunks.
+const DEFAULT_CACHE_MAX_BYTES: u64 = 64 * 1024 * 1024;
+/// The default limit for the number of cached chunks.
+const DEFAULT_CACHE_MAX_ENTRIES: usize = 1000;
+/// The default time after which chunks which haven't been requested are dropped from the cache.
+const DEFAULT_CACHE_EXPIRY_SECS: u64 = 60 * 60;
+/// The default limit for the size of the cache directory.
+const DEFAULT_CACHE_DIR_MAX_BYTES: u64 = 1024 * 1024 * 1024;
+/// The default time for which `StructuredData` and appendable data are cached.
+const DEFAULT_CACHE_MUTABLE_EXPIRY_SECS: u64 = 10;
+/// The number of requests for an `ImmutableData` chunk needed before it is admitted to the cache.
+const ADMISSION_MIN_REQUESTS: u8 = 2;
+/// The number of rows of the frequency sketch.  Each row is indexed by a different part of the
+/// chunk name.
+const SKETCH_DEPTH: usize = 4;
+/// The limit for the number of counters in each row of the frequency sketch.
+const SKETCH_MAX_WIDTH: usize = 1 << 20;
+/// The value at which the counters of the frequency sketch saturate.
+const SKETCH_MAX_COUNT: u8 = 15;
+/// Interval in seconds between logging the cache statistics.
+const STATUS_LOG_INTERVAL: u64 = 120;
 
+/// Counters showing how effective the cache is, since it was created.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CacheStats {
+    /// The number of `Get` requests answered from the cache.
+    pub hits: u64,
+    /// The number of `Get` requests for cacheable data which couldn't be answered from the cache.
+    pub misses: u64,
+    /// The number of chunks added to the cache.
+    pub inserts: u64,
+    /// The number of chunks not added to the cache as they weren't requested often enough.
+    pub rejections: u64,
+    /// The number of chunks dropped from the cache to stay within its limits, or as they expired.
+    pub evictions: u64,
+    /// The total size of the `ImmutableData` chunks held in memory, in bytes.
+    pub bytes: u64,
+    /// The total size of the chunks held in the cache directory, in bytes.
+    pub disk_bytes: u64,
+}
+
+/// An approximation of the number of recent requests for each chunk, as used by TinyLFU: a
+/// count-min sketch whose counters are halved after a number of requests proportional to its
+/// width, so that chunks which were popular a long time ago don't keep a high count.
+struct FrequencySketch {
+    rows: Vec<Vec<u8>>,
+    requests: usize,
+    reset_after: usize,
+}
+
+impl FrequencySketch {
+    fn new(max_entries: usize) -> FrequencySketch {
+        let width = cmp::min(cmp::max(max_entries, 16), SKETCH_MAX_WIDTH).next_power_of_two();
+        FrequencySketch {
+            rows: vec![vec![0; width]; SKETCH_DEPTH],
+            requests: 0,
+            reset_after: 10 * width,
+        }
+    }
+
+    fn record(&mut self, name: &XorName) {
+        for (row, counters) in self.rows.iter_mut().enumerate() {
+            let index = sketch_index(name, row, counters.len());
+            let counter = &mut counters[index];
+            if *counter < SKETCH_MAX_COUNT {
+                *counter += 1;
+            }
+        }
+        self.requests += 1;
+        if self.requests >= self.reset_after {
+            for counters in &mut self.rows {
+                for counter in counters.iter_mut() {
+                    *counter /= 2;
+                }
+            }
+            self.requests /= 2;
+        }
+    }
+
+    fn estimate(&self, name: &XorName) -> u8 {
+        self.rows
+            .iter()
+            .enumerate()
+            .map(|(row, counters)| counters[sketch_index(name, row, counters.len())])
+            .min()
+            .unwrap_or(0)
+    }
+}
+
+/// Returns the index of the counter for the chunk in the given row of a frequency sketch, whose
+/// width is a power of two.  Chunk names are uniformly distributed, so their bytes are used
+/// directly.
+fn sketch_index(name: &XorName, row: usize, width: usize) -> usize {
+    let bytes = &name.0[row * 4..row * 4 + 4];
+    bytes.iter().fold(0, |index, byte| (index << 8) | *byte as usize) & (width - 1)
+}
+
+/// The order in which cached chunks were last used.
+#[derive(Default)]
+struct LruOrder {
+    /// The names by the sequence number of their last use, so the least recently used one comes
+    /// first.
+    names: BTreeMap<u64, XorName>,
+    sequences: HashMap<XorName, u64>,
+    next_sequence: u64,
+}
+
+impl LruOrder {
+    /// Marks the chunk as the most recently used one, adding it if needed.
+    fn touch(&mut self, name: XorName) {
+        if let Some(sequence) = self.sequences.insert(name, self.next_sequence) {
+            let _ = self.names.remove(&sequence);
+        }
+        let _ = self.names.insert(self.next_sequence, name);
+        self.next_sequence += 1;
+    }
+
+    fn remove(&mut self, name: &XorName) {
+        if let Some(sequence) = self.sequences.remove(name) {
+            let _ = self.names.remove(&sequence);
+        }
+    }
+
+    /// Returns the name of the least recently used chunk.
+    fn oldest(&self) -> Option<XorName> {
+        self.names.values().next().cloned()
+    }
+}
+
+/// The chunks cached in memory.
+#[derive(Default)]
+struct MemoryCache {
+    entries: HashMap<XorName, (ImmutableData, Instant)>,
+    lru: LruOrder,
+    total_bytes: u64,
+    /// The number of chunks dropped so far.
+    evictions: u64,
+}
+
+impl MemoryCache {
+    fn get(&mut self, name: &XorName, expiry: Duration) -> Option<ImmutableData> {
+        let data = match self.entries.get_mut(name) {
+            Some(&mut (_, ref last_used)) if last_used.elapsed() > expiry => None,
+            Some(&mut (ref data, ref mut last_used)) => {
+                *last_used = Instant::now();
+                Some(data.clone())
+            }
+            None => return None,
+        };
+        match data {
+            Some(data) => {
+                self.lru.touch(*name);
+                Some(data)
+            }
+            None => {
+                let _ = self.remove(name);
+                self.evictions += 1;
+                None
+            }
+        }
+    }
+
+    fn insert(&mut self, data: ImmutableData) {
+        let name = *data.name();
+        let _ = self.remove(&name);
+        self.total_bytes += data.value().len() as u64;
+        self.lru.touch(name);
+        let _ = self.entries.insert(name, (data, Instant::now()));
+    }
+
+    fn remove(&mut self, name: &XorName) -> Option<(ImmutableData, Instant)> {
+        self.lru.remove(name);
+        let entry = self.entries.remove(name);
+        if let Some((ref data, _)) = entry {
+            self.total_bytes -= data.value().len() as u64;
+        }
+        entry
+    }
+}
+
+/// A cached version of a mutable chunk, or a marker that the chunk was recently mutated.
+struct MutableEntry {
+    /// The oldest version which may be cached.  Responses carrying older versions are ignored
+    /// until the entry expires.
+    min_version: u64,
+    data: Option<Data>,
+    cached_at: Instant,
+}
+
+/// The `StructuredData` and appendable data cached in memory.  Unlike `ImmutableData`, these are
+/// only cached for a short time, as they can change.
+#[derive(Default)]
+struct MutableCache {
+    entries: HashMap<DataIdentifier, MutableEntry>,
+    /// The number of cached versions dropped so far, other than by invalidation.
+    evictions: u64,
+}
+
+impl MutableCache {
+    fn get(&mut self, data_id: &DataIdentifier, expiry: Duration) -> Option<Data> {
+        let expired = match self.entries.get(data_id) {
+            Some(entry) => entry.cached_at.elapsed() > expiry,
+            None => return None,
+        };
+        if expired {
+            self.remove(data_id);
+            return None;
+        }
+        self.entries.get(data_id).and_then(|entry| entry.data.clone())
+    }
+
+    /// Caches the data, unless a newer version of it was seen recently.  Returns whether it was
+    /// cached.
+    fn insert(&mut self, data: Data, expiry: Duration, max_entries: usize) -> bool {
+        let (data_id, version) = data_manager::id_and_version_of(&data);
+        if let Some(entry) = self.entries.get(&data_id) {
+            if entry.cached_at.elapsed() <= expiry && version < entry.min_version {
+                return false;
+            }
+        }
+        let entry = MutableEntry {
+            min_version: version,
+            data: Some(data),
+            cached_at: Instant::now(),
+        };
+        let _ = self.entries.insert(data_id, entry);
+        self.evict(expiry, max_entries);
+        true
+    }
+
+    /// Drops the cached data after it was mutated.  Until the marker left in its place expires,
+    /// the version which was cached and older ones aren't cached again.
+    fn invalidate(&mut self, data_id: DataIdentifier, expiry: Duration, max_entries: usize) {
+        let min_version = match self.entries.get(&data_id) {
+            Some(&MutableEntry { min_version, data: Some(_), .. }) => min_version + 1,
+            Some(&MutableEntry { min_version, data: None, .. }) => min_version,
+            None => 0,
+        };
+        let entry = MutableEntry {
+            min_version: min_version,
+            data: None,
+            cached_at: Instant::now(),
+        };
+        let _ = self.entries.insert(data_id, entry);
+        self.evict(expiry, max_entries);
+    }
+
+    /// Drops the expired entries, and then the oldest ones, if there are more than `max_entries`.
+    fn evict(&mut self, expiry: Duration, max_entries: usize) {
+        if self.entries.len() <= max_entries {
+            return;
+        }
+        let expired = self.entries
+            .iter()
+            .filter(|&(_, entry)| entry.cached_at.elapsed() > expiry)
+            .map(|(data_id, _)| *data_id)
+            .collect::<Vec<_>>();
+        for data_id in expired {
+            self.remove(&data_id);
+        }
+        while self.entries.len() > max_entries {
+            let oldest = self.entries
+                .iter()
+                .min_by_key(|&(_, entry)| entry.cached_at)
+                .map(|(data_id, _)| *data_id);
+            match oldest {
+                Some(data_id) => self.remove(&data_id),
+                None => return,
+            }
+        }
+    }
+
+    fn remove(&mut self, data_id: &DataIdentifier) {
+        if let Some(MutableEntry { data: Some(_), .. }) = self.entries.remove(data_id) {
+            self.evictions += 1;
+        }
+    }
+}
+
+/// The second cache level, holding the chunks evicted from memory in a directory of their own.
+/// It survives restarts, so the chunks are checked against their names when they are loaded.
+struct DiskCache {
+    store: ChunkStore<XorName, Vec<u8>>,
+    lru: LruOrder,
+    /// The number of chunks dropped so far to make room for others.
+    evictions: u64,
+}
+
+impl DiskCache {
+    fn open(dir: PathBuf, max_bytes: u64) -> Result<DiskCache, InternalError> {
+        let store = ChunkStoreBuilder::new(dir, max_bytes).persistent(true).create()?;
+        let mut lru = LruOrder::default();
+        for name in store.iter_keys() {
+            lru.touch(name);
+        }
+        Ok(DiskCache {
+            store: store,
+            lru: lru,
+            evictions: 0,
+        })
+    }
+
+    fn get(&mut self, name: &XorName) -> Option<ImmutableData> {
+        if !self.store.has(name) {
+            return None;
+        }
+        match self.store.get(name) {
+            Ok(value) => {
+                let data = ImmutableData::new(value);
+                if data.name() == name {
+                    self.lru.touch(*name);
+                    return Some(data);
+                }
+                warn!("Cached chunk {:?} doesn't match its name.", name);
+            }
+            Err(error) => warn!("Failed to read cached chunk {:?}: {}", name, error),
+        }
+        self.remove(name);
+        None
+    }
+
+    /// Writes the chunk to disk, evicting the least recently used ones until it fits.
+    fn insert(&mut self, data: &ImmutableData) {
+        let name = *data.name();
+        if self.store.has(&name) {
+            self.lru.touch(name);
+            return;
+        }
+        loop {
+            match self.store.put(&name, data.value()) {
+                Ok(()) => {
+                    self.lru.touch(name);
+                    return;
+                }
+                Err(ChunkStoreError::NotEnoughSpace) => {
+                    match self.lru.oldest() {
+                        Some(oldest) => {
+                            self.remove(&oldest);
+                            self.evictions += 1;
+                        }
+                        None => return,
+                    }
+                }
+                Err(error) => {
+                    warn!("Failed to write cached chunk {:?}: {}", name, error);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn remove(&mut self, name: &XorName) {
+        self.lru.remove(name);
+        if let Err(error) = self.store.delete(name) {
+            warn!("Failed to remove cached chunk {:?}: {}", name, error);
+        }
+    }
+}
+
+/// A cache for `ImmutableData` chunks passing through this node, limited in the number and total
+/// size of the chunks it holds in memory.  The least recently used chunks are evicted first, and
+/// chunks which haven't been requested for the configured expiry duration are dropped.  If a cache
+/// directory is configured, evicted chunks which haven't expired are moved there.
+///
+/// `StructuredData` and appendable data are cached in memory for a shorter expiry duration, and
+/// are dropped when a successful `Post`, `Append` or `Delete` for them passes through this node.
+///
+/// An `ImmutableData` chunk is only admitted to the cache once it has been requested more than
+/// once recently, so chunks which are only requested once don't evict the popular ones.
 pub struct Cache {
-    store: RefCell<LruCache<XorName, ImmutableData>>,
+    memory: RefCell<MemoryCache>,
+    disk: Option<RefCell<DiskCache>>,
+    mutable: RefCell<MutableCache>,
+    sketch: RefCell<FrequencySketch>,
+    stats: Rc<Cell<CacheStats>>,
+    logging_time: Cell<Instant>,
+    max_bytes: u64,
+    max_entries: usize,
+    expiry: Duration,
+    mutable_expiry: Duration,
 }
 
 impl Cache {
-    pub fn new() -> Self {
-        Cache {
-            store: RefCell::new(LruCache::with_expiry_duration_and_capacity(
-                                    Duration::from_secs(CACHE_EXPIRY_DURATION_SECS),
-                                    CACHE_CAPACITY)),
+    /// Creates a cache with the limits given in `config`.  The chunks already in the cache
+    /// directory, if any, are kept.
+    pub fn new(config: &Config) -> Result<Self, InternalError> {
+        let disk = match config.cache_dir {
+            Some(ref dir) => {
+                let max_bytes = config.cache_dir_max_bytes.unwrap_or(DEFAULT_CACHE_DIR_MAX_BYTES);
+                Some(RefCell::new(DiskCache::open(PathBuf::from(dir), max_bytes)?))
+            }
+            None => None,
+        };
+        let max_entries = config.cache_max_entries.unwrap_or(DEFAULT_CACHE_MAX_ENTRIES);
+        let cache = Cache {
+            memory: RefCell::new(MemoryCache::default()),
+            disk: disk,
+            mutable: RefCell::new(MutableCache::default()),
+            sketch: RefCell::new(FrequencySketch::new(max_entries)),
+            stats: Rc::new(Cell::new(CacheStats::default())),
+            logging_time: Cell::new(Instant::now()),
+            max_bytes: config.cache_max_bytes.unwrap_or(DEFAULT_CACHE_MAX_BYTES),
+            max_entries: max_entries,
+            expiry: Duration::from_secs(config.cache_expiry_secs
+                .unwrap_or(DEFAULT_CACHE_EXPIRY_SECS)),
+            mutable_expiry: Duration::from_secs(config.cache_mutable_expiry_secs
+                .unwrap_or(DEFAULT_CACHE_MUTABLE_EXPIRY_SECS)),
+        };
+        cache.update_stats(|_| ());
+        Ok(cache)
+    }
+
+    /// Returns a handle to the statistics of this cache, which stays up to date after the cache
+    /// has been passed to routing.
+    pub fn stats(&self) -> Rc<Cell<CacheStats>> {
+        self.stats.clone()
+    }
+
+    /// Applies `update` to the statistics and refreshes the sizes and eviction counts in them.
+    /// Logs them if they haven't been logged for a while.
+    fn update_stats<F: FnOnce(&mut CacheStats)>(&self, update: F) {
+        let mut stats = self.stats.get();
+        update(&mut stats);
+        {
+            let memory = self.memory.borrow();
+            stats.bytes = memory.total_bytes;
+            stats.evictions = memory.evictions + self.mutable.borrow().evictions;
+        }
+        if let Some(ref disk) = self.disk {
+            let disk = disk.borrow();
+            stats.evictions += disk.evictions;
+            stats.disk_bytes = disk.store.used_space();
+        }
+        self.stats.set(stats);
+        if self.logging_time.get().elapsed().as_secs() >= STATUS_LOG_INTERVAL {
+            self.logging_time.set(Instant::now());
+            info!("Routing cache stats - {} hits, {} misses, {} inserts, {} rejections, {} \
+                   evictions. {} bytes in memory, {} bytes on disk.",
+                  stats.hits,
+                  stats.misses,
+                  stats.inserts,
+                  stats.rejections,
+                  stats.evictions,
+                  stats.bytes,
+                  stats.disk_bytes);
+        }
+    }
+
+    /// Looks the chunk up in memory, and then on disk.
+    fn get_immutable(&self, name: &XorName) -> Option<ImmutableData> {
+        let data = self.memory.borrow_mut().get(name, self.expiry);
+        if data.is_some() {
+            return data;
+        }
+        let data = match self.disk {
+            Some(ref disk) => disk.borrow_mut().get(name),
+            None => None,
+        };
+        if let Some(ref data) = data {
+            // Chunks requested again are moved back to memory, but stay on disk, too.
+            self.insert(data.clone());
+        }
+        data
+    }
+
+    /// Adds the chunk to the memory cache, and evicts the expired chunks, which are the least
+    /// recently used ones, and then as many of the others as needed to stay within the limits.
+    fn insert(&self, data: ImmutableData) {
+        if data.value().len() as u64 > self.max_bytes || self.max_entries == 0 {
+            if let Some(ref disk) = self.disk {
+                disk.borrow_mut().insert(&data);
+            }
+            return;
+        }
+        let mut memory = self.memory.borrow_mut();
+        memory.insert(data);
+        while let Some(name) = memory.lru.oldest() {
+            let expired = memory.entries
+                .get(&name)
+                .map_or(true, |&(_, last_used)| last_used.elapsed() > self.expiry);
+            if !expired && memory.total_bytes <= self.max_bytes &&
+               memory.entries.len() <= self.max_entries {
+                break;
+            }
+            let spilled = match (memory.remove(&name), self.disk.as_ref()) {
+                (Some((ref data, _)), Some(disk)) if !expired => {
+                    disk.borrow_mut().insert(data);
+                    true
+                }
+                _ => false,
+            };
+            if !spilled {
+                memory.evictions += 1;
+            }
         }
     }
 }
 
 impl RoutingCache for Cache {
     fn get(&self, request: &Request) -> Option<Response> {
-        if let Request::Get(DataIdentifier::Immutable(name), message_id) = *request {
-            self.store
-                .borrow_mut()
-                .get(&name)
-                .map(|data| Response::GetSuccess(Data::Immutable(data.clone()), message_id))
+        let response = match *request {
+            Request::Get(DataIdentifier::Immutable(name), message_id) => {
+                self.sketch.borrow_mut().record(&name);
+                self.get_immutable(&name)
+                    .map(|data| Response::GetSuccess(Data::Immutable(data), message_id))
+            }
+            Request::Get(_, _) if self.mutable_expiry == Duration::from_secs(0) => return None,
+            Request::Get(ref data_id, message_id) => {
+                self.mutable
+                    .borrow_mut()
+                    .get(data_id, self.mutable_expiry)
+                    .map(|data| Response::GetSuccess(data, message_id))
+            }
+            _ => return None,
+        };
+        if response.is_some() {
+            self.update_stats(|stats| stats.hits += 1);
         } else {
-            None
+            self.update_stats(|stats| stats.misses += 1);
         }
+        response
     }
 
     fn put(&self, response: Response) {
-        if let Response::GetSuccess(Data::Immutable(data), _) = response {
-            let _ = self.store.borrow_mut().insert(*data.name(), data);
+        match response {
+            Response::GetSuccess(Data::Immutable(data), _) => {
+                if self.sketch.borrow().estimate(data.name()) < ADMISSION_MIN_REQUESTS {
+                    self.update_stats(|stats| stats.rejections += 1);
+                    return;
+                }
+                self.insert(data);
+                self.update_stats(|stats| stats.inserts += 1);
+            }
+            Response::GetSuccess(data, _) => {
+                if self.mutable_expiry != Duration::from_secs(0) &&
+                   self.mutable.borrow_mut().insert(data, self.mutable_expiry, self.max_entries) {
+                    self.update_stats(|stats| stats.inserts += 1);
+                }
+            }
+            Response::PostSuccess(data_id, _) |
+            Response::AppendSuccess(data_id, _) |
+            Response::DeleteSuccess(data_id, _) => {
+                self.mutable
+                    .borrow_mut()
+                    .invalidate(data_id, self.mutable_expiry, self.max_entries);
+                self.update_stats(|_| ());
+            }
+            _ => (),
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use routing::{Data, ImmutableData, MessageId, Request, Response};
+    use chunk_store::{ChunkStorage, ChunkStore, ChunkStoreBuilder};
+    use config_handler::Config;
+    use rand::{self, Rng};
+    use routing::{Data, ImmutableData, MessageId, Request, Response, StructuredData, XorName};
     use routing::Cache as RoutingCache;
-    use super::Cache;
+    use rust_sodium::crypto::sign;
+    use std::collections::BTreeSet;
+    use std::iter;
+    use std::thread;
+    use std::time::Duration;
+    use super::{Cache, CacheStats};
+    use tempdir::TempDir;
+
+    /// Passes two requests for the data through the cache, so it will be admitted, and then the
+    /// response carrying it.
+    fn put(cache: &Cache, data: &Data) {
+        for _ in 0..2 {
+            let _ = cache.get(&Request::Get(data.identifier(), MessageId::new()));
+        }
+        cache.put(Response::GetSuccess(data.clone(), MessageId::new()));
+    }
+
+    fn is_cached(cache: &Cache, data: &Data) -> bool {
+        cache.get(&Request::Get(data.identifier(), MessageId::new())).is_some()
+    }
+
+    fn random_data(size: usize) -> Data {
+        Data::Immutable(ImmutableData::new(rand::thread_rng().gen_iter().take(size).collect()))
+    }
+
+    fn structured_data(name: XorName, version: u64) -> Data {
+        let owners = iter::once(sign::gen_keypair().0).collect::<BTreeSet<_>>();
+        let value = rand::thread_rng().gen_iter().take(10).collect();
+        Data::Structured(unwrap!(StructuredData::new(12345, name, version, value, owners)))
+    }
+
+    fn cached_data(cache: &Cache, data: &Data) -> Option<Data> {
+        match cache.get(&Request::Get(data.identifier(), MessageId::new())) {
+            Some(Response::GetSuccess(cached_data, _)) => Some(cached_data),
+            _ => None,
+        }
+    }
 
     #[test]
     fn put_and_get() {
-        let cache = Cache::new();
+        let cache = unwrap!(Cache::new(&Config::default()));
 
         let data = "hello world".bytes().collect();
         let data = Data::Immutable(ImmutableData::new(data));
-        let response_message_id = MessageId::new();
+        let request_message_id = MessageId::new();
+        let request = Request::Get(data.identifier(), request_message_id);
+        for _ in 0..2 {
+            assert!(cache.get(&request).is_none());
+        }
 
+        let response_message_id = MessageId::new();
         let response = Response::GetSuccess(data.clone(), response_message_id);
         cache.put(response);
 
-        let request_message_id = MessageId::new();
-        let request = Request::Get(data.identifier(), request_message_id);
-
         match cache.get(&request) {
             Some(Response::GetSuccess(cached_data, cached_message_id)) => {
                 assert_eq!(cached_data, data);
@@ -85,4 +650,158 @@ mod tests {
             _ => panic!("unexpected cached value"),
         }
     }
+
+    #[test]
+    fn evicts_least_recently_used_by_size_and_count() {
+        let config = Config {
+            cache_max_bytes: Some(3000),
+            cache_max_entries: Some(3),
+            ..Config::default()
+        };
+        let cache = unwrap!(Cache::new(&config));
+        let chunks = (0..3).map(|_| random_data(1000)).collect::<Vec<_>>();
+        for data in &chunks {
+            put(&cache, data);
+        }
+        assert!(chunks.iter().all(|data| is_cached(&cache, data)));
+
+        // Using the first chunk makes the second one the least recently used.
+        assert!(is_cached(&cache, &chunks[0]));
+        let small_data = random_data(10);
+        put(&cache, &small_data);
+        assert!(!is_cached(&cache, &chunks[1]));
+        assert!(is_cached(&cache, &chunks[0]));
+        assert!(is_cached(&cache, &small_data));
+
+        // A large chunk evicts as many others as needed to stay within the byte budget.
+        let large_data = random_data(2500);
+        put(&cache, &large_data);
+        assert!(is_cached(&cache, &large_data));
+        assert!(!is_cached(&cache, &chunks[2]));
+        assert!(!is_cached(&cache, &chunks[0]));
+
+        // Chunks larger than the whole budget aren't cached.
+        let huge_data = random_data(3001);
+        put(&cache, &huge_data);
+        assert!(!is_cached(&cache, &huge_data));
+        assert!(is_cached(&cache, &large_data));
+        assert_eq!(cache.stats().get().evictions, 3);
+    }
+
+    #[test]
+    fn chunks_are_admitted_after_repeated_requests() {
+        let cache = unwrap!(Cache::new(&Config::default()));
+        let data = random_data(100);
+
+        // A chunk which was requested once isn't cached.
+        assert!(!is_cached(&cache, &data));
+        cache.put(Response::GetSuccess(data.clone(), MessageId::new()));
+        assert!(!is_cached(&cache, &data));
+
+        // After the second request, it is.
+        cache.put(Response::GetSuccess(data.clone(), MessageId::new()));
+        assert!(is_cached(&cache, &data));
+
+        let expected_stats = CacheStats {
+            hits: 1,
+            misses: 2,
+            inserts: 1,
+            rejections: 1,
+            evictions: 0,
+            bytes: 100,
+            disk_bytes: 0,
+        };
+        assert_eq!(cache.stats().get(), expected_stats);
+    }
+
+    #[test]
+    fn expired_chunks_are_dropped() {
+        let config = Config { cache_expiry_secs: Some(0), ..Config::default() };
+        let cache = unwrap!(Cache::new(&config));
+        let data = random_data(100);
+        put(&cache, &data);
+        thread::sleep(Duration::from_millis(10));
+        assert!(!is_cached(&cache, &data));
+    }
+
+    #[test]
+    fn evicted_chunks_are_served_from_disk() {
+        let tempdir = unwrap!(TempDir::new("test"));
+        let cache_dir = tempdir.path().join("cache");
+        let config = Config {
+            cache_max_entries: Some(1),
+            cache_dir: Some(format!("{}", cache_dir.display())),
+            ..Config::default()
+        };
+        let chunks = (0..3).map(|_| random_data(1000)).collect::<Vec<_>>();
+        {
+            let cache = unwrap!(Cache::new(&config));
+            for data in &chunks {
+                put(&cache, data);
+            }
+            assert!(chunks.iter().all(|data| is_cached(&cache, data)));
+        }
+
+        // The chunks on disk survive a restart.
+        {
+            let cache = unwrap!(Cache::new(&config));
+            assert!(chunks.iter().all(|data| is_cached(&cache, data)));
+        }
+
+        // A chunk which doesn't match its name isn't served.
+        let name = *chunks[0].name();
+        {
+            let mut store: ChunkStore<XorName, Vec<u8>> =
+                unwrap!(ChunkStoreBuilder::new(cache_dir.clone(), 1024 * 1024)
+                    .persistent(true)
+                    .create());
+            unwrap!(store.put(&name, &vec![0; 1000]));
+        }
+        let cache = unwrap!(Cache::new(&config));
+        assert!(!is_cached(&cache, &chunks[0]));
+        assert!(is_cached(&cache, &chunks[1]));
+    }
+
+    #[test]
+    fn mutable_data_is_invalidated() {
+        let cache = unwrap!(Cache::new(&Config::default()));
+        let name = rand::random();
+        let version_0 = structured_data(name, 0);
+        let version_1 = structured_data(name, 1);
+
+        put(&cache, &version_1);
+        assert_eq!(cached_data(&cache, &version_1), Some(version_1.clone()));
+
+        // An older version arriving late doesn't replace the cached one.
+        put(&cache, &version_0);
+        assert_eq!(cached_data(&cache, &version_1), Some(version_1.clone()));
+
+        // A successful mutation drops the cached version, and it isn't cached again.
+        cache.put(Response::PostSuccess(version_1.identifier(), MessageId::new()));
+        assert_eq!(cached_data(&cache, &version_1), None);
+        put(&cache, &version_1);
+        assert_eq!(cached_data(&cache, &version_1), None);
+
+        let version_2 = structured_data(name, 2);
+        put(&cache, &version_2);
+        assert_eq!(cached_data(&cache, &version_2), Some(version_2.clone()));
+        cache.put(Response::DeleteSuccess(version_2.identifier(), MessageId::new()));
+        assert_eq!(cached_data(&cache, &version_2), None);
+    }
+
+    #[test]
+    fn mutable_data_expires() {
+        let config = Config { cache_mutable_expiry_secs: Some(1), ..Config::default() };
+        let cache = unwrap!(Cache::new(&config));
+        let data = structured_data(rand::random(), 0);
+        put(&cache, &data);
+        assert!(is_cached(&cache, &data));
+        thread::sleep(Duration::from_millis(1100));
+        assert!(!is_cached(&cache, &data));
+
+        let config = Config { cache_mutable_expiry_secs: Some(0), ..Config::default() };
+        let cache = unwrap!(Cache::new(&config));
+        put(&cache, &data);
+        assert!(!is_cached(&cache, &data));
+    }
 }
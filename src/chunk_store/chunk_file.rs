@@ -0,0 +1,239 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! The layout of a chunk file: a header holding the magic bytes, the layout version, the SHA-256
+//! checksum of the rest of the file and a format byte, followed by the body.  The format byte
+//! records the compression algorithm of the body, whether the body is encrypted and whether it
+//! starts with the chunk's serialised key.
+//!
+//! Before compression, the body is the serialised value, optionally preceded by the length of the
+//! serialised key (as a big-endian `u32`) and the serialised key itself.  An encrypted body is the
+//! nonce followed by the `secretbox` ciphertext of the compressed body.
+
+use flate2;
+use flate2::write::{ZlibDecoder, ZlibEncoder};
+use rust_sodium::crypto::hash::sha256;
+use rust_sodium::crypto::secretbox;
+use std::io::Write;
+use super::Error;
+
+/// The bytes every chunk file starts with.
+const MAGIC: [u8; 4] = [b'S', b'V', b'C', b'F'];
+/// The current version of the chunk file layout.
+pub const FORMAT_VERSION: u8 = 2;
+/// The version of the layouts used before versions were recorded.  The last of them started with
+/// the checksum and had neither magic bytes nor a version.  Older chunk files hold the checksum of
+/// the serialised value followed by it, or only the serialised value.  These have to be recognised
+/// by the store, which knows the type of the value (see `legacy_value`).
+pub const UNVERSIONED_FORMAT_VERSION: u8 = 1;
+/// The length of the magic bytes and the version.
+const PREFIX_LEN: usize = 5;
+/// The length of the checksum following the prefix.
+const CHECKSUM_LEN: usize = sha256::DIGESTBYTES;
+/// The length of the complete header.
+const HEADER_LEN: usize = PREFIX_LEN + CHECKSUM_LEN + 1;
+/// The format byte flag of encrypted bodies.
+const ENCRYPTED_FLAG: u8 = 0x80;
+/// The format byte flag of bodies starting with the serialised key.
+const KEY_FLAG: u8 = 0x40;
+/// The format byte bits holding the compression algorithm.
+const COMPRESSION_MASK: u8 = 0x3f;
+/// The length of the serialised key's length prefix.
+const KEY_LENGTH_LEN: usize = 4;
+
+/// The compression algorithms chunk bodies can be stored with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, RustcDecodable, RustcEncodable)]
+pub enum Compression {
+    /// The body is stored as is.
+    None,
+    /// The body is compressed with zlib.
+    Zlib,
+}
+
+impl Compression {
+    fn code(&self) -> u8 {
+        match *self {
+            Compression::None => 0,
+            Compression::Zlib => 1,
+        }
+    }
+
+    fn from_code(code: u8) -> Result<Compression, Error> {
+        match code {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Zlib),
+            _ => Err(Error::UnknownCompression(code)),
+        }
+    }
+}
+
+/// Returns the contents of a chunk file holding the given serialised value, and the serialised key
+/// if given.  The body is compressed with `compression` if that makes it smaller, and encrypted if
+/// `encryption_key` is given.
+pub fn encode(serialised_key: Option<&[u8]>,
+              serialised_value: &[u8],
+              compression: Compression,
+              encryption_key: Option<&secretbox::Key>)
+              -> Result<Vec<u8>, Error> {
+    let mut format = 0;
+    let mut body = Vec::new();
+    if let Some(serialised_key) = serialised_key {
+        format |= KEY_FLAG;
+        let key_len = serialised_key.len();
+        body.extend_from_slice(&[(key_len >> 24) as u8,
+                                 (key_len >> 16) as u8,
+                                 (key_len >> 8) as u8,
+                                 key_len as u8]);
+        body.extend_from_slice(serialised_key);
+    }
+    body.extend_from_slice(serialised_value);
+
+    let compressed_body = match compression {
+        Compression::None => None,
+        Compression::Zlib => {
+            let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::Default);
+            encoder.write_all(&body)?;
+            Some(encoder.finish()?)
+        }
+    };
+    if let Some(compressed_body) = compressed_body {
+        if compressed_body.len() < body.len() {
+            format |= compression.code();
+            body = compressed_body;
+        }
+    }
+
+    if let Some(encryption_key) = encryption_key {
+        format |= ENCRYPTED_FLAG;
+        let nonce = secretbox::gen_nonce();
+        let mut encrypted_body = nonce.0.to_vec();
+        encrypted_body.extend_from_slice(&secretbox::seal(&body, &nonce, encryption_key));
+        body = encrypted_body;
+    }
+
+    let mut contents = Vec::with_capacity(HEADER_LEN + body.len());
+    contents.extend_from_slice(&MAGIC);
+    contents.push(FORMAT_VERSION);
+    contents.extend_from_slice(&[0; CHECKSUM_LEN]);
+    contents.push(format);
+    contents.extend_from_slice(&body);
+    let checksum = sha256::hash(&contents[PREFIX_LEN + CHECKSUM_LEN..]);
+    contents[PREFIX_LEN..PREFIX_LEN + CHECKSUM_LEN].copy_from_slice(&checksum.0);
+    Ok(contents)
+}
+
+/// Returns the given chunk file contents, written in the layout with the given version, converted
+/// to the current layout.  Contents which already start with the current magic bytes and version
+/// are returned unchanged, so that a migration interrupted before the new format version was
+/// recorded can be run again.
+///
+/// If the version isn't known, it returns `Error::UnsupportedFormat`.
+pub fn migrate(contents: Vec<u8>, version: u8) -> Result<Vec<u8>, Error> {
+    if contents.len() >= PREFIX_LEN && contents[..MAGIC.len()] == MAGIC &&
+       contents[MAGIC.len()] == FORMAT_VERSION {
+        return Ok(contents);
+    }
+    match version {
+        FORMAT_VERSION => Ok(contents),
+        UNVERSIONED_FORMAT_VERSION => {
+            let mut migrated = Vec::with_capacity(PREFIX_LEN + contents.len());
+            migrated.extend_from_slice(&MAGIC);
+            migrated.push(FORMAT_VERSION);
+            migrated.extend_from_slice(&contents);
+            Ok(migrated)
+        }
+        _ => Err(Error::UnsupportedFormat(version)),
+    }
+}
+
+/// Returns the serialised value held in chunk file contents written before the compression format
+/// was recorded, i.e. the checksum of the serialised value followed by it.  Returns `None` if the
+/// contents don't match the checksum.
+pub fn legacy_value(contents: &[u8]) -> Option<&[u8]> {
+    if contents.len() < CHECKSUM_LEN {
+        return None;
+    }
+    let (checksum, body) = contents.split_at(CHECKSUM_LEN);
+    if sha256::hash(body).0[..] == *checksum {
+        Some(body)
+    } else {
+        None
+    }
+}
+
+/// Returns the serialised key, if present, and the serialised value held in the given chunk file
+/// contents.
+///
+/// If the contents don't match the checksum, it returns `Error::Corrupted`, and if they were
+/// written in another layout version, `Error::UnsupportedFormat`.  If they are encrypted and can't
+/// be decrypted with `encryption_key`, it returns `Error::Decryption`.
+pub fn decode(contents: &[u8],
+              encryption_key: Option<&secretbox::Key>)
+              -> Result<(Option<Vec<u8>>, Vec<u8>), Error> {
+    if contents.len() < HEADER_LEN || contents[..MAGIC.len()] != MAGIC {
+        return Err(Error::Corrupted);
+    }
+    if contents[MAGIC.len()] != FORMAT_VERSION {
+        return Err(Error::UnsupportedFormat(contents[MAGIC.len()]));
+    }
+    let (checksum, rest) = contents[PREFIX_LEN..].split_at(CHECKSUM_LEN);
+    if sha256::hash(rest).0[..] != *checksum {
+        return Err(Error::Corrupted);
+    }
+    let format = rest[0];
+    let mut body = rest[1..].to_vec();
+
+    if format & ENCRYPTED_FLAG != 0 {
+        let encryption_key = match encryption_key {
+            Some(encryption_key) => encryption_key,
+            None => return Err(Error::Decryption),
+        };
+        if body.len() < secretbox::NONCEBYTES {
+            return Err(Error::Corrupted);
+        }
+        let (nonce, ciphertext) = body.split_at(secretbox::NONCEBYTES);
+        let nonce = match secretbox::Nonce::from_slice(nonce) {
+            Some(nonce) => nonce,
+            None => return Err(Error::Corrupted),
+        };
+        body = secretbox::open(ciphertext, &nonce, encryption_key).map_err(|()| Error::Decryption)?;
+    }
+
+    match Compression::from_code(format & COMPRESSION_MASK)? {
+        Compression::None => (),
+        Compression::Zlib => {
+            let mut decoder = ZlibDecoder::new(Vec::new());
+            decoder.write_all(&body)?;
+            body = decoder.finish()?;
+        }
+    }
+
+    if format & KEY_FLAG == 0 {
+        return Ok((None, body));
+    }
+    if body.len() < KEY_LENGTH_LEN {
+        return Err(Error::Corrupted);
+    }
+    let key_len = body[..KEY_LENGTH_LEN]
+        .iter()
+        .fold(0, |key_len, byte| (key_len << 8) | *byte as usize);
+    if body.len() < KEY_LENGTH_LEN + key_len {
+        return Err(Error::Corrupted);
+    }
+    let serialised_value = body.split_off(KEY_LENGTH_LEN + key_len);
+    Ok((Some(body.split_off(KEY_LENGTH_LEN)), serialised_value))
+}
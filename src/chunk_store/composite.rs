@@ -0,0 +1,285 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use super::{ChunkStorage, CompressionStats, Error, ReservedSpace, SnapshotStats};
+use maidsafe_utilities::serialisation;
+use rustc_serialize::{Decodable, Encodable};
+use std::cmp;
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::path::Path;
+
+/// `CompositeChunkStore` spreads the data chunks across several underlying stores, e.g. one per
+/// disk.  New chunks are placed in the store with the most free space.
+///
+/// If one of the stores fails, it is dropped and the keys of the chunks it held are reported by
+/// `take_lost_keys`, while the remaining stores keep working.
+pub struct CompositeChunkStore<Key, Value> {
+    stores: Vec<Box<ChunkStorage<Key, Value>>>,
+    /// Whether the store at the same index has failed.
+    failed: Vec<bool>,
+    /// The index of the store holding each chunk, by serialised key.
+    locations: HashMap<Vec<u8>, usize>,
+    /// The serialised keys of the chunks lost since the last call to `take_lost_keys`.
+    lost_keys: Vec<Vec<u8>>,
+    /// The space reserved across all stores.
+    reserved_space: ReservedSpace,
+}
+
+impl<Key, Value> CompositeChunkStore<Key, Value>
+    where Key: Decodable + Encodable,
+          Value: Decodable + Encodable
+{
+    /// Creates a `CompositeChunkStore` from the given stores, which may already hold chunks.  If
+    /// several stores hold the same chunk, the copy in the first of them is kept and the others
+    /// are deleted.
+    pub fn new(mut stores: Vec<Box<ChunkStorage<Key, Value>>>)
+               -> Result<CompositeChunkStore<Key, Value>, Error> {
+        let mut locations = HashMap::new();
+        for index in 0..stores.len() {
+            for key in stores[index].keys() {
+                match locations.entry(serialisation::serialise(&key)?) {
+                    Entry::Occupied(entry) => {
+                        warn!("Chunk store {} holds a duplicate of a chunk in chunk store {}. \
+                               Deleting it.",
+                              index,
+                              entry.get());
+                        if let Err(error) = stores[index].delete(&key) {
+                            warn!("Failed to delete duplicate chunk: {}", error);
+                        }
+                    }
+                    Entry::Vacant(entry) => {
+                        let _ = entry.insert(index);
+                    }
+                }
+            }
+        }
+        Ok(CompositeChunkStore {
+            failed: vec![false; stores.len()],
+            stores: stores,
+            locations: locations,
+            lost_keys: Vec::new(),
+            reserved_space: ReservedSpace::default(),
+        })
+    }
+
+    /// Returns the indices of the stores which haven't failed, the one with the most free space
+    /// first.
+    fn stores_by_free_space(&self) -> Vec<usize> {
+        let mut indices = (0..self.stores.len())
+            .filter(|index| !self.failed[*index])
+            .collect::<Vec<_>>();
+        indices.sort_by(|lhs, rhs| self.free_space(*rhs).cmp(&self.free_space(*lhs)));
+        indices
+    }
+
+    fn free_space(&self, index: usize) -> u64 {
+        let store = &self.stores[index];
+        let max_space = store.max_space();
+        max_space - cmp::min(store.used_space(), max_space)
+    }
+
+    /// Checks whether the store at `index` is still usable after an error, and drops it if not.
+    fn handle_error(&mut self, index: usize, error: &Error) {
+        if let Error::Io(_) = *error {
+            if let Err(health_error) = self.stores[index].check_health() {
+                self.fail_store(index, &health_error);
+            }
+        }
+    }
+
+    /// Drops the store at `index`, recording the keys of all chunks it held as lost.
+    fn fail_store(&mut self, index: usize, error: &Error) {
+        error!("Chunk store {} failed: {}", index, error);
+        self.failed[index] = true;
+        let lost_keys = self.locations
+            .iter()
+            .filter(|&(_, location)| *location == index)
+            .map(|(serialised_key, _)| serialised_key.clone())
+            .collect::<Vec<_>>();
+        for serialised_key in &lost_keys {
+            let _ = self.locations.remove(serialised_key);
+        }
+        self.lost_keys.extend(lost_keys);
+    }
+}
+
+impl<Key, Value> ChunkStorage<Key, Value> for CompositeChunkStore<Key, Value>
+    where Key: Decodable + Encodable,
+          Value: Decodable + Encodable
+{
+    /// Stores a new data chunk under `key`, replacing an existing one in place, or placing a new
+    /// one in the store with the most free space which can hold it.
+    fn put(&mut self, key: &Key, value: &Value) -> Result<(), Error> {
+        let serialised_key = serialisation::serialise(key)?;
+        if let Some(index) = self.locations.get(&serialised_key).cloned() {
+            let result = self.stores[index].put(key, value);
+            if let Err(ref error) = result {
+                self.handle_error(index, error);
+            }
+            return result;
+        }
+
+        let mut result = Err(Error::NotEnoughSpace);
+        for index in self.stores_by_free_space() {
+            result = self.stores[index].put(key, value);
+            match result {
+                Ok(()) => {
+                    let _ = self.locations.insert(serialised_key, index);
+                    break;
+                }
+                Err(ref error) => self.handle_error(index, error),
+            }
+        }
+        result
+    }
+
+    fn delete(&mut self, key: &Key) -> Result<(), Error> {
+        let serialised_key = serialisation::serialise(key)?;
+        if let Some(index) = self.locations.remove(&serialised_key) {
+            if let Err(error) = self.stores[index].delete(key) {
+                self.handle_error(index, &error);
+                if !self.failed[index] {
+                    let _ = self.locations.insert(serialised_key, index);
+                }
+                return Err(error);
+            }
+        }
+        Ok(())
+    }
+
+    fn get(&self, key: &Key) -> Result<Value, Error> {
+        match self.locations.get(&serialisation::serialise(key)?) {
+            Some(index) => self.stores[*index].get(key),
+            None => Err(Error::NotFound),
+        }
+    }
+
+    fn has(&self, key: &Key) -> bool {
+        serialisation::serialise(key)
+            .map(|serialised_key| self.locations.contains_key(&serialised_key))
+            .unwrap_or(false)
+    }
+
+    fn keys(&self) -> Vec<Key> {
+        self.iter_keys().collect()
+    }
+
+    fn iter_keys<'a>(&'a self) -> Box<Iterator<Item = Key> + 'a>
+        where Key: 'a
+    {
+        Box::new(self.locations
+            .keys()
+            .filter_map(|serialised_key| serialisation::deserialise(serialised_key).ok()))
+    }
+
+    fn verify(&self, key: &Key) -> Result<(), Error> {
+        match self.locations.get(&serialisation::serialise(key)?) {
+            Some(index) => self.stores[*index].verify(key),
+            None => Err(Error::NotFound),
+        }
+    }
+
+    /// Only forgets the chunk's location once it has been quarantined.
+    fn quarantine(&mut self, key: &Key) -> Result<(), Error> {
+        let serialised_key = serialisation::serialise(key)?;
+        let index = match self.locations.get(&serialised_key) {
+            Some(index) => *index,
+            None => return Err(Error::NotFound),
+        };
+        if let Err(error) = self.stores[index].quarantine(key) {
+            self.handle_error(index, &error);
+            return Err(error);
+        }
+        let _ = self.locations.remove(&serialised_key);
+        Ok(())
+    }
+
+    /// Checks the health of all stores, and returns the keys of the chunks which were held by
+    /// stores that failed since the last call.
+    fn take_lost_keys(&mut self) -> Vec<Key> {
+        for index in 0..self.stores.len() {
+            if self.failed[index] {
+                continue;
+            }
+            if let Err(error) = self.stores[index].check_health() {
+                self.fail_store(index, &error);
+            }
+        }
+        self.lost_keys
+            .drain(..)
+            .filter_map(|serialised_key| serialisation::deserialise(&serialised_key).ok())
+            .collect()
+    }
+
+    fn max_space(&self) -> u64 {
+        (0..self.stores.len())
+            .filter(|index| !self.failed[*index])
+            .fold(0, |total, index| total + self.stores[index].max_space())
+    }
+
+    fn used_space(&self) -> u64 {
+        (0..self.stores.len())
+            .filter(|index| !self.failed[*index])
+            .fold(0, |total, index| total + self.stores[index].used_space())
+    }
+
+    /// Asks the store holding the chunk, or else the one a new chunk would be placed in first.
+    fn space_needed(&self, key: &Key, value: &Value) -> Result<u64, Error> {
+        let serialised_key = serialisation::serialise(key)?;
+        let index = match self.locations.get(&serialised_key) {
+            Some(index) => *index,
+            None => {
+                match self.stores_by_free_space().first() {
+                    Some(index) => *index,
+                    None => return Err(Error::NotEnoughSpace),
+                }
+            }
+        };
+        self.stores[index].space_needed(key, value)
+    }
+
+    /// The space is reserved in the combined capacity of the stores, as a new chunk may be placed
+    /// in any of them.
+    fn reserved(&self) -> &ReservedSpace {
+        &self.reserved_space
+    }
+
+    fn refresh_free_space(&mut self) {
+        for store in &mut self.stores {
+            store.refresh_free_space();
+        }
+    }
+
+    /// Includes the failed stores, so that the snapshots keep the stores' positions.
+    fn snapshot_stores(&self, dest: &Path, stats: &mut Vec<SnapshotStats>) -> Result<(), Error> {
+        for store in &self.stores {
+            store.snapshot_stores(dest, stats)?;
+        }
+        Ok(())
+    }
+
+    fn compression_stats(&self) -> CompressionStats {
+        self.stores.iter().fold(CompressionStats::default(), |total, store| {
+            let stats = store.compression_stats();
+            CompressionStats {
+                uncompressed_bytes: total.uncompressed_bytes + stats.uncompressed_bytes,
+                stored_bytes: total.stored_bytes + stats.stored_bytes,
+            }
+        })
+    }
+}
@@ -0,0 +1,121 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use super::{ChunkStorage, Error, ReservedSpace};
+use maidsafe_utilities::serialisation;
+use rustc_serialize::{Decodable, Encodable};
+use std::collections::HashMap;
+use std::marker::PhantomData;
+
+/// `MemoryChunkStore` is a store of serialised data held in memory, implementing a maximum usage
+/// to restrict storage in the same way as the disk-based `ChunkStore`.
+///
+/// The data chunks are lost when the `MemoryChunkStore` goes out of scope.
+pub struct MemoryChunkStore<Key, Value> {
+    chunks: HashMap<Vec<u8>, Vec<u8>>,
+    max_space: u64,
+    used_space: u64,
+    reserved_space: ReservedSpace,
+    phantom: PhantomData<(Key, Value)>,
+}
+
+impl<Key, Value> MemoryChunkStore<Key, Value> {
+    /// Creates a new, empty `MemoryChunkStore` with `max_space` allowed storage space.
+    pub fn new(max_space: u64) -> MemoryChunkStore<Key, Value> {
+        MemoryChunkStore {
+            chunks: HashMap::new(),
+            max_space: max_space,
+            used_space: 0,
+            reserved_space: ReservedSpace::default(),
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<Key, Value> ChunkStorage<Key, Value> for MemoryChunkStore<Key, Value>
+    where Key: Decodable + Encodable,
+          Value: Decodable + Encodable
+{
+    fn put(&mut self, key: &Key, value: &Value) -> Result<(), Error> {
+        let serialised_key = serialisation::serialise(key)?;
+        let serialised_value = serialisation::serialise(value)?;
+        let old_size = self.chunks
+            .get(&serialised_key)
+            .map_or(0, |old_value| old_value.len() as u64);
+        let new_used_space = self.used_space - old_size + serialised_value.len() as u64;
+        if new_used_space > self.max_space {
+            return Err(Error::NotEnoughSpace);
+        }
+        let _ = self.chunks.insert(serialised_key, serialised_value);
+        self.used_space = new_used_space;
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &Key) -> Result<(), Error> {
+        let serialised_key = serialisation::serialise(key)?;
+        if let Some(old_value) = self.chunks.remove(&serialised_key) {
+            self.used_space -= old_value.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn get(&self, key: &Key) -> Result<Value, Error> {
+        let serialised_key = serialisation::serialise(key)?;
+        match self.chunks.get(&serialised_key) {
+            Some(serialised_value) => Ok(serialisation::deserialise(serialised_value)?),
+            None => Err(Error::NotFound),
+        }
+    }
+
+    fn has(&self, key: &Key) -> bool {
+        serialisation::serialise(key)
+            .map(|serialised_key| self.chunks.contains_key(&serialised_key))
+            .unwrap_or(false)
+    }
+
+    fn keys(&self) -> Vec<Key> {
+        self.iter_keys().collect()
+    }
+
+    fn iter_keys<'a>(&'a self) -> Box<Iterator<Item = Key> + 'a>
+        where Key: 'a
+    {
+        Box::new(self.chunks
+            .keys()
+            .filter_map(|serialised_key| serialisation::deserialise(serialised_key).ok()))
+    }
+
+    fn max_space(&self) -> u64 {
+        self.max_space
+    }
+
+    fn used_space(&self) -> u64 {
+        self.used_space
+    }
+
+    fn space_needed(&self, key: &Key, value: &Value) -> Result<u64, Error> {
+        let serialised_key = serialisation::serialise(key)?;
+        let old_size = self.chunks
+            .get(&serialised_key)
+            .map_or(0, |old_value| old_value.len() as u64);
+        Ok((serialisation::serialise(value)?.len() as u64).saturating_sub(old_size))
+    }
+
+    fn reserved(&self) -> &ReservedSpace {
+        &self.reserved_space
+    }
+}
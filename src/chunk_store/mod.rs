@@ -16,22 +16,65 @@
 // relating to use of the SAFE Network Software.
 
 //! # Chunk Store
-//! A simple, non-persistent, disk-based key-value store.
+//! A simple, disk-based key-value store, optionally persisting across restarts and compressing or
+//! encrypting the stored chunks.  The `ChunkStorage` trait allows other backends, like the
+//! in-memory `MemoryChunkStore`, the `CompositeChunkStore` spanning several disks or the
+//! `TieredChunkStore` combining a fast and a slow store, to be used in its place.
 
-use fs2::FileExt;
+mod chunk_file;
+mod composite;
+mod memory;
+mod name_index;
+mod pack;
+mod snapshot;
+mod tiered;
+
+pub use self::chunk_file::Compression;
+pub use self::composite::CompositeChunkStore;
+pub use self::memory::MemoryChunkStore;
+pub use self::name_index::NameIndex;
+pub use self::tiered::TieredChunkStore;
+
+use fs2::{self, FileExt};
 use maidsafe_utilities::serialisation::{self, SerialisationError};
+use rust_sodium::crypto::auth;
+use rust_sodium::crypto::hash::sha256;
+use rust_sodium::crypto::secretbox;
 use rustc_serialize::{Decodable, Encodable};
 use rustc_serialize::hex::{FromHex, ToHex};
+use std::cell::Cell;
 use std::cmp;
+use std::collections::HashMap;
+use std::ffi::OsString;
 use std::fs::{self, File};
 use std::io::{self, Read, Write};
 use std::marker::PhantomData;
 use std::path::{Path, PathBuf};
+use self::pack::Packs;
+use self::snapshot::ManifestEntry;
 
 /// The max name length for a chunk file.
 const MAX_CHUNK_FILE_NAME_LENGTH: usize = 104;
 /// The name of the lock file for the chunk directory.
 const LOCK_FILE_NAME: &'static str = "lock";
+/// The name of the directory corrupted chunk files are moved to.
+const QUARANTINE_DIR_NAME: &'static str = "quarantine";
+/// The name of the directory holding the segment files small chunks are packed into.
+const PACKS_DIR_NAME: &'static str = "packs";
+/// The size after which no more chunks are appended to a segment file.
+const MAX_PACK_SEGMENT_SIZE: u64 = 4 * 1024 * 1024;
+/// The name of the file recording the format version of the store.
+const FORMAT_FILE_NAME: &'static str = "format";
+/// The contents of the format file, followed by the version.
+const FORMAT_FILE_PREFIX: &'static str = "safe_vault chunk store format ";
+/// The extension of chunk files which are still being written.
+const TEMP_FILE_EXTENSION: &'static str = "tmp";
+/// The default number of nested directory levels the chunk files are spread across.
+const DEFAULT_DIR_DEPTH: usize = 2;
+/// The maximum number of nested directory levels.
+const MAX_DIR_DEPTH: usize = 8;
+/// The default amount of space to keep free on the filesystem holding the chunk files.
+const DEFAULT_FREE_SPACE_MARGIN: u64 = 100 * 1024 * 1024;
 
 quick_error! {
     /// `ChunkStore` error.
@@ -61,100 +104,525 @@ quick_error! {
             description("Key, Value not found")
             display("Key, Value not found")
         }
+        /// The stored chunk doesn't match its checksum.
+        Corrupted {
+            description("Chunk corrupted")
+            display("Chunk corrupted")
+        }
+        /// The stored chunk was compressed with an unknown algorithm.
+        UnknownCompression(code: u8) {
+            description("Unknown compression algorithm")
+            display("Unknown compression algorithm: {}", code)
+        }
+        /// The stored chunk is encrypted, but the store's key can't decrypt it.
+        Decryption {
+            description("Chunk decryption failed")
+            display("Chunk decryption failed")
+        }
+        /// The store or a chunk was written in a format version this vault doesn't know.
+        UnsupportedFormat(version: u8) {
+            description("Unsupported chunk store format")
+            display("Unsupported chunk store format version: {}", version)
+        }
+        /// The store was written in an older format version and wasn't allowed to be migrated.
+        OutdatedFormat(version: u8) {
+            description("Outdated chunk store format")
+            display("Chunk store format version {} needs to be migrated", version)
+        }
+        /// The store was opened read-only.
+        ReadOnly {
+            description("Chunk store is read-only")
+            display("Chunk store is read-only")
+        }
+        /// The snapshot to restore from is incomplete or doesn't match its manifest.
+        InvalidSnapshot {
+            description("Invalid chunk store snapshot")
+            display("Invalid chunk store snapshot")
+        }
+        /// The chunk store backend doesn't keep its chunks in directories which can be snapshotted.
+        SnapshotUnsupported {
+            description("Chunk store snapshots not supported")
+            display("The chunk store backend doesn't support snapshots")
+        }
+        /// The configured directory depth exceeds the maximum.
+        InvalidDirDepth(dir_depth: usize) {
+            description("Invalid chunk store directory depth")
+            display("Invalid chunk store directory depth {}, the maximum is {}",
+                    dir_depth,
+                    MAX_DIR_DEPTH)
+        }
     }
 }
 
+/// The operations provided by every chunk store backend.
+pub trait ChunkStorage<Key, Value> {
+    /// Stores a new data chunk under `key`.
+    ///
+    /// If there is not enough storage space available, returns `Error::NotEnoughSpace`.
+    ///
+    /// If the key already exists, it will be overwritten.
+    fn put(&mut self, key: &Key, value: &Value) -> Result<(), Error>;
+
+    /// Deletes the data chunk stored under `key`.
+    ///
+    /// If the data doesn't exist, it does nothing and returns `Ok`.
+    fn delete(&mut self, key: &Key) -> Result<(), Error>;
+
+    /// Returns a data chunk previously stored under `key`.
+    ///
+    /// If the data can't be found, it returns `Error::NotFound`.
+    fn get(&self, key: &Key) -> Result<Value, Error>;
+
+    /// Tests if a data chunk has been previously stored under `key`.
+    fn has(&self, key: &Key) -> bool;
+
+    /// Lists all keys of currently-data stored.
+    fn keys(&self) -> Vec<Key>;
+
+    /// Returns an iterator over the keys of the stored data, for backends which can list them
+    /// without collecting them all first.
+    fn iter_keys<'a>(&'a self) -> Box<Iterator<Item = Key> + 'a>
+        where Key: 'a
+    {
+        Box::new(self.keys().into_iter())
+    }
+
+    /// Checks the integrity of the data chunk stored under `key`.
+    ///
+    /// If the chunk is damaged, it returns `Error::Corrupted`.
+    fn verify(&self, key: &Key) -> Result<(), Error> {
+        self.get(key).map(|_| ())
+    }
+
+    /// Removes a corrupted data chunk from the store.
+    fn quarantine(&mut self, key: &Key) -> Result<(), Error> {
+        self.delete(key)
+    }
+
+    /// Returns the maximum amount of storage space available for this store.
+    fn max_space(&self) -> u64;
+
+    /// Returns the amount of storage space already used by this store.
+    fn used_space(&self) -> u64;
+
+    /// Returns how much the used space would grow if `value` was stored under `key`, replacing the
+    /// chunk currently stored under it.
+    fn space_needed(&self, key: &Key, value: &Value) -> Result<u64, Error>;
+
+    /// Returns the record of the storage space reserved in this store.
+    fn reserved(&self) -> &ReservedSpace;
+
+    /// Reserves `size` bytes for a chunk which is going to be stored later, so that further
+    /// reservations can't claim the same space.  Reservations don't restrict `put`, so they should
+    /// be released right before the chunk is stored.
+    ///
+    /// If less than `size` bytes are neither used nor reserved, returns `Error::NotEnoughSpace`.
+    fn reserve(&mut self, size: u64) -> Result<(), Error> {
+        let reserved_space = self.reserved_space();
+        if self.used_space() + reserved_space + size > self.max_space() {
+            return Err(Error::NotEnoughSpace);
+        }
+        self.reserved().0.set(reserved_space + size);
+        Ok(())
+    }
+
+    /// Releases `size` bytes reserved by `reserve`.
+    fn release(&mut self, size: u64) {
+        let reserved_space = self.reserved_space();
+        self.reserved().0.set(reserved_space - cmp::min(size, reserved_space));
+    }
+
+    /// Returns the amount of storage space currently reserved.
+    fn reserved_space(&self) -> u64 {
+        self.reserved().0.get()
+    }
+
+    /// Returns how well the chunks written to this store so far could be compressed.
+    fn compression_stats(&self) -> CompressionStats {
+        CompressionStats::default()
+    }
+
+    /// Checks whether the underlying storage is still usable.
+    fn check_health(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Returns the keys of the data chunks lost to storage failures since the last call.
+    fn take_lost_keys(&mut self) -> Vec<Key> {
+        Vec::new()
+    }
+
+    /// Moves data chunks between storage tiers according to how often they have been read.  Each
+    /// call only moves a limited number of chunks, so this is meant to be called periodically.
+    fn rebalance(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Updates the amount of free space left on the underlying storage, which limits `max_space`.
+    /// Querying it is comparatively expensive, so this is meant to be called periodically.
+    fn refresh_free_space(&mut self) {}
+
+    /// Writes a snapshot of each chunk store directory this store consists of to a new
+    /// subdirectory of the existing directory `dest`, named after its position in `stats`, and
+    /// appends its statistics to `stats`.  The directories are listed like the configured chunk
+    /// store roots: the cold tier's before the hot tier's.
+    ///
+    /// If the backend doesn't keep its chunks in directories, returns `Error::SnapshotUnsupported`.
+    fn snapshot_stores(&self, _dest: &Path, _stats: &mut Vec<SnapshotStats>) -> Result<(), Error> {
+        Err(Error::SnapshotUnsupported)
+    }
+}
+
+/// The storage space reserved in a chunk store by `ChunkStorage::reserve`.
+#[derive(Default)]
+pub struct ReservedSpace(Cell<u64>);
+
+/// The number of bytes written to a chunk store before and after compression.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CompressionStats {
+    /// The total size of the serialised values.
+    pub uncompressed_bytes: u64,
+    /// The total size of the chunk files written for them.
+    pub stored_bytes: u64,
+}
+
+impl CompressionStats {
+    /// Returns the stored size relative to the uncompressed size, or 1 if nothing was written.
+    pub fn ratio(&self) -> f64 {
+        if self.uncompressed_bytes == 0 {
+            1.0
+        } else {
+            self.stored_bytes as f64 / self.uncompressed_bytes as f64
+        }
+    }
+}
+
+/// The files written to a snapshot of a chunk store.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SnapshotStats {
+    /// The number of files in the snapshot.
+    pub files: u64,
+    /// The total size of the files.  Hardlinked files don't take up any additional space.
+    pub bytes: u64,
+}
+
+/// Checks that the snapshot in `snapshot_dir` is complete and matches its manifest, without
+/// restoring it.  Otherwise, returns `Error::InvalidSnapshot`.
+pub fn check_snapshot(snapshot_dir: &Path) -> Result<(), Error> {
+    snapshot::read_manifest(snapshot_dir).map(|_| ())
+}
+
+/// Returns whether the chunk store in `root` is currently open for writing, e.g. by a running
+/// vault.
+pub fn is_in_use(root: &Path) -> bool {
+    let lock_file = match File::open(root.join(LOCK_FILE_NAME)) {
+        Ok(lock_file) => lock_file,
+        Err(_) => return false,
+    };
+    match lock_file.try_lock_shared() {
+        Ok(()) => {
+            let _ = lock_file.unlock();
+            false
+        }
+        Err(error) => error.raw_os_error() == fs2::lock_contended_error().raw_os_error(),
+    }
+}
+
+/// Builder for a `ChunkStore`.
+pub struct ChunkStoreBuilder {
+    root: PathBuf,
+    max_space: u64,
+    persistent: bool,
+    dir_depth: usize,
+    compression: Compression,
+    encryption_key: Option<secretbox::Key>,
+    file_name_key: Option<auth::Key>,
+    free_space_margin: u64,
+    pack_threshold: u64,
+    read_only: bool,
+    migrate_format: bool,
+    restore_from: Option<PathBuf>,
+}
+
+impl ChunkStoreBuilder {
+    /// Creates a builder for a non-persistent, uncompressed and unencrypted `ChunkStore` in `root`,
+    /// with `max_space` allowed storage space and the default directory depth.
+    pub fn new(root: PathBuf, max_space: u64) -> ChunkStoreBuilder {
+        ChunkStoreBuilder {
+            root: root,
+            max_space: max_space,
+            persistent: false,
+            dir_depth: DEFAULT_DIR_DEPTH,
+            compression: Compression::None,
+            encryption_key: None,
+            file_name_key: None,
+            free_space_margin: DEFAULT_FREE_SPACE_MARGIN,
+            pack_threshold: 0,
+            read_only: false,
+            migrate_format: true,
+            restore_from: None,
+        }
+    }
+
+    /// Sets whether the chunks are kept on disk when the store is dropped and reopened when it is
+    /// created again.
+    pub fn persistent(self, persistent: bool) -> ChunkStoreBuilder {
+        ChunkStoreBuilder { persistent: persistent, ..self }
+    }
+
+    /// Sets the number of nested directory levels the chunk files are spread across.  Each level
+    /// is named after the next two hex digits of the hash of the chunk's key.  If it exceeds
+    /// `MAX_DIR_DEPTH`, `create` fails with `Error::InvalidDirDepth`.
+    pub fn dir_depth(self, dir_depth: usize) -> ChunkStoreBuilder {
+        ChunkStoreBuilder { dir_depth: dir_depth, ..self }
+    }
+
+    /// Sets the algorithm newly written chunks are compressed with.  Chunks which don't get
+    /// smaller are stored uncompressed, and chunks already on disk are read regardless of the
+    /// algorithm they were written with.
+    pub fn compression(self, compression: Compression) -> ChunkStoreBuilder {
+        ChunkStoreBuilder { compression: compression, ..self }
+    }
+
+    /// Sets the key newly written chunks are encrypted with.  Encrypted chunks already on disk can
+    /// only be read with the key they were written with.
+    pub fn encryption_key(self, encryption_key: secretbox::Key) -> ChunkStoreBuilder {
+        ChunkStoreBuilder { encryption_key: Some(encryption_key), ..self }
+    }
+
+    /// Sets the key the chunk file names are derived from, so that they don't reveal the chunks'
+    /// keys.  The keys are stored inside the chunk files instead.  Chunks written without a file
+    /// name key are converted when a writable store is opened with one, but a store with hashed
+    /// file names must always be opened with the same key.
+    pub fn file_name_key(self, file_name_key: auth::Key) -> ChunkStoreBuilder {
+        ChunkStoreBuilder { file_name_key: Some(file_name_key), ..self }
+    }
+
+    /// Sets the amount of space the store leaves free on the filesystem holding `root`, even if
+    /// `max_space` hasn't been reached yet.
+    pub fn free_space_margin(self, free_space_margin: u64) -> ChunkStoreBuilder {
+        ChunkStoreBuilder { free_space_margin: free_space_margin, ..self }
+    }
+
+    /// Sets the size up to which chunks are appended to shared segment files instead of getting a
+    /// file of their own, which saves filesystem blocks and inodes for small chunks.  Outdated
+    /// entries are reclaimed by compacting the segment files.  The default of 0 disables packing.
+    pub fn pack_threshold(self, pack_threshold: u64) -> ChunkStoreBuilder {
+        ChunkStoreBuilder { pack_threshold: pack_threshold, ..self }
+    }
+
+    /// Sets whether an existing store is opened for inspection only.  A read-only store is always
+    /// persistent and only shares the lock on `root`, so it can't be opened while it is in use.
+    /// Nothing in `root` is modified, not even chunk files outside the configured directory layout,
+    /// which can't be read then.  Writing chunks fails with `Error::ReadOnly`.
+    pub fn read_only(self, read_only: bool) -> ChunkStoreBuilder {
+        ChunkStoreBuilder { read_only: read_only, ..self }
+    }
 
+    /// Sets whether a store written in an older format version is migrated to the current one when
+    /// it is opened.  If not, or if the store is read-only, opening it fails with
+    /// `Error::OutdatedFormat` instead.  Stores in a newer format version always fail to open with
+    /// `Error::UnsupportedFormat`.
+    pub fn migrate_format(self, migrate_format: bool) -> ChunkStoreBuilder {
+        ChunkStoreBuilder { migrate_format: migrate_format, ..self }
+    }
+
+    /// Sets a snapshot written by `ChunkStore::snapshot` to replace the contents of `root` with.
+    /// The store is opened as persistent afterwards.  If the snapshot is incomplete or its files
+    /// don't match the manifest, `create` fails with `Error::InvalidSnapshot` and leaves `root`
+    /// untouched.  The snapshot is restored into a new directory next to `root` first, which then
+    /// takes `root`'s place, so a failure while restoring also leaves `root` untouched.
+    pub fn restore_from(self, snapshot_dir: PathBuf) -> ChunkStoreBuilder {
+        ChunkStoreBuilder { restore_from: Some(snapshot_dir), ..self }
+    }
+
+    /// Creates the `ChunkStore`.
+    ///
+    /// If `root` doesn't exist, it will be created.  For a persistent store, any chunks already in
+    /// `root` are moved into the configured directory layout and accounted for in `used_space`;
+    /// otherwise they are removed.
+    pub fn create<Key, Value>(self) -> Result<ChunkStore<Key, Value>, Error>
+        where Key: Decodable + Encodable,
+              Value: Decodable + Encodable
+    {
+        if self.dir_depth > MAX_DIR_DEPTH {
+            return Err(Error::InvalidDirDepth(self.dir_depth));
+        }
+        let lock_file = if self.read_only {
+            ChunkStore::<Key, Value>::lock_dir_shared(&self.root)?
+        } else if let Some(ref snapshot_dir) = self.restore_from {
+            let entries = snapshot::read_manifest(snapshot_dir)?;
+            ChunkStore::<Key, Value>::restore_dir(snapshot_dir, &entries, &self.root)?
+        } else if self.persistent {
+            ChunkStore::<Key, Value>::lock_dir(&self.root)?
+        } else {
+            ChunkStore::<Key, Value>::lock_and_clear_dir(&self.root)?
+        };
+        let packs_dir = self.root.join(PACKS_DIR_NAME);
+        let packs = if self.read_only {
+            if packs_dir.is_dir() {
+                Some(Packs::open_read_only(packs_dir)?)
+            } else {
+                None
+            }
+        } else if self.pack_threshold > 0 || packs_dir.is_dir() {
+            Some(Packs::open(packs_dir, MAX_PACK_SEGMENT_SIZE)?)
+        } else {
+            None
+        };
+        let mut chunk_store = ChunkStore {
+            rootdir: self.root,
+            lock_file: Some(lock_file),
+            max_space: self.max_space,
+            used_space: 0,
+            reserved_space: ReservedSpace::default(),
+            persistent: self.persistent || self.read_only || self.restore_from.is_some(),
+            read_only: self.read_only,
+            dir_depth: self.dir_depth,
+            compression: self.compression,
+            compression_stats: CompressionStats::default(),
+            encryption_key: self.encryption_key,
+            file_name_key: self.file_name_key,
+            hashed_keys: HashMap::new(),
+            free_space_margin: self.free_space_margin,
+            free_space_limit: u64::max_value(),
+            packs: packs,
+            pack_threshold: self.pack_threshold,
+            phantom: PhantomData,
+        };
+        if chunk_store.read_only {
+            chunk_store.check_format(false)?;
+            let chunk_files = chunk_store.chunk_files()?;
+            chunk_store.index_chunk_files(chunk_files)?;
+            chunk_store.index_packs()?;
+        } else if chunk_store.persistent {
+            chunk_store.remove_temp_files()?;
+            chunk_store.check_format(self.migrate_format)?;
+            let chunk_files = chunk_store.migrate_layout()?;
+            chunk_store.index_chunk_files(chunk_files)?;
+            chunk_store.index_packs()?;
+        } else {
+            chunk_store.check_format(false)?;
+        }
+        chunk_store.refresh_free_space();
+        Ok(chunk_store)
+    }
+}
 
 /// `ChunkStore` is a store of data held as serialised files on disk, implementing a maximum disk
-/// usage to restrict storage.
+/// usage to restrict storage.  The maximum is lowered when the filesystem runs out of free space.
 ///
-/// The data chunks are deleted when the `ChunkStore` goes out of scope.
+/// The chunk files are spread across nested subdirectories to keep directory sizes small, and small
+/// chunks may be packed into shared segment files instead.  The maximum disk usage applies to the
+/// chunk files' actual, possibly compressed, size.  Unless the store was opened as persistent, the
+/// data chunks are deleted when the `ChunkStore` goes out of scope.
 pub struct ChunkStore<Key, Value> {
     rootdir: PathBuf,
     lock_file: Option<File>,
     max_space: u64,
     used_space: u64,
+    reserved_space: ReservedSpace,
+    persistent: bool,
+    read_only: bool,
+    dir_depth: usize,
+    compression: Compression,
+    compression_stats: CompressionStats,
+    encryption_key: Option<secretbox::Key>,
+    file_name_key: Option<auth::Key>,
+    /// The serialised keys of the chunk files, by path.  Only used if the file names are hashed.
+    hashed_keys: HashMap<PathBuf, Vec<u8>>,
+    free_space_margin: u64,
+    /// The size the store can grow to before the free space on the filesystem falls below the
+    /// margin, as of the last call to `refresh_free_space`.
+    free_space_limit: u64,
+    /// The segment files small chunks are packed into, named like their chunk files would be.
+    packs: Option<Packs>,
+    pack_threshold: u64,
     phantom: PhantomData<(Key, Value)>,
 }
 
-impl<Key, Value> ChunkStore<Key, Value>
+impl<Key, Value> ChunkStorage<Key, Value> for ChunkStore<Key, Value>
     where Key: Decodable + Encodable,
           Value: Decodable + Encodable
 {
-    /// Creates a new `ChunkStore` with `max_space` allowed storage space.
-    ///
-    /// The data is stored in a root directory. If `root` doesn't exist, it will be created.
-    pub fn new(root: PathBuf, max_space: u64) -> Result<ChunkStore<Key, Value>, Error> {
-        let lock_file = Self::lock_and_clear_dir(&root)?;
-        Ok(ChunkStore {
-            rootdir: root,
-            lock_file: Some(lock_file),
-            max_space: max_space,
-            used_space: 0,
-            phantom: PhantomData,
-        })
-    }
-
     /// Stores a new data chunk under `key`.
     ///
     /// If there is not enough storage space available, returns `Error::NotEnoughSpace`.  In case of
     /// an IO error, it returns `Error::Io`.
     ///
-    /// If the key already exists, it will be overwritten.
-    pub fn put(&mut self, key: &Key, value: &Value) -> Result<(), Error> {
-        let serialised_value = serialisation::serialise(value)?;
-        if self.used_space + serialised_value.len() as u64 > self.max_space {
+    /// If the key already exists, it will be atomically replaced.
+    fn put(&mut self, key: &Key, value: &Value) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        let serialised_key = serialisation::serialise(key)?;
+        let (serialised_value, contents) = self.encode_chunk(&serialised_key, value)?;
+        let file_path = self.file_path(key)?;
+        let old_size = file_size(&file_path);
+        let used_space = self.used_space();
+        if used_space - cmp::min(old_size, used_space) + contents.len() as u64 > self.max_space() {
             return Err(Error::NotEnoughSpace);
         }
 
-        // If a file corresponding to 'key' already exists, delete it.
-        let file_path = self.file_path(key)?;
-        let _ = self.do_delete(&file_path);
+        let name = pack_name(&file_path);
+        if self.packs.is_some() && contents.len() as u64 <= self.pack_threshold {
+            if let Some(ref mut packs) = self.packs {
+                packs.put(&name, &contents)?;
+            }
+            if old_size > 0 {
+                fs::remove_file(&file_path)?;
+            }
+        } else {
+            self.write_file(&file_path, &contents)?;
+            if let Some(ref mut packs) = self.packs {
+                let _ = packs.delete(&name)?;
+            }
+            self.used_space += contents.len() as u64;
+        }
+        self.used_space -= cmp::min(old_size, self.used_space);
 
-        // Write the file.
-        File::create(&file_path)
-            .and_then(|mut file| {
-                file.write_all(&serialised_value)
-                    .and_then(|()| file.sync_all())
-                    .and_then(|()| file.metadata())
-                    .map(|metadata| {
-                        self.used_space += metadata.len();
-                    })
-            })
-            .map_err(From::from)
+        self.compression_stats.uncompressed_bytes += serialised_value.len() as u64;
+        self.compression_stats.stored_bytes += contents.len() as u64;
+        if self.file_name_key.is_some() {
+            let _ = self.hashed_keys.insert(file_path, serialised_key);
+        }
+        Ok(())
     }
 
     /// Deletes the data chunk stored under `key`.
     ///
     /// If the data doesn't exist, it does nothing and returns `Ok`.  In the case of an IO error, it
     /// returns `Error::Io`.
-    pub fn delete(&mut self, key: &Key) -> Result<(), Error> {
+    fn delete(&mut self, key: &Key) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
         let file_path = self.file_path(key)?;
         self.do_delete(&file_path)
     }
 
     /// Returns a data chunk previously stored under `key`.
     ///
-    /// If the data file can't be accessed, it returns `Error::ChunkNotFound`.
-    pub fn get(&self, key: &Key) -> Result<Value, Error> {
-        match File::open(self.file_path(key)?) {
-            Ok(mut file) => {
-                let mut contents = Vec::<u8>::new();
-                let _ = file.read_to_end(&mut contents)?;
-                Ok(serialisation::deserialise::<Value>(&contents)?)
-            }
-            Err(_) => Err(Error::NotFound),
-        }
+    /// If the data file can't be accessed, it returns `Error::ChunkNotFound`.  If it doesn't match
+    /// its checksum, it returns `Error::Corrupted`, and if it can't be decrypted,
+    /// `Error::Decryption`.
+    fn get(&self, key: &Key) -> Result<Value, Error> {
+        let contents = self.read_chunk(&self.file_path(key)?)?;
+        let (_, serialised_value) = chunk_file::decode(&contents, self.encryption_key.as_ref())?;
+        Ok(serialisation::deserialise::<Value>(&serialised_value)?)
     }
 
-    /// Tests if a data chunk has been previously stored under `key`.
-    pub fn has(&self, key: &Key) -> bool {
+    fn has(&self, key: &Key) -> bool {
         let file_path = if let Ok(path) = self.file_path(key) {
             path
         } else {
             return false;
         };
+        if let Some(ref packs) = self.packs {
+            if packs.contains(&pack_name(&file_path)) {
+                return true;
+            }
+        }
         if let Ok(metadata) = fs::metadata(file_path) {
             return metadata.is_file();
         } else {
@@ -162,33 +630,265 @@ impl<Key, Value> ChunkStore<Key, Value>
         }
     }
 
-    /// Lists all keys of currently-data stored.
-    pub fn keys(&self) -> Vec<Key> {
-        fs::read_dir(&self.rootdir)
-            .and_then(|dir_entries| {
-                let dir_entry_to_routing_name = |dir_entry: io::Result<fs::DirEntry>| {
-                    dir_entry.ok()
-                        .and_then(|entry| entry.file_name().into_string().ok())
-                        .and_then(|hex_name| hex_name.from_hex().ok())
-                        .and_then(|bytes| serialisation::deserialise::<Key>(&*bytes).ok())
-                };
-                Ok(dir_entries.filter_map(dir_entry_to_routing_name).collect())
-            })
-            .unwrap_or_else(|_| Vec::new())
+    fn keys(&self) -> Vec<Key> {
+        self.iter_keys().collect()
     }
 
-    /// Returns the maximum amount of storage space available for this ChunkStore.
-    pub fn max_space(&self) -> u64 {
-        self.max_space
+    /// Walks the chunk directories while iterating, unless the file names are hashed, in which
+    /// case the keys are already held in memory.
+    fn iter_keys<'a>(&'a self) -> Box<Iterator<Item = Key> + 'a>
+        where Key: 'a
+    {
+        if self.file_name_key.is_some() {
+            return Box::new(self.hashed_keys
+                .values()
+                .filter_map(|serialised_key| serialisation::deserialise(serialised_key).ok()));
+        }
+        let chunk_file_keys = ChunkFileKeys {
+            chunk_store: self,
+            dirs: vec![self.rootdir.clone()],
+            entries: None,
+        };
+        let pack_keys = self.packs
+            .iter()
+            .flat_map(|packs| packs.names().into_iter())
+            .filter_map(|name| name.from_hex().ok())
+            .filter_map(|bytes| serialisation::deserialise(&bytes).ok());
+        Box::new(chunk_file_keys.chain(pack_keys))
     }
 
-    /// Returns the amount of storage space already used by this ChunkStore.
-    pub fn used_space(&self) -> u64 {
-        self.used_space
+    fn verify(&self, key: &Key) -> Result<(), Error> {
+        let contents = self.read_chunk(&self.file_path(key)?)?;
+        chunk_file::decode(&contents, self.encryption_key.as_ref()).map(|_| ())
+    }
+
+    /// Moves the chunk file, or a copy of the packed chunk, into the quarantine directory, where it
+    /// is no longer accounted for in `used_space` and can be inspected or removed by the operator.
+    fn quarantine(&mut self, key: &Key) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        let file_path = self.file_path(key)?;
+        let name = pack_name(&file_path);
+        if let Some(ref mut packs) = self.packs {
+            if let Some(contents) = packs.get(&name)? {
+                let quarantine_dir = self.rootdir.join(QUARANTINE_DIR_NAME);
+                fs::create_dir_all(&quarantine_dir)?;
+                let mut file = File::create(quarantine_dir.join(&name))?;
+                file.write_all(&contents)?;
+                let _ = packs.delete(&name)?;
+                let _ = self.hashed_keys.remove(&file_path);
+                return Ok(());
+            }
+        }
+        let metadata = match fs::metadata(&file_path) {
+            Ok(metadata) => metadata,
+            Err(_) => return Err(Error::NotFound),
+        };
+        let quarantine_dir = self.rootdir.join(QUARANTINE_DIR_NAME);
+        fs::create_dir_all(&quarantine_dir)?;
+        if let Some(file_name) = file_path.file_name() {
+            fs::rename(&file_path, quarantine_dir.join(file_name))?;
+        }
+        let _ = self.hashed_keys.remove(&file_path);
+        self.used_space -= cmp::min(metadata.len(), self.used_space);
+        Ok(())
+    }
+
+    /// Returns the configured maximum, or less if the filesystem didn't have that much space left
+    /// apart from the free space margin when it was last checked.
+    fn max_space(&self) -> u64 {
+        cmp::min(self.max_space, self.free_space_limit)
+    }
+
+    /// Includes the full size of the segment files, up to their next compaction.
+    fn used_space(&self) -> u64 {
+        self.used_space + self.packs.as_ref().map_or(0, Packs::size)
+    }
+
+    /// Like `put`, this encodes the chunk, and only counts the replaced chunk file, not a replaced
+    /// packed chunk.
+    fn space_needed(&self, key: &Key, value: &Value) -> Result<u64, Error> {
+        let serialised_key = serialisation::serialise(key)?;
+        let (_, contents) = self.encode_chunk(&serialised_key, value)?;
+        Ok((contents.len() as u64).saturating_sub(file_size(&self.file_path(key)?)))
+    }
+
+    fn reserved(&self) -> &ReservedSpace {
+        &self.reserved_space
+    }
+
+    /// Only covers the chunks written since the store was created.
+    fn compression_stats(&self) -> CompressionStats {
+        self.compression_stats
+    }
+
+    /// Checks that the root directory and the lock file inside it can still be accessed.
+    fn check_health(&self) -> Result<(), Error> {
+        let _ = fs::read_dir(&self.rootdir)?;
+        let _ = fs::metadata(self.rootdir.join(LOCK_FILE_NAME))?;
+        Ok(())
+    }
+
+    fn refresh_free_space(&mut self) {
+        self.free_space_limit = match fs2::available_space(&self.rootdir) {
+            Ok(available_space) => {
+                self.used_space()
+                    .saturating_add(available_space.saturating_sub(self.free_space_margin))
+            }
+            Err(error) => {
+                warn!("Failed to get the available space for {}: {}",
+                      self.rootdir.display(),
+                      error);
+                u64::max_value()
+            }
+        };
+    }
+
+    fn snapshot_stores(&self, dest: &Path, stats: &mut Vec<SnapshotStats>) -> Result<(), Error> {
+        let store_stats = self.snapshot(&dest.join(stats.len().to_string()))?;
+        stats.push(store_stats);
+        Ok(())
+    }
+}
+
+impl<Key, Value> ChunkStore<Key, Value>
+    where Key: Decodable + Encodable,
+          Value: Decodable + Encodable
+{
+    /// Creates a new, non-persistent `ChunkStore` with `max_space` allowed storage space.
+    ///
+    /// The data is stored in a root directory. If `root` doesn't exist, it will be created.
+    pub fn new(root: PathBuf, max_space: u64) -> Result<ChunkStore<Key, Value>, Error> {
+        ChunkStoreBuilder::new(root, max_space).create()
+    }
+
+    /// Opens a persistent `ChunkStore` with `max_space` allowed storage space, keeping any chunks
+    /// already stored in `root`.
+    pub fn open(root: PathBuf, max_space: u64) -> Result<ChunkStore<Key, Value>, Error> {
+        ChunkStoreBuilder::new(root, max_space).persistent(true).create()
+    }
+
+    /// Returns the size the chunk stored under `key` takes up on disk.
+    ///
+    /// If the data can't be found, it returns `Error::NotFound`.
+    pub fn stored_size(&self, key: &Key) -> Result<u64, Error> {
+        let file_path = self.file_path(key)?;
+        if let Some(contents_len) = self.packs
+            .as_ref()
+            .and_then(|packs| packs.contents_len(&pack_name(&file_path))) {
+            return Ok(contents_len);
+        }
+        match fs::metadata(&file_path) {
+            Ok(ref metadata) if metadata.is_file() => Ok(metadata.len()),
+            _ => Err(Error::NotFound),
+        }
+    }
+
+    /// Returns the paths of the files in the chunk directories which don't belong to any key,
+    /// e.g. because their names or, if the names are hashed, their contents are damaged.
+    /// Quarantined and packed chunks aren't included.
+    pub fn stray_files(&self) -> Result<Vec<PathBuf>, Error> {
+        let mut stray_files = Vec::new();
+        let mut dirs = vec![self.rootdir.clone()];
+        let lock_file_path = self.rootdir.join(LOCK_FILE_NAME);
+        let format_file_path = self.rootdir.join(FORMAT_FILE_NAME);
+        let quarantine_dir = self.rootdir.join(QUARANTINE_DIR_NAME);
+        let packs_dir = self.rootdir.join(PACKS_DIR_NAME);
+        while let Some(dir) = dirs.pop() {
+            for entry_result in fs::read_dir(&dir)? {
+                let entry = entry_result?;
+                let path = entry.path();
+                if entry.file_type()?.is_dir() {
+                    if path != quarantine_dir && path != packs_dir {
+                        dirs.push(path);
+                    }
+                } else if path != lock_file_path && path != format_file_path &&
+                          self.chunk_file_key(entry.file_name(), &path).is_none() {
+                    stray_files.push(path);
+                }
+            }
+        }
+        Ok(stray_files)
+    }
+
+    /// Writes a snapshot of the store to the new directory `dest`, which should be on the same
+    /// filesystem so that the files can be hardlinked instead of copied.  As this borrows the
+    /// store, no chunks can be written until it returns.
+    ///
+    /// The chunk files are always replaced instead of modified, so the snapshot isn't affected by
+    /// later writes to the store.  The pack segments are appended to, so they are copied.
+    /// Quarantined chunks and temporary files are left out.
+    pub fn snapshot(&self, dest: &Path) -> Result<SnapshotStats, Error> {
+        let mut files = Vec::new();
+        let mut dirs = vec![self.rootdir.clone()];
+        let lock_file_path = self.rootdir.join(LOCK_FILE_NAME);
+        let quarantine_dir = self.rootdir.join(QUARANTINE_DIR_NAME);
+        let packs_dir = self.rootdir.join(PACKS_DIR_NAME);
+        while let Some(dir) = dirs.pop() {
+            for entry_result in fs::read_dir(&dir)? {
+                let entry = entry_result?;
+                let path = entry.path();
+                if entry.file_type()?.is_dir() {
+                    if path != quarantine_dir {
+                        dirs.push(path);
+                    }
+                } else if path != lock_file_path &&
+                          path.extension().map_or(true, |ext| ext != TEMP_FILE_EXTENSION) {
+                    let copy = path.starts_with(&packs_dir);
+                    files.push((path, copy));
+                }
+            }
+        }
+        snapshot::create(&self.rootdir, dest, files)
+    }
+
+    /// Restores the snapshot in `snapshot_dir` into a new directory next to `root`, then replaces
+    /// `root` with it and returns a locked file inside it.
+    fn restore_dir(snapshot_dir: &Path,
+                   entries: &[ManifestEntry],
+                   root: &PathBuf)
+                   -> Result<File, Error> {
+        // Fail early if the store is in use.  The lock is released again before the directories
+        // are renamed, as open files prevent that on some platforms.
+        drop(Self::lock_dir(root)?);
+        let restored_dir = sibling_dir(root, ".restoring");
+        let replaced_dir = sibling_dir(root, ".replaced");
+        for dir in &[&restored_dir, &replaced_dir] {
+            if dir.exists() {
+                fs::remove_dir_all(dir)?;
+            }
+        }
+        fs::create_dir_all(&restored_dir)?;
+        if let Err(error) = snapshot::restore(snapshot_dir, &restored_dir, entries) {
+            let _ = fs::remove_dir_all(&restored_dir);
+            return Err(error);
+        }
+        fs::rename(root, &replaced_dir)?;
+        fs::rename(&restored_dir, root)?;
+        fs::remove_dir_all(&replaced_dir)?;
+        Self::lock_dir(root)
     }
 
     /// Creates and clears the given root directory and returns a locked file inside it.
     fn lock_and_clear_dir(root: &PathBuf) -> Result<File, Error> {
+        let lock_file = Self::lock_dir(root)?;
+
+        // Clear the chunk directory.
+        let lock_file_path = root.join(LOCK_FILE_NAME);
+        for entry_result in fs::read_dir(&root)? {
+            let entry = entry_result?;
+            if entry.file_type()?.is_dir() {
+                fs::remove_dir_all(entry.path())?;
+            } else if entry.path() != lock_file_path.as_path() {
+                fs::remove_file(entry.path())?;
+            }
+        }
+        Ok(lock_file)
+    }
+
+    /// Creates the given root directory if needed and returns a locked file inside it.
+    fn lock_dir(root: &PathBuf) -> Result<File, Error> {
         // Create the chunk directory and a lock file.
         fs::create_dir_all(&root)?;
         let lock_file_path = root.join(LOCK_FILE_NAME);
@@ -197,19 +897,398 @@ impl<Key, Value> ChunkStore<Key, Value>
 
         // Verify that chunk files can be created.
         let name: String = (0..MAX_CHUNK_FILE_NAME_LENGTH).map(|_| '0').collect();
-        let _ = File::create(&root.join(name))?;
+        let test_file_path = root.join(name);
+        let _ = File::create(&test_file_path)?;
+        fs::remove_file(test_file_path)?;
 
-        // Clear the chunk directory.
-        for entry_result in fs::read_dir(&root)? {
-            let entry = entry_result?;
-            if entry.path() != lock_file_path.as_path() {
-                fs::remove_file(entry.path())?;
+        Ok(lock_file)
+    }
+
+    /// Returns a file inside the given existing root directory, holding a shared lock.
+    fn lock_dir_shared(root: &PathBuf) -> Result<File, Error> {
+        let lock_file = File::open(root.join(LOCK_FILE_NAME))?;
+        lock_file.try_lock_shared()?;
+        Ok(lock_file)
+    }
+
+    /// Checks the format version recorded in the root directory, migrating the chunks from an older
+    /// version if allowed.  A store without a recorded version is new if it is empty, and written
+    /// in the unversioned format otherwise.
+    fn check_format(&mut self, migrate: bool) -> Result<(), Error> {
+        let format_file_path = self.rootdir.join(FORMAT_FILE_NAME);
+        let version = match File::open(&format_file_path) {
+            Ok(mut file) => {
+                let mut contents = String::new();
+                let _ = file.read_to_string(&mut contents)?;
+                if !contents.starts_with(FORMAT_FILE_PREFIX) {
+                    return Err(Error::Corrupted);
+                }
+                contents[FORMAT_FILE_PREFIX.len()..]
+                    .trim()
+                    .parse::<u8>()
+                    .map_err(|_| Error::Corrupted)?
+            }
+            Err(ref error) if error.kind() == io::ErrorKind::NotFound => {
+                let is_empty = self.raw_chunk_files()?.is_empty() &&
+                               self.packs.as_ref().map_or(true, |packs| packs.names().is_empty());
+                if is_empty {
+                    chunk_file::FORMAT_VERSION
+                } else {
+                    chunk_file::UNVERSIONED_FORMAT_VERSION
+                }
             }
+            Err(error) => return Err(From::from(error)),
+        };
+        if version > chunk_file::FORMAT_VERSION {
+            return Err(Error::UnsupportedFormat(version));
+        }
+        if version < chunk_file::FORMAT_VERSION {
+            if !migrate || self.read_only {
+                return Err(Error::OutdatedFormat(version));
+            }
+            self.migrate_format(version)?;
+        }
+        if !self.read_only {
+            let contents = format!("{}{}\n", FORMAT_FILE_PREFIX, chunk_file::FORMAT_VERSION);
+            self.write_file(&format_file_path, contents.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Rewrites all chunk files and packed chunks written in the given older format version in the
+    /// current one.
+    fn migrate_format(&mut self, version: u8) -> Result<(), Error> {
+        let file_paths = self.raw_chunk_files()?;
+        for file_path in &file_paths {
+            let contents = self.read_file(file_path)?;
+            let contents = if version == chunk_file::UNVERSIONED_FORMAT_VERSION {
+                self.migrate_unversioned_file(file_path, contents)?
+            } else {
+                chunk_file::migrate(contents, version)?
+            };
+            self.write_file(file_path, &contents)?;
+        }
+        let mut packed_count = 0;
+        if let Some(ref mut packs) = self.packs {
+            for name in packs.names() {
+                if let Some(contents) = packs.get(&name)? {
+                    packs.put(&name, &chunk_file::migrate(contents, version)?)?;
+                    packed_count += 1;
+                }
+            }
+        }
+        info!("Migrated {} chunk files and {} packed chunks from format version {} to {}.",
+              file_paths.len(),
+              packed_count,
+              version,
+              chunk_file::FORMAT_VERSION);
+        Ok(())
+    }
+
+    /// Converts the contents of a chunk file written before the format version was recorded to the
+    /// current layout.  Files from before the compression format was recorded, and bare serialised
+    /// values from before chunks were checksummed, are recognised by their value deserialising, and
+    /// are encoded again like a new chunk.  Files which can't be read in any of the old layouts are
+    /// converted as if they were in the last one, so they are reported as corrupted when read.
+    fn migrate_unversioned_file(&self,
+                                file_path: &Path,
+                                contents: Vec<u8>)
+                                -> Result<Vec<u8>, Error> {
+        let migrated = chunk_file::migrate(contents.clone(),
+                                           chunk_file::UNVERSIONED_FORMAT_VERSION)?;
+        let is_value = |serialised_value: &[u8]| {
+            serialisation::deserialise::<Value>(serialised_value).is_ok()
+        };
+        if let Ok((_, serialised_value)) = chunk_file::decode(&migrated,
+                                                              self.encryption_key.as_ref()) {
+            if is_value(&serialised_value[..]) {
+                return Ok(migrated);
+            }
+        }
+        let serialised_value = match chunk_file::legacy_value(&contents) {
+            Some(serialised_value) if is_value(serialised_value) => serialised_value,
+            _ if is_value(&contents) => &contents[..],
+            _ => return Ok(migrated),
+        };
+        // Chunk files were named after their serialised key before the names could be hashed.
+        let serialised_key = file_path.file_name()
+            .and_then(|file_name| file_name.to_str())
+            .and_then(|hex_name| hex_name.from_hex().ok());
+        let embedded_key = if self.file_name_key.is_some() {
+            serialised_key.as_ref().map(|serialised_key| &serialised_key[..])
+        } else {
+            None
+        };
+        chunk_file::encode(embedded_key,
+                           serialised_value,
+                           self.compression,
+                           self.encryption_key.as_ref())
+    }
+
+    /// Returns the paths of all files below the root directory which are named like chunk files,
+    /// without reading them.  Quarantined and packed chunks aren't included.
+    fn raw_chunk_files(&self) -> Result<Vec<PathBuf>, Error> {
+        let mut file_paths = Vec::new();
+        let mut dirs = vec![self.rootdir.clone()];
+        let quarantine_dir = self.rootdir.join(QUARANTINE_DIR_NAME);
+        let packs_dir = self.rootdir.join(PACKS_DIR_NAME);
+        while let Some(dir) = dirs.pop() {
+            for entry_result in fs::read_dir(&dir)? {
+                let entry = entry_result?;
+                let path = entry.path();
+                if entry.file_type()?.is_dir() {
+                    if path != quarantine_dir && path != packs_dir {
+                        dirs.push(path);
+                    }
+                } else if entry.file_name()
+                    .into_string()
+                    .ok()
+                    .map_or(false, |name| name.from_hex().is_ok()) {
+                    file_paths.push(path);
+                }
+            }
+        }
+        Ok(file_paths)
+    }
+
+    /// Moves every chunk file which isn't where the configured directory layout expects it, e.g.
+    /// after the directory depth was changed or when opening a store with the old flat layout.
+    /// Chunk files written before the file names were hashed are rewritten with their key.
+    /// Returns the keys and new paths of all chunk files.
+    fn migrate_layout(&self) -> Result<Vec<(Key, PathBuf)>, Error> {
+        let mut moved_count = 0;
+        let mut chunk_files = self.chunk_files()?;
+        for &mut (ref key, ref mut path) in &mut chunk_files {
+            let new_path = self.file_path(key)?;
+            if new_path != *path {
+                if let Some(dir) = new_path.parent() {
+                    fs::create_dir_all(dir)?;
+                }
+                match self.embed_key(key, &self.read_file(&*path)?)? {
+                    Some(contents) => {
+                        self.write_file(&new_path, &contents)?;
+                        fs::remove_file(&*path)?;
+                    }
+                    None => fs::rename(&*path, &new_path)?,
+                }
+                *path = new_path;
+                moved_count += 1;
+            }
+        }
+        if moved_count > 0 {
+            let _ = remove_empty_dirs(&self.rootdir)?;
+            info!("Moved {} chunk files to a directory layout of depth {}.",
+                  moved_count,
+                  self.dir_depth);
+        }
+        Ok(chunk_files)
+    }
+
+    /// Accounts for the given chunk files in `used_space`, and records their keys if the file
+    /// names are hashed.
+    fn index_chunk_files(&mut self, chunk_files: Vec<(Key, PathBuf)>) -> Result<(), Error> {
+        for (key, file_path) in chunk_files {
+            self.used_space += fs::metadata(&file_path)?.len();
+            if self.file_name_key.is_some() {
+                let _ = self.hashed_keys.insert(file_path, serialisation::serialise(&key)?);
+            }
+        }
+        Ok(())
+    }
+
+    /// Records the keys of the packed chunks if the names are hashed.  Unless the store is
+    /// read-only, chunks packed before the names were hashed are packed again under their hashed
+    /// name, with their key.
+    fn index_packs(&mut self) -> Result<(), Error> {
+        if self.file_name_key.is_none() {
+            return Ok(());
+        }
+        let names = self.packs.as_ref().map_or_else(Vec::new, Packs::names);
+        for name in names {
+            let contents = match self.packs.as_ref() {
+                Some(packs) => packs.get(&name)?,
+                None => None,
+            };
+            let decoded = contents.map(|contents| {
+                chunk_file::decode(&contents, self.encryption_key.as_ref())
+            });
+            match decoded {
+                Some(Ok((Some(serialised_key), _))) => {
+                    if let Ok(key) = serialisation::deserialise::<Key>(&serialised_key) {
+                        let file_path = self.file_path(&key)?;
+                        let _ = self.hashed_keys.insert(file_path, serialised_key);
+                        continue;
+                    }
+                }
+                Some(Ok((None, ref serialised_value))) if !self.read_only => {
+                    let key = name.from_hex()
+                        .ok()
+                        .and_then(|bytes| serialisation::deserialise::<Key>(&bytes).ok());
+                    if let Some(key) = key {
+                        let serialised_key = serialisation::serialise(&key)?;
+                        let contents = chunk_file::encode(Some(&serialised_key),
+                                                          serialised_value,
+                                                          self.compression,
+                                                          self.encryption_key.as_ref())?;
+                        let file_path = self.file_path(&key)?;
+                        if let Some(ref mut packs) = self.packs {
+                            packs.put(&pack_name(&file_path), &contents)?;
+                            let _ = packs.delete(&name)?;
+                        }
+                        let _ = self.hashed_keys.insert(file_path, serialised_key);
+                        continue;
+                    }
+                }
+                _ => (),
+            }
+            warn!("Failed to read the key of packed chunk {}.", name);
+        }
+        Ok(())
+    }
+
+    /// Removes the temporary files left behind by writes which were interrupted by a crash.
+    fn remove_temp_files(&self) -> Result<(), Error> {
+        let mut removed_count = 0;
+        let mut dirs = vec![self.rootdir.clone()];
+        while let Some(dir) = dirs.pop() {
+            for entry_result in fs::read_dir(&dir)? {
+                let entry = entry_result?;
+                let path = entry.path();
+                if entry.file_type()?.is_dir() {
+                    dirs.push(path);
+                } else if path.extension().map_or(false, |ext| ext == TEMP_FILE_EXTENSION) {
+                    fs::remove_file(path)?;
+                    removed_count += 1;
+                }
+            }
+        }
+        if removed_count > 0 {
+            info!("Removed {} incomplete chunk files.", removed_count);
+        }
+        Ok(())
+    }
+
+    /// Returns the keys and paths of all chunk files below the root directory, excluding the
+    /// quarantined and packed ones.
+    fn chunk_files(&self) -> Result<Vec<(Key, PathBuf)>, Error> {
+        let mut chunk_files = Vec::new();
+        let mut dirs = vec![self.rootdir.clone()];
+        let quarantine_dir = self.rootdir.join(QUARANTINE_DIR_NAME);
+        let packs_dir = self.rootdir.join(PACKS_DIR_NAME);
+        while let Some(dir) = dirs.pop() {
+            for entry_result in fs::read_dir(&dir)? {
+                let entry = entry_result?;
+                if entry.file_type()?.is_dir() {
+                    if entry.path() != quarantine_dir && entry.path() != packs_dir {
+                        dirs.push(entry.path());
+                    }
+                } else if let Some(key) = self.chunk_file_key(entry.file_name(), &entry.path()) {
+                    chunk_files.push((key, entry.path()));
+                }
+            }
+        }
+        Ok(chunk_files)
+    }
+
+    /// Returns the key of the chunk file with the given name and path, which is either encoded in
+    /// the name or, if the names are hashed, stored in the file.  Chunk files written before the
+    /// names were hashed don't hold their key, so it is taken from their name.
+    fn chunk_file_key(&self, file_name: OsString, file_path: &Path) -> Option<Key> {
+        let bytes = match file_name.into_string()
+            .ok()
+            .and_then(|hex_name| hex_name.from_hex().ok()) {
+            Some(bytes) => bytes,
+            None => return None,
+        };
+        if self.file_name_key.is_none() {
+            return serialisation::deserialise::<Key>(&bytes).ok();
+        }
+        let decoded = self.read_file(file_path)
+            .and_then(|contents| chunk_file::decode(&contents, self.encryption_key.as_ref()));
+        match decoded {
+            Ok((Some(serialised_key), _)) => {
+                serialisation::deserialise::<Key>(&serialised_key).ok()
+            }
+            Ok((None, _)) => serialisation::deserialise::<Key>(&bytes).ok(),
+            _ => {
+                warn!("Failed to read the key of chunk file {}.", file_path.display());
+                None
+            }
+        }
+    }
+
+    /// Returns the given chunk file contents encoded again with `key` embedded, if the file names
+    /// are hashed but the contents were written without the key.
+    fn embed_key(&self, key: &Key, contents: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        if self.file_name_key.is_none() {
+            return Ok(None);
+        }
+        match chunk_file::decode(contents, self.encryption_key.as_ref())? {
+            (None, serialised_value) => {
+                let serialised_key = serialisation::serialise(key)?;
+                Ok(Some(chunk_file::encode(Some(&serialised_key),
+                                           &serialised_value,
+                                           self.compression,
+                                           self.encryption_key.as_ref())?))
+            }
+            (Some(_), _) => Ok(None),
+        }
+    }
+
+    /// Writes the contents to a temporary file first, and only then replaces any existing file at
+    /// `file_path`, so a crash can't leave a truncated chunk behind.
+    fn write_file(&self, file_path: &Path, contents: &[u8]) -> Result<(), Error> {
+        let dir = match file_path.parent() {
+            Some(dir) => dir.to_path_buf(),
+            None => self.rootdir.clone(),
+        };
+        fs::create_dir_all(&dir)?;
+        let temp_file_path = file_path.with_extension(TEMP_FILE_EXTENSION);
+        let result = File::create(&temp_file_path)
+            .and_then(|mut file| {
+                file.write_all(contents)
+                    .and_then(|()| file.sync_all())
+            })
+            .and_then(|()| fs::rename(&temp_file_path, file_path));
+        if let Err(error) = result {
+            let _ = fs::remove_file(&temp_file_path);
+            return Err(From::from(error));
+        }
+        // Persist the rename.  Directories can't be opened like this on every platform, so errors
+        // are ignored.
+        let _ = File::open(&dir).and_then(|dir| dir.sync_all());
+        Ok(())
+    }
+
+    /// Returns the contents of the chunk file at `file_path`, or of the packed chunk with the same
+    /// name.
+    fn read_chunk(&self, file_path: &Path) -> Result<Vec<u8>, Error> {
+        if let Some(ref packs) = self.packs {
+            if let Some(contents) = packs.get(&pack_name(file_path))? {
+                return Ok(contents);
+            }
+        }
+        self.read_file(file_path)
+    }
+
+    fn read_file(&self, file_path: &Path) -> Result<Vec<u8>, Error> {
+        match File::open(file_path) {
+            Ok(mut file) => {
+                let mut contents = Vec::<u8>::new();
+                let _ = file.read_to_end(&mut contents)?;
+                Ok(contents)
+            }
+            Err(_) => Err(Error::NotFound),
         }
-        Ok(lock_file)
     }
 
     fn do_delete(&mut self, file_path: &Path) -> Result<(), Error> {
+        let _ = self.hashed_keys.remove(file_path);
+        if let Some(ref mut packs) = self.packs {
+            if packs.delete(&pack_name(file_path))? {
+                return Ok(());
+            }
+        }
         if let Ok(metadata) = fs::metadata(file_path) {
             self.used_space -= cmp::min(metadata.len(), self.used_space);
             fs::remove_file(file_path).map_err(From::from)
@@ -218,18 +1297,146 @@ impl<Key, Value> ChunkStore<Key, Value>
         }
     }
 
+    /// Returns the serialised value and the chunk file contents holding it.
+    fn encode_chunk(&self,
+                    serialised_key: &[u8],
+                    value: &Value)
+                    -> Result<(Vec<u8>, Vec<u8>), Error> {
+        let serialised_value = serialisation::serialise(value)?;
+        let embedded_key = if self.file_name_key.is_some() {
+            Some(serialised_key)
+        } else {
+            None
+        };
+        let contents = chunk_file::encode(embedded_key,
+                                          &serialised_value,
+                                          self.compression,
+                                          self.encryption_key.as_ref())?;
+        Ok((serialised_value, contents))
+    }
+
     fn file_path(&self, key: &Key) -> Result<PathBuf, Error> {
-        let filename = serialisation::serialise(key)?.to_hex();
-        let path_name = Path::new(&filename);
-        Ok(self.rootdir.join(path_name))
+        let serialised_key = serialisation::serialise(key)?;
+        let (file_name, hex_hash) = match self.file_name_key {
+            Some(ref file_name_key) => {
+                let hex_tag = auth::authenticate(&serialised_key, file_name_key).0[..].to_hex();
+                (hex_tag.clone(), hex_tag)
+            }
+            None => (serialised_key.to_hex(), sha256::hash(&serialised_key).0[..].to_hex()),
+        };
+        let mut path = self.rootdir.clone();
+        for level in 0..self.dir_depth {
+            path.push(&hex_hash[2 * level..2 * level + 2]);
+        }
+        path.push(file_name);
+        Ok(path)
     }
 }
 
 impl<Key, Value> Drop for ChunkStore<Key, Value> {
     fn drop(&mut self) {
         let _ = self.lock_file.take().iter().map(File::unlock);
-        let _ = fs::remove_dir_all(&self.rootdir);
+        if !self.persistent {
+            let _ = fs::remove_dir_all(&self.rootdir);
+        }
+    }
+}
+
+/// An iterator over the keys of the chunk files, listing one directory at a time.
+struct ChunkFileKeys<'a, Key: 'a, Value: 'a> {
+    chunk_store: &'a ChunkStore<Key, Value>,
+    /// The directories still to be listed.
+    dirs: Vec<PathBuf>,
+    /// The remaining entries of the directory being listed.
+    entries: Option<fs::ReadDir>,
+}
+
+impl<'a, Key, Value> Iterator for ChunkFileKeys<'a, Key, Value>
+    where Key: Decodable + Encodable,
+          Value: Decodable + Encodable
+{
+    type Item = Key;
+
+    fn next(&mut self) -> Option<Key> {
+        loop {
+            let next_entry = match self.entries {
+                Some(ref mut entries) => entries.next(),
+                None => None,
+            };
+            let entry = match next_entry {
+                Some(Ok(entry)) => entry,
+                Some(Err(error)) => {
+                    warn!("Failed to list chunk files: {}", error);
+                    self.entries = None;
+                    continue;
+                }
+                None => {
+                    let dir = match self.dirs.pop() {
+                        Some(dir) => dir,
+                        None => return None,
+                    };
+                    match fs::read_dir(&dir) {
+                        Ok(entries) => self.entries = Some(entries),
+                        Err(error) => {
+                            warn!("Failed to list chunk files in {}: {}", dir.display(), error);
+                            self.entries = None;
+                        }
+                    }
+                    continue;
+                }
+            };
+            let path = entry.path();
+            match entry.file_type() {
+                Ok(file_type) => {
+                    if !file_type.is_dir() {
+                        let key = self.chunk_store.chunk_file_key(entry.file_name(), &path);
+                        if key.is_some() {
+                            return key;
+                        }
+                    } else if path != self.chunk_store.rootdir.join(QUARANTINE_DIR_NAME) &&
+                              path != self.chunk_store.rootdir.join(PACKS_DIR_NAME) {
+                        self.dirs.push(path);
+                    }
+                }
+                Err(error) => warn!("Failed to read the type of {}: {}", path.display(), error),
+            }
+        }
+    }
+}
+
+/// Returns the path of a directory next to `root`, named like it with `suffix` appended.
+fn sibling_dir(root: &Path, suffix: &str) -> PathBuf {
+    let mut name = root.file_name().map_or_else(OsString::new, |name| name.to_os_string());
+    name.push(suffix);
+    root.with_file_name(name)
+}
+
+/// Returns the size of the file at `path`, or 0 if there is none.
+fn file_size(path: &Path) -> u64 {
+    match fs::metadata(path) {
+        Ok(ref metadata) if metadata.is_file() => metadata.len(),
+        _ => 0,
+    }
+}
+
+/// Returns the name under which the chunk belonging at `file_path` is packed.
+fn pack_name(file_path: &Path) -> String {
+    file_path.file_name().map_or_else(String::new, |name| name.to_string_lossy().into_owned())
+}
+
+/// Recursively removes all empty subdirectories of `dir`, and returns whether `dir` itself is
+/// empty afterwards.
+fn remove_empty_dirs(dir: &Path) -> io::Result<bool> {
+    let mut is_empty = true;
+    for entry_result in fs::read_dir(dir)? {
+        let entry = entry_result?;
+        if entry.file_type()?.is_dir() && remove_empty_dirs(&entry.path())? {
+            fs::remove_dir(entry.path())?;
+        } else {
+            is_empty = false;
+        }
     }
+    Ok(is_empty)
 }
 
 #[cfg(test)]
@@ -0,0 +1,166 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use routing::XorName;
+use std::collections::BTreeMap;
+use std::collections::Bound::Included;
+
+/// The number of bits in a name, and the number of buckets below the exact match.
+const NAME_BITS: usize = 256;
+
+/// `NameIndex` keeps the keys of stored chunks sorted by their names, to answer queries by XOR
+/// distance without going through every chunk.
+///
+/// Relative to a target name, the names are split into buckets: bucket `i` holds the names which
+/// share exactly their first `i` bits with the target, i.e. whose XOR distance to it lies in
+/// `[2^(255 - i), 2^(256 - i))`.  Bucket 256 only holds the target itself.  Each bucket is a
+/// contiguous range of the sorted names.
+pub struct NameIndex<Key> {
+    entries: BTreeMap<XorName, Vec<Key>>,
+}
+
+impl<Key> Default for NameIndex<Key> {
+    fn default() -> NameIndex<Key> {
+        NameIndex { entries: BTreeMap::new() }
+    }
+}
+
+impl<Key: PartialEq> NameIndex<Key> {
+    /// Adds `key` under `name`, unless it is already present.
+    pub fn insert(&mut self, name: XorName, key: Key) {
+        let keys = self.entries.entry(name).or_insert_with(Vec::new);
+        if !keys.contains(&key) {
+            keys.push(key);
+        }
+    }
+
+    /// Removes `key` from under `name`.
+    pub fn remove(&mut self, name: &XorName, key: &Key) {
+        let is_empty = match self.entries.get_mut(name) {
+            Some(keys) => {
+                keys.retain(|existing| existing != key);
+                keys.is_empty()
+            }
+            None => return,
+        };
+        if is_empty {
+            let _ = self.entries.remove(name);
+        }
+    }
+
+    /// Returns the non-empty buckets relative to `target` as pairs of the bucket index and the
+    /// keys in it, closest bucket first.  Within a bucket, the keys are sorted by name.
+    pub fn buckets(&self, target: &XorName) -> Vec<(usize, Vec<&Key>)> {
+        (0..NAME_BITS + 1)
+            .rev()
+            .filter_map(|bucket_index| {
+                let keys = self.bucket_entries(target, bucket_index)
+                    .flat_map(|(_, keys)| keys)
+                    .collect::<Vec<_>>();
+                if keys.is_empty() {
+                    None
+                } else {
+                    Some((bucket_index, keys))
+                }
+            })
+            .collect()
+    }
+
+    /// Returns the `count` keys whose names are closest to `target`, sorted by their distance to
+    /// it.  Only the buckets needed to find them are looked at.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn closest(&self, target: &XorName, count: usize) -> Vec<&Key> {
+        let mut closest = Vec::new();
+        for bucket_index in (0..NAME_BITS + 1).rev() {
+            if closest.len() >= count {
+                break;
+            }
+            let mut entries = self.bucket_entries(target, bucket_index).collect::<Vec<_>>();
+            entries.sort_by(|lhs, rhs| target.cmp_distance(lhs.0, rhs.0));
+            closest.extend(entries.into_iter().flat_map(|(_, keys)| keys));
+        }
+        closest.truncate(count);
+        closest
+    }
+
+    /// Returns the keys whose names have at most the XOR distance `distance` to `target`, sorted by
+    /// that distance.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn within_distance(&self, target: &XorName, distance: &XorName) -> Vec<&Key> {
+        // The farthest name, and for each set bit of the distance the names which match the
+        // farthest one in the bits before it and the target in that bit, and so are closer.  These
+        // ranges are listed in order of their distances to the target.
+        let farthest = xor(target, distance);
+        let mut entries = Vec::new();
+        for bit_index in 0..NAME_BITS {
+            if distance.0[bit_index / 8] & (0x80 >> (bit_index % 8)) != 0 {
+                let mut prefix = farthest;
+                prefix.0[bit_index / 8] ^= 0x80 >> (bit_index % 8);
+                let mut range_entries = self.prefix_entries(&prefix, bit_index + 1)
+                    .collect::<Vec<_>>();
+                range_entries.sort_by(|lhs, rhs| target.cmp_distance(lhs.0, rhs.0));
+                entries.extend(range_entries);
+            }
+        }
+        entries.extend(self.prefix_entries(&farthest, NAME_BITS));
+        entries.into_iter().flat_map(|(_, keys)| keys).collect()
+    }
+
+    /// Returns the entries in bucket `bucket_index` relative to `target`.
+    fn bucket_entries<'a>(&'a self,
+                          target: &XorName,
+                          bucket_index: usize)
+                          -> Box<Iterator<Item = (&'a XorName, &'a Vec<Key>)> + 'a> {
+        if bucket_index >= NAME_BITS {
+            return self.prefix_entries(target, NAME_BITS);
+        }
+        // The bucket's names keep the first `bucket_index` bits of the target and flip the next
+        // one.
+        let mut prefix = *target;
+        prefix.0[bucket_index / 8] ^= 0x80 >> (bucket_index % 8);
+        self.prefix_entries(&prefix, bucket_index + 1)
+    }
+
+    /// Returns the entries whose names agree with `prefix` in their first `prefix_len` bits.
+    fn prefix_entries<'a>(&'a self,
+                          prefix: &XorName,
+                          prefix_len: usize)
+                          -> Box<Iterator<Item = (&'a XorName, &'a Vec<Key>)> + 'a> {
+        let mut lowest = prefix.0;
+        let mut highest = prefix.0;
+        if prefix_len < NAME_BITS {
+            let byte = prefix_len / 8;
+            let lower_bits = 0xff_u8 >> (prefix_len % 8);
+            lowest[byte] &= !lower_bits;
+            highest[byte] |= lower_bits;
+            for (low, high) in lowest[byte + 1..].iter_mut().zip(highest[byte + 1..].iter_mut()) {
+                *low = 0;
+                *high = 255;
+            }
+        }
+        Box::new(self.entries.range((Included(XorName(lowest)), Included(XorName(highest)))))
+    }
+}
+
+/// Returns the bitwise XOR of the two names.
+fn xor(lhs: &XorName, rhs: &XorName) -> XorName {
+    let mut result = lhs.0;
+    for (byte, rhs_byte) in result.iter_mut().zip(rhs.0.iter()) {
+        *byte ^= *rhs_byte;
+    }
+    XorName(result)
+}
@@ -0,0 +1,386 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! Segment files holding small chunks.  New entries are always appended to the newest segment, and
+//! older segments are compacted once most of their entries have been overwritten or deleted.
+//!
+//! Each entry consists of a kind byte, the length of the chunk's name and the length of its
+//! contents (both as big-endian `u32`s), followed by the name and the contents.  A deletion is
+//! recorded as an entry without contents.
+//!
+//! Segment files are never modified in place, as they may be hardlinked into snapshots.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// The extension of segment files.
+const SEGMENT_FILE_EXTENSION: &'static str = "pack";
+/// The extension of the copies kept of damaged segment files.
+const DAMAGED_FILE_EXTENSION: &'static str = "damaged";
+/// The extension of segment files which are still being written.
+const TEMP_FILE_EXTENSION: &'static str = "tmp";
+/// The length of the kind byte and the two lengths at the start of each entry.
+const ENTRY_HEADER_LEN: usize = 9;
+/// The kind of entries holding a chunk.
+const PUT_ENTRY: u8 = 1;
+/// The kind of entries recording the deletion of a chunk.
+const DELETE_ENTRY: u8 = 0;
+
+/// Where the contents of the latest entry for a chunk are found.
+#[derive(Clone, Copy)]
+struct Location {
+    segment: u64,
+    /// The offset of the entry within the segment.
+    offset: u64,
+    /// The length of the complete entry.
+    len: u64,
+    /// The length of the contents at the end of the entry.
+    contents_len: u64,
+}
+
+#[derive(Default)]
+struct Segment {
+    size: u64,
+    /// The total length of the entries in this segment which are still current.
+    live_size: u64,
+    /// Whether entries which can't be read were found in this segment.  Nothing is appended to it
+    /// then, as the new entries couldn't be read either.
+    damaged: bool,
+}
+
+struct Entry {
+    kind: u8,
+    name: String,
+    offset: u64,
+    len: u64,
+    contents_len: u64,
+}
+
+/// The segment files in a directory, and an index of the chunks they hold.
+pub struct Packs {
+    dir: PathBuf,
+    /// The size after which no more entries are appended to a segment.
+    max_segment_size: u64,
+    index: HashMap<String, Location>,
+    segments: BTreeMap<u64, Segment>,
+}
+
+impl Packs {
+    /// Opens the segment files in `dir`, creating the directory if needed.  An incomplete entry
+    /// left behind by a crash at the end of the newest segment is removed.  If a segment is
+    /// damaged in any other way, only the entries before the damage are read, and a copy of the
+    /// segment is kept next to it for inspection.
+    pub fn open(dir: PathBuf, max_segment_size: u64) -> io::Result<Packs> {
+        fs::create_dir_all(&dir)?;
+        Self::load(dir, max_segment_size, false)
+    }
+
+    /// Opens the existing segment files in `dir` without modifying them.  Incomplete entries are
+    /// ignored.
+    pub fn open_read_only(dir: PathBuf) -> io::Result<Packs> {
+        Self::load(dir, 0, true)
+    }
+
+    fn load(dir: PathBuf, max_segment_size: u64, read_only: bool) -> io::Result<Packs> {
+        let mut segment_numbers = Vec::new();
+        for entry_result in fs::read_dir(&dir)? {
+            let path = entry_result?.path();
+            if path.extension().map_or(false, |ext| ext == SEGMENT_FILE_EXTENSION) {
+                if let Some(number) = path.file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .and_then(|stem| stem.parse::<u64>().ok()) {
+                    segment_numbers.push(number);
+                }
+            }
+        }
+        segment_numbers.sort();
+
+        let mut packs = Packs {
+            dir: dir,
+            max_segment_size: max_segment_size,
+            index: HashMap::new(),
+            segments: BTreeMap::new(),
+        };
+        let newest = segment_numbers.last().cloned();
+        for number in segment_numbers {
+            let path = packs.segment_path(number);
+            let contents = read_segment(&path)?;
+            let (entries, valid_len) = parse_entries(&contents);
+            let mut segment = Segment {
+                size: valid_len as u64,
+                live_size: 0,
+                damaged: false,
+            };
+            if valid_len < contents.len() && !read_only {
+                if Some(number) == newest && is_incomplete_entry(&contents[valid_len..]) {
+                    warn!("Removing {} bytes of an incomplete entry from {}.",
+                          contents.len() - valid_len,
+                          path.display());
+                    write_segment(&path, &contents[..valid_len])?;
+                } else {
+                    let damaged_path = path.with_extension(DAMAGED_FILE_EXTENSION);
+                    warn!("{} is damaged after {} bytes.  Only the entries before are read, and \
+                           a copy is kept in {}.",
+                          path.display(),
+                          valid_len,
+                          damaged_path.display());
+                    if !damaged_path.exists() {
+                        let _ = fs::copy(&path, &damaged_path)?;
+                    }
+                    segment.size = contents.len() as u64;
+                    segment.damaged = true;
+                }
+            }
+            let _ = packs.segments.insert(number, segment);
+            for entry in entries {
+                let location = Location {
+                    segment: number,
+                    offset: entry.offset,
+                    len: entry.len,
+                    contents_len: entry.contents_len,
+                };
+                if entry.kind == PUT_ENTRY {
+                    packs.set_location(entry.name, location);
+                } else {
+                    packs.remove_location(&entry.name);
+                }
+            }
+        }
+        Ok(packs)
+    }
+
+    /// Returns the names of all chunks held in the segments.
+    pub fn names(&self) -> Vec<String> {
+        self.index.keys().cloned().collect()
+    }
+
+    /// Returns whether a chunk with the given name is held in the segments.
+    pub fn contains(&self, name: &str) -> bool {
+        self.index.contains_key(name)
+    }
+
+    /// Returns the contents of the chunk with the given name, or `None` if it isn't held in the
+    /// segments.
+    pub fn get(&self, name: &str) -> io::Result<Option<Vec<u8>>> {
+        let location = match self.index.get(name) {
+            Some(location) => *location,
+            None => return Ok(None),
+        };
+        let mut file = File::open(self.segment_path(location.segment))?;
+        let _ = file.seek(SeekFrom::Start(location.offset + location.len -
+                                          location.contents_len))?;
+        let mut contents = vec![0; location.contents_len as usize];
+        file.read_exact(&mut contents)?;
+        Ok(Some(contents))
+    }
+
+    /// Returns the length of the contents of the chunk with the given name, or `None` if it isn't
+    /// held in the segments.
+    pub fn contents_len(&self, name: &str) -> Option<u64> {
+        self.index.get(name).map(|location| location.contents_len)
+    }
+
+    /// Appends the chunk to the newest segment, replacing any previous entry with the same name.
+    pub fn put(&mut self, name: &str, contents: &[u8]) -> io::Result<()> {
+        let location = self.append(PUT_ENTRY, name, contents)?;
+        self.set_location(name.to_owned(), location);
+        self.compact()
+    }
+
+    /// Records the deletion of the chunk with the given name.  Returns whether it was held in the
+    /// segments.
+    pub fn delete(&mut self, name: &str) -> io::Result<bool> {
+        if !self.index.contains_key(name) {
+            return Ok(false);
+        }
+        let _ = self.append(DELETE_ENTRY, name, &[])?;
+        self.remove_location(name);
+        self.compact()?;
+        Ok(true)
+    }
+
+    /// Returns the total size of the segment files, including outdated entries.
+    pub fn size(&self) -> u64 {
+        self.segments.values().fold(0, |total, segment| total + segment.size)
+    }
+
+    fn segment_path(&self, number: u64) -> PathBuf {
+        self.dir.join(format!("{}.{}", number, SEGMENT_FILE_EXTENSION))
+    }
+
+    fn set_location(&mut self, name: String, location: Location) {
+        if let Some(old_location) = self.index.insert(name, location) {
+            self.remove_live_entry(&old_location);
+        }
+        if let Some(segment) = self.segments.get_mut(&location.segment) {
+            segment.live_size += location.len;
+        }
+    }
+
+    fn remove_location(&mut self, name: &str) {
+        if let Some(old_location) = self.index.remove(name) {
+            self.remove_live_entry(&old_location);
+        }
+    }
+
+    fn remove_live_entry(&mut self, location: &Location) {
+        if let Some(segment) = self.segments.get_mut(&location.segment) {
+            segment.live_size -= location.len;
+        }
+    }
+
+    /// Appends an entry to the newest segment, or to a new one if that is full.
+    fn append(&mut self, kind: u8, name: &str, contents: &[u8]) -> io::Result<Location> {
+        let number = match self.segments.iter().next_back() {
+            Some((number, segment)) if segment.size < self.max_segment_size &&
+                                       !segment.damaged => *number,
+            Some((number, _)) => *number + 1,
+            None => 0,
+        };
+        let mut entry = Vec::with_capacity(ENTRY_HEADER_LEN + name.len() + contents.len());
+        entry.push(kind);
+        push_u32(&mut entry, name.len());
+        push_u32(&mut entry, contents.len());
+        entry.extend_from_slice(name.as_bytes());
+        entry.extend_from_slice(contents);
+
+        let mut file = OpenOptions::new().create(true)
+            .append(true)
+            .open(self.segment_path(number))?;
+        file.write_all(&entry)?;
+        file.sync_data()?;
+
+        let segment = self.segments.entry(number).or_insert_with(Segment::default);
+        let location = Location {
+            segment: number,
+            offset: segment.size,
+            len: entry.len() as u64,
+            contents_len: contents.len() as u64,
+        };
+        segment.size += location.len;
+        Ok(location)
+    }
+
+    /// Rewrites the current entries of the oldest segment in which they make up less than half of
+    /// the size, and removes it.
+    fn compact(&mut self) -> io::Result<()> {
+        let newest = match self.segments.keys().next_back() {
+            Some(newest) => *newest,
+            None => return Ok(()),
+        };
+        let number = match self.segments.iter().find(|&(number, segment)| {
+            *number != newest && segment.live_size * 2 < segment.size
+        }) {
+            Some((number, _)) => *number,
+            None => return Ok(()),
+        };
+        // Deletions only have to be kept while older segments may still hold the deleted chunks.
+        let is_oldest = self.segments.keys().next() == Some(&number);
+
+        let path = self.segment_path(number);
+        let contents = read_segment(&path)?;
+        let (entries, _) = parse_entries(&contents);
+        for entry in entries {
+            let entry_contents = &contents[(entry.offset + entry.len - entry.contents_len) as
+                                           usize..(entry.offset + entry.len) as usize];
+            if entry.kind == PUT_ENTRY {
+                let is_current = self.index
+                    .get(&entry.name)
+                    .map_or(false, |location| {
+                        location.segment == number && location.offset == entry.offset
+                    });
+                if is_current {
+                    let location = self.append(PUT_ENTRY, &entry.name, entry_contents)?;
+                    self.set_location(entry.name, location);
+                }
+            } else if !is_oldest && !self.index.contains_key(&entry.name) {
+                let _ = self.append(DELETE_ENTRY, &entry.name, &[])?;
+            }
+        }
+        fs::remove_file(&path)?;
+        let _ = self.segments.remove(&number);
+        Ok(())
+    }
+}
+
+fn read_segment(path: &Path) -> io::Result<Vec<u8>> {
+    let mut contents = Vec::new();
+    let _ = File::open(path)?.read_to_end(&mut contents)?;
+    Ok(contents)
+}
+
+/// Replaces the segment file at `path` with a new file holding `contents`.
+fn write_segment(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let temp_path = path.with_extension(TEMP_FILE_EXTENSION);
+    let mut file = File::create(&temp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&temp_path, path)
+}
+
+/// Returns whether `rest` is the start of an entry which was cut short, like a crash while
+/// appending it leaves behind.
+fn is_incomplete_entry(rest: &[u8]) -> bool {
+    match rest.first() {
+        Some(&kind) if kind == PUT_ENTRY || kind == DELETE_ENTRY => (),
+        _ => return false,
+    }
+    rest.len() < ENTRY_HEADER_LEN ||
+    ENTRY_HEADER_LEN + read_u32(&rest[1..5]) + read_u32(&rest[5..9]) > rest.len()
+}
+
+/// Returns the complete entries at the start of `contents`, and their total length.
+fn parse_entries(contents: &[u8]) -> (Vec<Entry>, usize) {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    while contents.len() - offset >= ENTRY_HEADER_LEN {
+        let kind = contents[offset];
+        let name_len = read_u32(&contents[offset + 1..offset + 5]);
+        let contents_len = read_u32(&contents[offset + 5..offset + 9]);
+        let len = ENTRY_HEADER_LEN + name_len + contents_len;
+        if (kind != PUT_ENTRY && kind != DELETE_ENTRY) || contents.len() - offset < len {
+            break;
+        }
+        let name_start = offset + ENTRY_HEADER_LEN;
+        let name = match String::from_utf8(contents[name_start..name_start + name_len].to_vec()) {
+            Ok(name) => name,
+            Err(_) => break,
+        };
+        entries.push(Entry {
+            kind: kind,
+            name: name,
+            offset: offset as u64,
+            len: len as u64,
+            contents_len: contents_len as u64,
+        });
+        offset += len;
+    }
+    (entries, offset)
+}
+
+fn push_u32(buffer: &mut Vec<u8>, value: usize) {
+    buffer.extend_from_slice(&[(value >> 24) as u8,
+                               (value >> 16) as u8,
+                               (value >> 8) as u8,
+                               value as u8]);
+}
+
+fn read_u32(bytes: &[u8]) -> usize {
+    bytes.iter().fold(0, |value, byte| (value << 8) | *byte as usize)
+}
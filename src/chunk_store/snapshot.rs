@@ -0,0 +1,165 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! Snapshots of the files in a chunk store directory.  The files are hardlinked into the snapshot
+//! directory where possible, which relies on the store replacing its files instead of modifying
+//! them.  Files which are modified in place, like pack segments, are copied.
+//!
+//! The manifest is written last, so only complete snapshots have one.  After a header line, it
+//! lists each file as `link` or `copy`, its length and its path relative to the store directory,
+//! separated by spaces.
+
+use super::{Error, PACKS_DIR_NAME, SnapshotStats, TEMP_FILE_EXTENSION};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Component, Path, PathBuf};
+
+/// The name of the manifest file in the snapshot directory.
+const MANIFEST_FILE_NAME: &'static str = "manifest";
+/// The first line of the manifest.
+const MANIFEST_HEADER: &'static str = "safe_vault chunk store snapshot 1";
+
+/// A file in a snapshot.
+pub struct ManifestEntry {
+    /// The path relative to the store directory.
+    path: PathBuf,
+    /// Whether the file has to be copied instead of hardlinked.
+    copy: bool,
+}
+
+/// Writes a snapshot of the given files below `root` to the new directory `dest`.  The flag with
+/// each file tells whether it has to be copied.
+pub fn create(root: &Path,
+              dest: &Path,
+              files: Vec<(PathBuf, bool)>)
+              -> Result<SnapshotStats, Error> {
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::create_dir(dest)?;
+    let mut stats = SnapshotStats::default();
+    let mut manifest = format!("{}\n", MANIFEST_HEADER);
+    for (file_path, copy) in files {
+        let path = match file_path.strip_prefix(root) {
+            Ok(path) => path.to_path_buf(),
+            Err(_) => continue,
+        };
+        let len = transfer(&file_path, &dest.join(&path), copy)?;
+        manifest.push_str(&format!("{} {} {}\n",
+                                   if copy { "copy" } else { "link" },
+                                   len,
+                                   relative_path_string(&path)));
+        stats.files += 1;
+        stats.bytes += len;
+    }
+    let temp_manifest_path = dest.join(MANIFEST_FILE_NAME).with_extension(TEMP_FILE_EXTENSION);
+    let mut file = File::create(&temp_manifest_path)?;
+    file.write_all(manifest.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&temp_manifest_path, dest.join(MANIFEST_FILE_NAME))?;
+    Ok(stats)
+}
+
+/// Reads the manifest of the snapshot in `snapshot_dir`, and checks that all files it lists are
+/// present with the recorded length.
+pub fn read_manifest(snapshot_dir: &Path) -> Result<Vec<ManifestEntry>, Error> {
+    let file = match File::open(snapshot_dir.join(MANIFEST_FILE_NAME)) {
+        Ok(file) => file,
+        Err(ref error) if error.kind() == io::ErrorKind::NotFound => {
+            return Err(Error::InvalidSnapshot);
+        }
+        Err(error) => return Err(From::from(error)),
+    };
+    let mut lines = BufReader::new(file).lines();
+    match lines.next() {
+        Some(Ok(ref header)) if header == MANIFEST_HEADER => (),
+        _ => return Err(Error::InvalidSnapshot),
+    }
+    let mut entries = Vec::new();
+    for line in lines {
+        let line = line?;
+        let mut fields = line.splitn(3, ' ');
+        let copy = match fields.next() {
+            Some("copy") => true,
+            Some("link") => false,
+            _ => return Err(Error::InvalidSnapshot),
+        };
+        let len = match fields.next().and_then(|len| len.parse::<u64>().ok()) {
+            Some(len) => len,
+            None => return Err(Error::InvalidSnapshot),
+        };
+        // Only accept plain relative paths, so a manifest can't refer to files outside the
+        // snapshot.
+        let path = match fields.next() {
+            Some(path) if !path.is_empty() => PathBuf::from(path),
+            _ => return Err(Error::InvalidSnapshot),
+        };
+        if path.components().any(|component| match component {
+            Component::Normal(_) => false,
+            _ => true,
+        }) {
+            return Err(Error::InvalidSnapshot);
+        }
+        match fs::metadata(snapshot_dir.join(&path)) {
+            Ok(ref metadata) if metadata.is_file() && metadata.len() == len => (),
+            _ => return Err(Error::InvalidSnapshot),
+        }
+        entries.push(ManifestEntry {
+            path: path,
+            copy: copy,
+        });
+    }
+    Ok(entries)
+}
+
+/// Puts the files listed in the manifest of the snapshot in `snapshot_dir` into `root`.  Pack
+/// segments are always copied, as snapshots from older versions may list them as hardlinked.
+pub fn restore(snapshot_dir: &Path, root: &Path, entries: &[ManifestEntry]) -> Result<(), Error> {
+    for entry in entries {
+        let copy = entry.copy || entry.path.starts_with(PACKS_DIR_NAME);
+        let _ = transfer(&snapshot_dir.join(&entry.path), &root.join(&entry.path), copy)?;
+    }
+    Ok(())
+}
+
+/// Hardlinks or copies the file at `from` to `to`, creating the parent directories.  Hardlinking
+/// falls back to copying, e.g. if the paths are on different filesystems.  Returns the length of
+/// the file.
+fn transfer(from: &Path, to: &Path, copy: bool) -> Result<u64, Error> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if !copy {
+        match fs::hard_link(from, to) {
+            Ok(()) => return Ok(fs::metadata(to)?.len()),
+            Err(error) => {
+                debug!("Copying {} as it can't be hardlinked: {}",
+                       from.display(),
+                       error);
+            }
+        }
+    }
+    Ok(fs::copy(from, to)?)
+}
+
+/// Returns the path with `/` as separator, so manifests can be read on every platform.
+fn relative_path_string(path: &Path) -> String {
+    path.components()
+        .map(|component| component.as_os_str().to_string_lossy().into_owned())
+        .collect::<Vec<_>>()
+        .join("/")
+}
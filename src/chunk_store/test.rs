@@ -26,23 +26,52 @@ macro_rules! assert_err {
     }
 }
 
-use chunk_store::{ChunkStore, Error};
+use chunk_store::{ChunkStorage, ChunkStore, ChunkStoreBuilder, CompositeChunkStore, Compression,
+                  CompressionStats, Error, FORMAT_FILE_NAME, MemoryChunkStore, NameIndex,
+                  QUARANTINE_DIR_NAME, TEMP_FILE_EXTENSION, TieredChunkStore, check_snapshot};
+use chunk_store::chunk_file;
+use chunk_store::pack::Packs;
 use maidsafe_utilities::serialisation;
 use rand::{self, Rng};
+use routing::XorName;
+use rust_sodium;
+use rust_sodium::crypto::{auth, secretbox};
+use rust_sodium::crypto::hash::sha256;
+use rustc_serialize::{Decodable, Encodable};
+use rustc_serialize::hex::ToHex;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
 use tempdir::TempDir;
 
+fn open_chunk_store<Key, Value>(root: PathBuf,
+                                max_space: u64,
+                                dir_depth: usize)
+                                -> Result<ChunkStore<Key, Value>, Error>
+    where Key: Decodable + Encodable,
+          Value: Decodable + Encodable
+{
+    ChunkStoreBuilder::new(root, max_space).persistent(true).dir_depth(dir_depth).create()
+}
+
 #[cfg_attr(feature="clippy", allow(cast_possible_truncation))]
 fn generate_random_bytes(size: u64) -> Vec<u8> {
     rand::thread_rng().gen_iter().take(size as usize).collect()
 }
 
+// Returns the size of the uncompressed and unencrypted chunk file holding `data`.
+fn chunk_file_size(data: &[u8]) -> u64 {
+    let serialised_data = unwrap!(serialisation::serialise(&data));
+    unwrap!(chunk_file::encode(None, &serialised_data, Compression::None, None)).len() as u64
+}
+
 struct Chunks {
     data_and_sizes: Vec<(Vec<u8>, u64)>,
     total_size: u64,
 }
 
 // Construct random amount of randomly-sized chunks, keeping track of the total size of all
-// chunks when serialised.
+// chunks when stored in chunk files.
 fn generate_random_chunks() -> Chunks {
     let mut chunks = Chunks {
         data_and_sizes: vec![],
@@ -52,9 +81,9 @@ fn generate_random_chunks() -> Chunks {
     for _ in 0..chunk_count {
         let size: u8 = rand::random();
         let data = generate_random_bytes(size as u64);
-        let serialised_size = unwrap!(serialisation::serialise(&data)).len() as u64;
-        chunks.total_size += serialised_size;
-        chunks.data_and_sizes.push((data, serialised_size));
+        let file_size = chunk_file_size(&data);
+        chunks.total_size += file_size;
+        chunks.data_and_sizes.push((data, file_size));
     }
     chunks
 }
@@ -138,6 +167,62 @@ fn failed_put_when_not_enough_space() {
     assert_err!(store.put(&key, &data), Error::NotEnoughSpace);
 }
 
+#[test]
+fn reserve_and_release_space() {
+    let k_disk_size = 64;
+    let root = unwrap!(TempDir::new("test"));
+    let mut store = unwrap!(ChunkStore::new(root.path().to_path_buf(), k_disk_size));
+    unwrap!(store.reserve(40));
+    assert_eq!(store.reserved_space(), 40);
+    assert_err!(store.reserve(k_disk_size - 39), Error::NotEnoughSpace);
+    unwrap!(store.reserve(k_disk_size - 40));
+
+    // Releasing a reservation lets the space be claimed again.
+    store.release(40);
+    assert_eq!(store.reserved_space(), k_disk_size - 40);
+    unwrap!(store.reserve(40));
+    store.release(k_disk_size);
+    assert_eq!(store.reserved_space(), 0);
+
+    // Used space can't be reserved.
+    let key: u8 = rand::random();
+    unwrap!(store.put(&key, &generate_random_bytes(16)));
+    let free_space = k_disk_size - store.used_space();
+    assert_err!(store.reserve(free_space + 1), Error::NotEnoughSpace);
+    unwrap!(store.reserve(free_space));
+
+    let mut memory_store = MemoryChunkStore::<u8, Vec<u8>>::new(k_disk_size);
+    unwrap!(memory_store.reserve(k_disk_size));
+    assert_err!(memory_store.reserve(1), Error::NotEnoughSpace);
+    memory_store.release(k_disk_size);
+    unwrap!(memory_store.reserve(1));
+}
+
+#[test]
+fn space_needed_matches_put() {
+    let root = unwrap!(TempDir::new("test"));
+    let mut store: ChunkStore<u64, Vec<u8>> =
+        unwrap!(ChunkStoreBuilder::new(root.path().to_path_buf(), 1024)
+            .encryption_key(secretbox::gen_key())
+            .file_name_key(auth::gen_key())
+            .create());
+    let key: u64 = rand::random();
+    let data = generate_random_bytes(100);
+
+    // The chunk file's header, the embedded key and the encryption are included.
+    let needed = unwrap!(store.space_needed(&key, &data));
+    assert!(needed > unwrap!(serialisation::serialise(&data)).len() as u64 + 8);
+    unwrap!(store.put(&key, &data));
+    assert_eq!(store.used_space(), needed);
+
+    // Replacing a chunk only needs the space by which the new version is larger.
+    assert_eq!(unwrap!(store.space_needed(&key, &data[..50].to_vec())), 0);
+    let larger_data = generate_random_bytes(150);
+    assert_eq!(unwrap!(store.space_needed(&key, &larger_data)), 50);
+    unwrap!(store.put(&key, &larger_data));
+    assert_eq!(store.used_space(), needed + 50);
+}
+
 #[test]
 fn delete() {
     let chunks = generate_random_chunks();
@@ -213,3 +298,911 @@ fn keys() {
                    chunks.data_and_sizes.len() - index - 1);
     }
 }
+
+#[test]
+fn persistent_store_survives_reopen() {
+    let chunks = generate_random_chunks();
+    let tempdir = unwrap!(TempDir::new("test"));
+    let storedir = tempdir.path().join("test");
+
+    {
+        let mut chunk_store = unwrap!(ChunkStore::open(storedir.clone(), chunks.total_size));
+        for (index, &(ref data, _)) in chunks.data_and_sizes.iter().enumerate() {
+            unwrap!(chunk_store.put(&index, data));
+        }
+        assert_eq!(chunk_store.used_space(), chunks.total_size);
+    }
+
+    assert!(storedir.exists());
+
+    {
+        let chunk_store = unwrap!(ChunkStore::<usize, Vec<u8>>::open(storedir.clone(),
+                                                                     chunks.total_size));
+        assert_eq!(chunk_store.used_space(), chunks.total_size);
+        let mut keys = chunk_store.keys();
+        keys.sort();
+        assert_eq!((0..chunks.data_and_sizes.len()).collect::<Vec<_>>(), keys);
+        for (index, &(ref data, _)) in chunks.data_and_sizes.iter().enumerate() {
+            assert_eq!(*data, unwrap!(chunk_store.get(&index)));
+        }
+    }
+
+    // Creating a non-persistent store in the same directory clears it.
+    {
+        let chunk_store = unwrap!(ChunkStore::<usize, Vec<u8>>::new(storedir.clone(),
+                                                                    chunks.total_size));
+        assert_eq!(chunk_store.used_space(), 0);
+        assert!(chunk_store.keys().is_empty());
+    }
+
+    assert!(!storedir.exists());
+}
+
+#[test]
+fn change_dir_depth() {
+    let chunks = generate_random_chunks();
+    let tempdir = unwrap!(TempDir::new("test"));
+    let storedir = tempdir.path().join("test");
+
+    {
+        let mut chunk_store = unwrap!(open_chunk_store(storedir.clone(), chunks.total_size, 0));
+        for (index, &(ref data, _)) in chunks.data_and_sizes.iter().enumerate() {
+            unwrap!(chunk_store.put(&index, data));
+        }
+    }
+
+    // Reopening with a different depth moves the chunk files into the new layout, and leaves
+    // only subdirectories, the lock file and the format file in the root directory.
+    for dir_depth in vec![3, 1, 0] {
+        let mut chunk_store: ChunkStore<usize, Vec<u8>> =
+            unwrap!(open_chunk_store(storedir.clone(), chunks.total_size, dir_depth));
+        assert_eq!(chunk_store.used_space(), chunks.total_size);
+        assert_eq!(chunk_store.keys().len(), chunks.data_and_sizes.len());
+        for (index, &(ref data, _)) in chunks.data_and_sizes.iter().enumerate() {
+            assert_eq!(*data, unwrap!(chunk_store.get(&index)));
+        }
+        let root_entries: Vec<_> = unwrap!(storedir.read_dir())
+            .map(|entry| unwrap!(entry))
+            .collect();
+        let file_count = root_entries.iter()
+            .filter(|entry| unwrap!(entry.file_type()).is_file())
+            .count();
+        if dir_depth == 0 {
+            assert_eq!(root_entries.len(), file_count);
+            assert_eq!(file_count, chunks.data_and_sizes.len() + 2);
+        } else {
+            assert_eq!(file_count, 2);
+        }
+
+        if let Some(&(ref data, _)) = chunks.data_and_sizes.first() {
+            unwrap!(chunk_store.delete(&0));
+            assert!(!chunk_store.has(&0));
+            unwrap!(chunk_store.put(&0, data));
+            assert!(chunk_store.has(&0));
+        }
+    }
+
+    // A depth beyond the maximum is rejected instead of being silently reduced.
+    assert_err!(open_chunk_store::<usize, Vec<u8>>(storedir, chunks.total_size, 9),
+                Error::InvalidDirDepth(9));
+}
+
+#[test]
+fn memory_store() {
+    let chunks = generate_random_chunks();
+    let serialised_sizes: Vec<u64> = chunks.data_and_sizes
+        .iter()
+        .map(|&(ref data, _)| unwrap!(serialisation::serialise(data)).len() as u64)
+        .collect();
+    let total_size: u64 = serialised_sizes.iter().fold(0, |total, size| total + *size);
+    let mut chunk_store = MemoryChunkStore::new(total_size);
+
+    for (index, &(ref data, _)) in chunks.data_and_sizes.iter().enumerate() {
+        let size_before_insert = chunk_store.used_space();
+        assert!(!chunk_store.has(&index));
+        unwrap!(chunk_store.put(&index, data));
+        assert_eq!(chunk_store.used_space(),
+                   serialised_sizes[index] + size_before_insert);
+        assert!(chunk_store.has(&index));
+    }
+    assert_eq!(chunk_store.used_space(), total_size);
+
+    let mut keys = chunk_store.keys();
+    keys.sort();
+    assert_eq!((0..chunks.data_and_sizes.len()).collect::<Vec<_>>(), keys);
+
+    let data = generate_random_bytes(1);
+    let key = chunks.data_and_sizes.len();
+    assert_err!(chunk_store.put(&key, &data), Error::NotEnoughSpace);
+    assert_err!(chunk_store.get(&key), Error::NotFound);
+
+    for (index, &(ref data, _)) in chunks.data_and_sizes.iter().enumerate() {
+        assert_eq!(*data, unwrap!(chunk_store.get(&index)));
+        unwrap!(chunk_store.delete(&index));
+        assert!(!chunk_store.has(&index));
+    }
+    assert_eq!(chunk_store.used_space(), 0);
+}
+
+#[test]
+fn corrupted_chunk_is_detected_and_quarantined() {
+    let root = unwrap!(TempDir::new("test"));
+    let mut chunk_store = unwrap!(ChunkStore::new(root.path().to_path_buf(), 1024));
+    let data = generate_random_bytes(100);
+    let key: u64 = rand::random();
+    unwrap!(chunk_store.put(&key, &data));
+    unwrap!(chunk_store.verify(&key));
+    let used_space = chunk_store.used_space();
+
+    // Flip the last byte of the chunk file.
+    let file_path = unwrap!(chunk_store.file_path(&key));
+    {
+        let mut file = unwrap!(OpenOptions::new().write(true).open(&file_path));
+        let _ = unwrap!(file.seek(SeekFrom::End(-1)));
+        unwrap!(file.write_all(&[!data[data.len() - 1]]));
+    }
+
+    assert_err!(chunk_store.verify(&key), Error::Corrupted);
+    assert_err!(chunk_store.get(&key), Error::Corrupted);
+
+    unwrap!(chunk_store.quarantine(&key));
+    assert!(!chunk_store.has(&key));
+    assert!(chunk_store.keys().is_empty());
+    assert_eq!(chunk_store.used_space(), 0);
+    assert!(used_space > 0);
+    let quarantine_dir = root.path().join(QUARANTINE_DIR_NAME);
+    assert_eq!(unwrap!(quarantine_dir.read_dir()).count(), 1);
+}
+
+#[test]
+fn incomplete_writes_are_removed_on_open() {
+    let tempdir = unwrap!(TempDir::new("test"));
+    let storedir = tempdir.path().join("test");
+    let data = generate_random_bytes(100);
+    let key: u64 = rand::random();
+
+    let temp_file_path = {
+        let mut chunk_store = unwrap!(ChunkStore::open(storedir.clone(), 1024));
+        unwrap!(chunk_store.put(&key, &data));
+
+        // Simulate a crash in the middle of overwriting the chunk.
+        let file_path = unwrap!(chunk_store.file_path(&key));
+        let temp_file_path = file_path.with_extension(TEMP_FILE_EXTENSION);
+        let mut file = unwrap!(File::create(&temp_file_path));
+        unwrap!(file.write_all(&data[..10]));
+        temp_file_path
+    };
+    assert!(temp_file_path.exists());
+
+    let chunk_store: ChunkStore<u64, Vec<u8>> = unwrap!(ChunkStore::open(storedir, 1024));
+    assert!(!temp_file_path.exists());
+    assert_eq!(chunk_store.keys(), vec![key]);
+    assert_eq!(data, unwrap!(chunk_store.get(&key)));
+    let file_size = chunk_file_size(&data);
+    assert_eq!(chunk_store.used_space(), file_size);
+}
+
+#[test]
+fn compressed_chunks() {
+    let tempdir = unwrap!(TempDir::new("test"));
+    let storedir = tempdir.path().join("test");
+    let compressible_data = vec![7u8; 1000];
+    let random_data = generate_random_bytes(1000);
+    let compressed_key: u64 = rand::random();
+    let random_key = compressed_key.wrapping_add(1);
+
+    {
+        let mut chunk_store: ChunkStore<u64, Vec<u8>> =
+            unwrap!(ChunkStoreBuilder::new(storedir.clone(), 2048)
+                .persistent(true)
+                .compression(Compression::Zlib)
+                .create());
+        unwrap!(chunk_store.put(&compressed_key, &compressible_data));
+        let compressed_size = chunk_store.used_space();
+        assert!(compressed_size < compressible_data.len() as u64);
+
+        // Random data doesn't get smaller, so it is stored uncompressed.
+        unwrap!(chunk_store.put(&random_key, &random_data));
+        let file_size = chunk_file_size(&random_data);
+        assert_eq!(chunk_store.used_space(), compressed_size + file_size);
+
+        assert_eq!(compressible_data, unwrap!(chunk_store.get(&compressed_key)));
+        assert_eq!(random_data, unwrap!(chunk_store.get(&random_key)));
+        let stats = chunk_store.compression_stats();
+        assert_eq!(stats.stored_bytes, chunk_store.used_space());
+        assert!(stats.ratio() < 1.0);
+    }
+
+    // Compressed chunks can still be read after compression has been switched off.
+    let chunk_store: ChunkStore<u64, Vec<u8>> = unwrap!(ChunkStore::open(storedir, 2048));
+    assert_eq!(compressible_data, unwrap!(chunk_store.get(&compressed_key)));
+    assert_eq!(random_data, unwrap!(chunk_store.get(&random_key)));
+    assert_eq!(chunk_store.compression_stats(), CompressionStats::default());
+}
+
+#[test]
+fn encrypted_chunks_with_hashed_file_names() {
+    rust_sodium::init();
+    let tempdir = unwrap!(TempDir::new("test"));
+    let storedir = tempdir.path().join("test");
+    let encryption_key = secretbox::gen_key();
+    let file_name_key = auth::gen_key();
+    let data = vec![7u8; 100];
+    let key: u64 = rand::random();
+    let open_encrypted_store = || -> ChunkStore<u64, Vec<u8>> {
+        unwrap!(ChunkStoreBuilder::new(storedir.clone(), 1024)
+            .persistent(true)
+            .encryption_key(encryption_key.clone())
+            .file_name_key(file_name_key.clone())
+            .create())
+    };
+
+    let file_path = {
+        let mut chunk_store = open_encrypted_store();
+        unwrap!(chunk_store.put(&key, &data));
+        assert_eq!(data, unwrap!(chunk_store.get(&key)));
+        unwrap!(chunk_store.verify(&key));
+        assert_eq!(chunk_store.keys(), vec![key]);
+        unwrap!(chunk_store.file_path(&key))
+    };
+
+    // Neither the file name nor the contents reveal the key or the data.
+    let serialised_key = unwrap!(serialisation::serialise(&key));
+    assert!(!unwrap!(file_path.to_str()).contains(&serialised_key.to_hex()));
+    let mut contents = Vec::new();
+    let _ = unwrap!(unwrap!(File::open(&file_path)).read_to_end(&mut contents));
+    assert!(!contents.windows(data.len()).any(|window| window == &data[..]));
+
+    // The keys are recovered from the chunk files on reopening.
+    {
+        let mut chunk_store = open_encrypted_store();
+        assert_eq!(chunk_store.keys(), vec![key]);
+        assert_eq!(chunk_store.used_space(), contents.len() as u64);
+        assert_eq!(data, unwrap!(chunk_store.get(&key)));
+        unwrap!(chunk_store.delete(&key));
+        assert!(chunk_store.keys().is_empty());
+        unwrap!(chunk_store.put(&key, &data));
+    }
+
+    // Without the encryption key, the chunk can't be read.
+    let chunk_store: ChunkStore<u64, Vec<u8>> =
+        unwrap!(ChunkStoreBuilder::new(storedir.clone(), 1024)
+            .persistent(true)
+            .file_name_key(file_name_key.clone())
+            .create());
+    assert!(chunk_store.keys().is_empty());
+    assert_err!(chunk_store.get(&key), Error::Decryption);
+    assert!(unwrap!(fs::metadata(&file_path)).is_file());
+}
+
+#[test]
+fn hashing_file_names_of_an_existing_store() {
+    rust_sodium::init();
+    let tempdir = unwrap!(TempDir::new("test"));
+    let storedir = tempdir.path().join("test");
+    let file_name_key = auth::gen_key();
+    let small_data = vec![1u8; 10];
+    let large_data = vec![2u8; 200];
+    let builder = || {
+        ChunkStoreBuilder::new(storedir.clone(), 4096).persistent(true).pack_threshold(100)
+    };
+
+    {
+        let mut chunk_store: ChunkStore<u64, Vec<u8>> = unwrap!(builder().create());
+        unwrap!(chunk_store.put(&0, &small_data));
+        unwrap!(chunk_store.put(&1, &large_data));
+    }
+
+    // The chunks written without hashed names are converted, both when packed and in files.
+    for _ in 0..2 {
+        let chunk_store: ChunkStore<u64, Vec<u8>> =
+            unwrap!(builder().file_name_key(file_name_key.clone()).create());
+        let mut keys = chunk_store.keys();
+        keys.sort();
+        assert_eq!(keys, vec![0, 1]);
+        assert_eq!(small_data, unwrap!(chunk_store.get(&0)));
+        assert_eq!(large_data, unwrap!(chunk_store.get(&1)));
+        assert!(unwrap!(chunk_store.stray_files()).is_empty());
+        let serialised_key = unwrap!(serialisation::serialise(&1u64));
+        assert!(!unwrap!(unwrap!(chunk_store.file_path(&1)).to_str())
+            .contains(&serialised_key.to_hex()));
+    }
+}
+
+#[test]
+fn max_space_is_limited_by_free_disk_space() {
+    let root = unwrap!(TempDir::new("test"));
+    let mut chunk_store: ChunkStore<u64, Vec<u8>> =
+        unwrap!(ChunkStoreBuilder::new(root.path().to_path_buf(), 1024)
+            .free_space_margin(u64::max_value())
+            .create());
+    assert_eq!(chunk_store.max_space(), 0);
+    let key: u64 = rand::random();
+    assert_err!(chunk_store.put(&key, &generate_random_bytes(10)),
+                Error::NotEnoughSpace);
+    assert_eq!(chunk_store.used_space(), 0);
+}
+
+#[test]
+fn composite_store_survives_failed_store() {
+    let tempdir = unwrap!(TempDir::new("test"));
+    let first_root = tempdir.path().join("first");
+    let second_root = tempdir.path().join("second");
+    let first: ChunkStore<u64, Vec<u8>> = unwrap!(ChunkStore::new(first_root.clone(), 4096));
+    let second: ChunkStore<u64, Vec<u8>> = unwrap!(ChunkStore::new(second_root.clone(), 4096));
+    let mut chunk_store =
+        unwrap!(CompositeChunkStore::new(vec![Box::new(first) as Box<ChunkStorage<_, _>>,
+                                              Box::new(second)]));
+
+    let keys = (0..20).collect::<Vec<u64>>();
+    for key in &keys {
+        unwrap!(chunk_store.put(key, &generate_random_bytes(100)));
+    }
+    let mut stored_keys = chunk_store.keys();
+    stored_keys.sort();
+    assert_eq!(stored_keys, keys);
+    assert!(chunk_store.take_lost_keys().is_empty());
+
+    // After the first store failed, the second one keeps working.  As the chunks were balanced
+    // across both stores, only some of them are lost.
+    let used_space = chunk_store.used_space();
+    unwrap!(fs::remove_dir_all(&first_root));
+    let lost_keys = chunk_store.take_lost_keys();
+    assert!(!lost_keys.is_empty());
+    assert!(lost_keys.len() < keys.len());
+    assert!(chunk_store.used_space() < used_space);
+    for key in &lost_keys {
+        assert!(!chunk_store.has(key));
+        assert_err!(chunk_store.get(key), Error::NotFound);
+    }
+    let mut remaining_keys = chunk_store.keys();
+    for key in &remaining_keys {
+        let _ = unwrap!(chunk_store.get(key));
+    }
+    remaining_keys.extend(lost_keys.iter().cloned());
+    remaining_keys.sort();
+    assert_eq!(remaining_keys, keys);
+    assert!(chunk_store.take_lost_keys().is_empty());
+
+    unwrap!(chunk_store.put(&lost_keys[0], &generate_random_bytes(100)));
+    assert!(chunk_store.has(&lost_keys[0]));
+}
+
+#[test]
+fn composite_store_keeps_one_copy_of_duplicates() {
+    let tempdir = unwrap!(TempDir::new("test"));
+    let first_root = tempdir.path().join("first");
+    let second_root = tempdir.path().join("second");
+    let first_data = generate_random_bytes(100);
+    let second_data = generate_random_bytes(100);
+    let open = |root: &PathBuf| -> ChunkStore<u64, Vec<u8>> {
+        unwrap!(ChunkStore::open(root.clone(), 4096))
+    };
+    {
+        let mut first = open(&first_root);
+        let mut second = open(&second_root);
+        unwrap!(first.put(&1, &first_data));
+        unwrap!(second.put(&1, &second_data));
+        unwrap!(second.put(&2, &second_data));
+    }
+
+    // The copy in the first store is kept, and the one in the second store deleted.
+    let chunk_store = unwrap!(CompositeChunkStore::new(vec![Box::new(open(&first_root)) as
+                                                            Box<ChunkStorage<_, _>>,
+                                                            Box::new(open(&second_root))]));
+    assert_eq!(first_data, unwrap!(chunk_store.get(&1)));
+    assert_eq!(second_data, unwrap!(chunk_store.get(&2)));
+    drop(chunk_store);
+    let second = open(&second_root);
+    assert!(!second.has(&1));
+    assert!(second.has(&2));
+}
+
+#[test]
+fn tiered_store_promotes_and_demotes_chunks() {
+    let tempdir = unwrap!(TempDir::new("test"));
+    let hot_root = tempdir.path().join("hot");
+    let cold_root = tempdir.path().join("cold");
+    let values = (0..5).map(|_| generate_random_bytes(100)).collect::<Vec<_>>();
+    // The hot tier only has room for two chunks.
+    let hot_capacity = 2 * chunk_file_size(&values[0]);
+    let open_tier = |root: &PathBuf, capacity| -> ChunkStore<u64, Vec<u8>> {
+        unwrap!(ChunkStoreBuilder::new(root.clone(), capacity).persistent(true).create())
+    };
+    let open_store = || {
+        unwrap!(TieredChunkStore::new(Box::new(open_tier(&hot_root, hot_capacity)),
+                                      Box::new(open_tier(&cold_root, 4096))))
+    };
+    let hot_keys = || {
+        let mut keys = open_tier(&hot_root, hot_capacity).keys();
+        keys.sort();
+        keys
+    };
+
+    // New chunks are placed in the cold tier, and the ones read often are promoted.
+    {
+        let mut chunk_store = open_store();
+        for (key, value) in values.iter().enumerate() {
+            unwrap!(chunk_store.put(&(key as u64), value));
+        }
+        unwrap!(chunk_store.rebalance());
+        for _ in 0..4 {
+            let _ = unwrap!(chunk_store.get(&0));
+            let _ = unwrap!(chunk_store.get(&1));
+        }
+        unwrap!(chunk_store.rebalance());
+    }
+    assert_eq!(hot_keys(), vec![0, 1]);
+    assert!(!open_tier(&cold_root, 4096).has(&0));
+
+    // A hot chunk's new version which doesn't fit in the hot tier is moved to the cold tier.  If
+    // it doesn't fit there either, the old version is kept.
+    {
+        let mut chunk_store = open_store();
+        assert_err!(chunk_store.put(&1, &generate_random_bytes(4000)),
+                    Error::NotEnoughSpace);
+        assert_eq!(values[1], unwrap!(chunk_store.get(&1)));
+        let larger_value = generate_random_bytes(200);
+        unwrap!(chunk_store.put(&1, &larger_value));
+        assert_eq!(larger_value, unwrap!(chunk_store.get(&1)));
+        unwrap!(chunk_store.put(&1, &values[1]));
+    }
+    assert_eq!(hot_keys(), vec![0]);
+
+    // Hot chunks which aren't read anymore make room for others.
+    {
+        let mut chunk_store = open_store();
+        for _ in 0..8 {
+            let _ = unwrap!(chunk_store.get(&2));
+        }
+        let _ = unwrap!(chunk_store.get(&0));
+        unwrap!(chunk_store.rebalance());
+    }
+    assert_eq!(hot_keys(), vec![0, 2]);
+
+    {
+        let mut chunk_store = open_store();
+        unwrap!(chunk_store.rebalance());
+        let mut keys = chunk_store.keys();
+        keys.sort();
+        assert_eq!(keys, (0..5).collect::<Vec<_>>());
+        for (key, value) in values.iter().enumerate() {
+            assert_eq!(*value, unwrap!(chunk_store.get(&(key as u64))));
+        }
+    }
+    assert!(hot_keys().is_empty());
+}
+
+#[test]
+fn small_chunks_are_packed() {
+    let tempdir = unwrap!(TempDir::new("test"));
+    let storedir = tempdir.path().join("test");
+    let small_data = generate_random_bytes(100);
+    let large_data = generate_random_bytes(1000);
+    let small_key: u64 = rand::random();
+    let large_key = small_key.wrapping_add(1);
+    let open_packing_store = || -> ChunkStore<u64, Vec<u8>> {
+        unwrap!(ChunkStoreBuilder::new(storedir.clone(), 8192)
+            .persistent(true)
+            .pack_threshold(500)
+            .create())
+    };
+
+    {
+        let mut chunk_store = open_packing_store();
+        unwrap!(chunk_store.put(&small_key, &small_data));
+        unwrap!(chunk_store.put(&large_key, &large_data));
+        assert!(!unwrap!(chunk_store.file_path(&small_key)).exists());
+        assert!(unwrap!(chunk_store.file_path(&large_key)).is_file());
+        assert!(chunk_store.has(&small_key));
+        assert_eq!(small_data, unwrap!(chunk_store.get(&small_key)));
+        unwrap!(chunk_store.verify(&small_key));
+    }
+
+    // Packed chunks survive reopening, and move between the packs and their own files when they
+    // change size.
+    let mut chunk_store = open_packing_store();
+    let mut keys = chunk_store.keys();
+    keys.sort();
+    assert_eq!(keys, vec![small_key, large_key]);
+    assert_eq!(small_data, unwrap!(chunk_store.get(&small_key)));
+    assert_eq!(large_data, unwrap!(chunk_store.get(&large_key)));
+
+    unwrap!(chunk_store.put(&small_key, &large_data));
+    unwrap!(chunk_store.put(&large_key, &small_data));
+    assert!(unwrap!(chunk_store.file_path(&small_key)).is_file());
+    assert!(!unwrap!(chunk_store.file_path(&large_key)).exists());
+    assert_eq!(large_data, unwrap!(chunk_store.get(&small_key)));
+    assert_eq!(small_data, unwrap!(chunk_store.get(&large_key)));
+
+    unwrap!(chunk_store.delete(&large_key));
+    assert!(!chunk_store.has(&large_key));
+    assert_err!(chunk_store.get(&large_key), Error::NotFound);
+    assert_eq!(chunk_store.keys(), vec![small_key]);
+}
+
+#[test]
+fn snapshot_and_restore() {
+    let tempdir = unwrap!(TempDir::new("test"));
+    let storedir = tempdir.path().join("test");
+    let snapshot_dir = tempdir.path().join("snapshot");
+    let small_data = generate_random_bytes(100);
+    let large_data = generate_random_bytes(1000);
+    let small_key: u64 = rand::random();
+    let large_key = small_key.wrapping_add(1);
+    let builder = || ChunkStoreBuilder::new(storedir.clone(), 8192).pack_threshold(500);
+
+    {
+        let mut chunk_store: ChunkStore<u64, Vec<u8>> =
+            unwrap!(builder().persistent(true).create());
+        unwrap!(chunk_store.put(&small_key, &small_data));
+        unwrap!(chunk_store.put(&large_key, &large_data));
+        let stats = unwrap!(chunk_store.snapshot(&snapshot_dir));
+        // The large chunk file, the pack segment and the format file.
+        assert_eq!(stats.files, 3);
+        assert_err!(chunk_store.snapshot(&snapshot_dir), Error::Io(_));
+
+        // Later writes don't affect the snapshot.
+        unwrap!(chunk_store.put(&small_key, &large_data));
+        unwrap!(chunk_store.put(&large_key, &small_data));
+        unwrap!(chunk_store.put(&(small_key.wrapping_add(2)), &small_data));
+    }
+
+    // Pack segments are copied rather than hardlinked, so appending to the restored store's
+    // segments doesn't affect the snapshot either.
+    for _ in 0..2 {
+        unwrap!(check_snapshot(&snapshot_dir));
+        let mut chunk_store: ChunkStore<u64, Vec<u8>> =
+            unwrap!(builder().restore_from(snapshot_dir.clone()).create());
+        let mut keys = chunk_store.keys();
+        keys.sort();
+        assert_eq!(keys, vec![small_key, large_key]);
+        assert_eq!(small_data, unwrap!(chunk_store.get(&small_key)));
+        assert_eq!(large_data, unwrap!(chunk_store.get(&large_key)));
+        unwrap!(chunk_store.put(&small_key, &generate_random_bytes(200)));
+        unwrap!(chunk_store.delete(&large_key));
+    }
+
+    // A snapshot which doesn't match its manifest is refused without touching the store.
+    unwrap!(fs::remove_file(snapshot_dir.join("packs").join("0.pack")));
+    assert_err!(check_snapshot(&snapshot_dir), Error::InvalidSnapshot);
+    assert_err!(builder().restore_from(snapshot_dir.clone()).create::<u64, Vec<u8>>().map(|_| ()),
+                Error::InvalidSnapshot);
+    let chunk_store: ChunkStore<u64, Vec<u8>> = unwrap!(builder().persistent(true).create());
+    assert_eq!(chunk_store.keys(), vec![small_key]);
+}
+
+#[test]
+fn pack_segments_are_compacted() {
+    let tempdir = unwrap!(TempDir::new("test"));
+    let dir = tempdir.path().join("packs");
+    let contents = generate_random_bytes(100);
+    let names = (0..10).map(|index| format!("{}", index)).collect::<Vec<_>>();
+
+    let mut packs = unwrap!(Packs::open(dir.clone(), 500));
+    for name in &names {
+        unwrap!(packs.put(name, &contents));
+    }
+    let full_size = packs.size();
+
+    // Overwriting and deleting most chunks lets the old segments be removed.
+    for name in &names[..8] {
+        let _ = unwrap!(packs.delete(name));
+    }
+    unwrap!(packs.put(&names[8], &contents[..10]));
+    assert!(packs.size() < full_size);
+    let mut remaining_names = packs.names();
+    remaining_names.sort();
+    assert_eq!(remaining_names, vec![names[8].clone(), names[9].clone()]);
+    assert_eq!(Some(contents[..10].to_vec()), unwrap!(packs.get(&names[8])));
+    assert_eq!(Some(contents.clone()), unwrap!(packs.get(&names[9])));
+    assert_eq!(None, unwrap!(packs.get(&names[0])));
+
+    // Reopening gives the same index, even with a torn entry at the end of the newest segment.
+    let size = packs.size();
+    let newest_segment = unwrap!(unwrap!(fs::read_dir(&dir))
+            .map(|entry| unwrap!(entry).path())
+            .max_by_key(|path| {
+                unwrap!(unwrap!(path.file_stem()).to_string_lossy().parse::<u64>())
+            }));
+    let mut file = unwrap!(OpenOptions::new().append(true).open(newest_segment));
+    unwrap!(file.write_all(&[1, 0, 0]));
+    let packs = unwrap!(Packs::open(dir, 500));
+    assert_eq!(packs.size(), size);
+    assert_eq!(Some(contents.clone()), unwrap!(packs.get(&names[9])));
+    assert!(!packs.contains(&names[0]));
+}
+
+#[test]
+fn damaged_pack_segments_are_kept() {
+    let tempdir = unwrap!(TempDir::new("test"));
+    let dir = tempdir.path().join("packs");
+    let contents = generate_random_bytes(100);
+    let names = (0..9).map(|index| format!("{}", index)).collect::<Vec<_>>();
+
+    // Segment 0 holds the first five chunks, segment 1 the next three.
+    let mut packs = unwrap!(Packs::open(dir.clone(), 500));
+    for name in &names[..8] {
+        unwrap!(packs.put(name, &contents));
+    }
+    let segment_path = dir.join("1.pack");
+    let mut segment = Vec::new();
+    let _ = unwrap!(unwrap!(File::open(&segment_path)).read_to_end(&mut segment));
+    assert_eq!(segment.len(), 330);
+
+    // Damage the kind byte of the second entry of the newest segment.  The entries before it and
+    // in the other segment are still read, and the segment is neither truncated nor appended to.
+    let mut file = unwrap!(OpenOptions::new().write(true).open(&segment_path));
+    let _ = unwrap!(file.seek(SeekFrom::Start(110)));
+    unwrap!(file.write_all(&[7]));
+    let mut packs = unwrap!(Packs::open(dir.clone(), 500));
+    for name in &names[..6] {
+        assert_eq!(Some(contents.clone()), unwrap!(packs.get(name)));
+    }
+    assert!(!packs.contains(&names[6]));
+    assert!(!packs.contains(&names[7]));
+    assert_eq!(unwrap!(fs::metadata(&segment_path)).len(), 330);
+    let mut copy = Vec::new();
+    let _ = unwrap!(unwrap!(File::open(dir.join("1.damaged"))).read_to_end(&mut copy));
+    segment[110] = 7;
+    assert_eq!(copy, segment);
+
+    unwrap!(packs.put(&names[8], &contents));
+    assert!(dir.join("2.pack").exists());
+    let packs = unwrap!(Packs::open(dir, 500));
+    assert_eq!(Some(contents.clone()), unwrap!(packs.get(&names[5])));
+    assert_eq!(Some(contents.clone()), unwrap!(packs.get(&names[8])));
+}
+
+#[test]
+fn iter_keys() {
+    let root = unwrap!(TempDir::new("test"));
+    let mut chunk_store: ChunkStore<u64, Vec<u8>> =
+        unwrap!(ChunkStoreBuilder::new(root.path().to_path_buf(), 64 * 1024)
+            .pack_threshold(100)
+            .create());
+    assert_eq!(chunk_store.iter_keys().next(), None);
+
+    let mut keys = (0..20).collect::<Vec<u64>>();
+    for key in &keys {
+        let size = if key % 2 == 0 { 10 } else { 1000 };
+        unwrap!(chunk_store.put(key, &generate_random_bytes(size)));
+    }
+    let mut iterated_keys = chunk_store.iter_keys().collect::<Vec<_>>();
+    iterated_keys.sort();
+    assert_eq!(iterated_keys, keys);
+
+    unwrap!(chunk_store.quarantine(&0));
+    unwrap!(chunk_store.quarantine(&1));
+    let _ = keys.drain(..2);
+    let mut iterated_keys = chunk_store.iter_keys().collect::<Vec<_>>();
+    iterated_keys.sort();
+    assert_eq!(iterated_keys, keys);
+}
+
+#[test]
+fn name_index_buckets() {
+    let name = |first_byte: u8, last_byte: u8| {
+        let mut bytes = [0; 32];
+        bytes[0] = first_byte;
+        bytes[31] = last_byte;
+        XorName(bytes)
+    };
+    let target = name(0b1000_0000, 0);
+    let mut index = NameIndex::default();
+    index.insert(name(0b0000_0000, 1), 1);
+    index.insert(name(0b0100_0000, 2), 2);
+    index.insert(name(0b1100_0000, 3), 3);
+    index.insert(name(0b1000_0000, 4), 4);
+    index.insert(name(0b1000_0000, 5), 5);
+    index.insert(target, 6);
+    index.insert(target, 6);
+
+    // Buckets are listed closest first, and hold the names sharing exactly that many leading bits
+    // with the target.
+    assert_eq!(index.buckets(&target),
+               vec![(256, vec![&6]), (253, vec![&4, &5]), (1, vec![&3]), (0, vec![&1, &2])]);
+
+    // The closest keys and those within a distance are sorted by their distance to the target.
+    assert_eq!(index.closest(&target, 4), vec![&6, &4, &5, &3]);
+    assert_eq!(index.closest(&name(0b0100_0000, 0), 2), vec![&2, &1]);
+    assert_eq!(index.within_distance(&target, &name(0, 0)), vec![&6]);
+    assert_eq!(index.within_distance(&target, &name(0, 4)), vec![&6, &4]);
+    assert_eq!(index.within_distance(&target, &name(0b0100_0000, 3)),
+               vec![&6, &4, &5, &3]);
+    assert_eq!(index.within_distance(&name(0b1100_0000, 0), &name(0b1000_0000, 0)),
+               vec![&3, &6, &4, &5]);
+
+    // Keys are only removed from under their own name.
+    index.remove(&target, &6);
+    index.remove(&name(0b0000_0000, 1), &2);
+    assert_eq!(index.buckets(&target),
+               vec![(253, vec![&4, &5]), (1, vec![&3]), (0, vec![&1, &2])]);
+}
+
+#[test]
+fn read_only_store() {
+    let tempdir = unwrap!(TempDir::new("test"));
+    let storedir = tempdir.path().join("test");
+    let data = generate_random_bytes(100);
+    let key: u64 = rand::random();
+    let stray_file_path = storedir.join("not_a_chunk");
+
+    {
+        let mut chunk_store = unwrap!(ChunkStore::<u64, Vec<u8>>::open(storedir.clone(), 1024));
+        unwrap!(chunk_store.put(&key, &data));
+        assert_eq!(unwrap!(chunk_store.stored_size(&key)), chunk_file_size(&data));
+
+        // A store in use can't be opened read-only.
+        assert_err!(ChunkStoreBuilder::new(storedir.clone(), 1024)
+                        .read_only(true)
+                        .create::<u64, Vec<u8>>()
+                        .map(|_| ()),
+                    Error::Io(_));
+    }
+    let _ = unwrap!(File::create(&stray_file_path));
+
+    let mut chunk_store: ChunkStore<u64, Vec<u8>> =
+        unwrap!(ChunkStoreBuilder::new(storedir.clone(), 1024).read_only(true).create());
+    assert_eq!(chunk_store.keys(), vec![key]);
+    assert_eq!(data, unwrap!(chunk_store.get(&key)));
+    assert_eq!(chunk_store.used_space(), chunk_file_size(&data));
+    assert_eq!(unwrap!(chunk_store.stray_files()), vec![stray_file_path.clone()]);
+    assert_err!(chunk_store.put(&key, &data), Error::ReadOnly);
+    assert_err!(chunk_store.delete(&key), Error::ReadOnly);
+    assert_err!(chunk_store.quarantine(&key), Error::ReadOnly);
+    assert_err!(chunk_store.stored_size(&key.wrapping_add(1)), Error::NotFound);
+    drop(chunk_store);
+
+    // Dropping a read-only store doesn't remove anything.
+    assert!(stray_file_path.is_file());
+    let chunk_store: ChunkStore<u64, Vec<u8>> =
+        unwrap!(ChunkStoreBuilder::new(storedir, 1024).read_only(true).create());
+    assert_eq!(chunk_store.keys(), vec![key]);
+}
+
+#[test]
+fn unversioned_store_is_migrated() {
+    let tempdir = unwrap!(TempDir::new("test"));
+    let storedir = tempdir.path().join("test");
+    let data = generate_random_bytes(100);
+    let packed_data = generate_random_bytes(10);
+    let key: u64 = rand::random();
+    let packed_key = key.wrapping_add(1);
+    let open_store = |migrate_format: bool| {
+        ChunkStoreBuilder::new(storedir.clone(), 1024)
+            .persistent(true)
+            .pack_threshold(100)
+            .migrate_format(migrate_format)
+            .create::<u64, Vec<u8>>()
+    };
+
+    // Turn the store into one written before format versions were recorded, by stripping the
+    // magic bytes and version from every chunk.
+    let file_path = {
+        let mut chunk_store = unwrap!(open_store(true));
+        unwrap!(chunk_store.put(&key, &data));
+        unwrap!(chunk_store.put(&packed_key, &packed_data));
+        let file_path = unwrap!(chunk_store.file_path(&key));
+        let packed_name = unwrap!(unwrap!(chunk_store.file_path(&packed_key)).file_name())
+            .to_string_lossy()
+            .into_owned();
+        let contents = unwrap!(chunk_store.read_file(&file_path));
+        let mut file = unwrap!(File::create(&file_path));
+        unwrap!(file.write_all(&contents[5..]));
+        if let Some(ref mut packs) = chunk_store.packs {
+            let contents = unwrap!(unwrap!(packs.get(&packed_name)));
+            unwrap!(packs.put(&packed_name, &contents[5..]));
+        }
+        file_path
+    };
+    unwrap!(fs::remove_file(storedir.join(FORMAT_FILE_NAME)));
+
+    assert_err!(open_store(false).map(|_| ()), Error::OutdatedFormat(1));
+    assert_err!(ChunkStoreBuilder::new(storedir.clone(), 1024)
+                    .read_only(true)
+                    .create::<u64, Vec<u8>>()
+                    .map(|_| ()),
+                Error::OutdatedFormat(1));
+
+    {
+        let chunk_store = unwrap!(open_store(true));
+        assert_eq!(data, unwrap!(chunk_store.get(&key)));
+        assert_eq!(packed_data, unwrap!(chunk_store.get(&packed_key)));
+        assert_eq!(unwrap!(chunk_store.stored_size(&key)), chunk_file_size(&data));
+    }
+
+    // A store from a newer version is refused.
+    let mut file = unwrap!(File::create(storedir.join(FORMAT_FILE_NAME)));
+    unwrap!(file.write_all(b"safe_vault chunk store format 200\n"));
+    assert_err!(open_store(true).map(|_| ()), Error::UnsupportedFormat(200));
+    assert!(file_path.is_file());
+}
+
+#[test]
+fn interrupted_migration_is_resumed() {
+    let tempdir = unwrap!(TempDir::new("test"));
+    let storedir = tempdir.path().join("test");
+    let data = generate_random_bytes(100);
+    let migrated_data = generate_random_bytes(100);
+    let packed_data = generate_random_bytes(10);
+    let key: u64 = rand::random();
+    let migrated_key = key.wrapping_add(1);
+    let packed_key = key.wrapping_add(2);
+    let open_store = || {
+        ChunkStoreBuilder::new(storedir.clone(), 1024)
+            .persistent(true)
+            .pack_threshold(100)
+            .create::<u64, Vec<u8>>()
+    };
+
+    // Leave the store as a crash halfway through migrating an unversioned store would: one chunk
+    // file is still unversioned, the other and the packed chunk are already migrated, and the new
+    // format version hasn't been recorded yet.
+    {
+        let mut chunk_store = unwrap!(open_store());
+        unwrap!(chunk_store.put(&key, &data));
+        unwrap!(chunk_store.put(&migrated_key, &migrated_data));
+        unwrap!(chunk_store.put(&packed_key, &packed_data));
+        let file_path = unwrap!(chunk_store.file_path(&key));
+        let contents = unwrap!(chunk_store.read_file(&file_path));
+        let mut file = unwrap!(File::create(&file_path));
+        unwrap!(file.write_all(&contents[5..]));
+    }
+    unwrap!(fs::remove_file(storedir.join(FORMAT_FILE_NAME)));
+
+    // Migrating again leaves the migrated chunks alone, and so does a third run after the format
+    // file is lost again.
+    for _ in 0..2 {
+        {
+            let chunk_store = unwrap!(open_store());
+            assert_eq!(data, unwrap!(chunk_store.get(&key)));
+            assert_eq!(migrated_data, unwrap!(chunk_store.get(&migrated_key)));
+            assert_eq!(packed_data, unwrap!(chunk_store.get(&packed_key)));
+            assert_eq!(unwrap!(chunk_store.stored_size(&key)), chunk_file_size(&data));
+        }
+        unwrap!(fs::remove_file(storedir.join(FORMAT_FILE_NAME)));
+    }
+}
+
+#[test]
+fn chunk_files_from_before_the_checksum_format_are_migrated() {
+    let tempdir = unwrap!(TempDir::new("test"));
+    let storedir = tempdir.path().join("test");
+    let bare_data = generate_random_bytes(100);
+    let checksummed_data = generate_random_bytes(100);
+    let bare_key: u64 = rand::random();
+    let checksummed_key = bare_key.wrapping_add(1);
+    let corrupted_key = bare_key.wrapping_add(2);
+    let open_store = || {
+        ChunkStoreBuilder::new(storedir.clone(), 1024)
+            .persistent(true)
+            .create::<u64, Vec<u8>>()
+    };
+
+    // Write the chunk files as they were before checksums, and before the compression format.
+    {
+        let mut chunk_store = unwrap!(open_store());
+        for &(key, data) in &[(bare_key, &bare_data),
+                                  (checksummed_key, &checksummed_data),
+                                  (corrupted_key, &bare_data)] {
+            unwrap!(chunk_store.put(&key, data));
+        }
+        let serialised_value = unwrap!(serialisation::serialise(&bare_data));
+        let mut file = unwrap!(File::create(unwrap!(chunk_store.file_path(&bare_key))));
+        unwrap!(file.write_all(&serialised_value));
+        let serialised_value = unwrap!(serialisation::serialise(&checksummed_data));
+        let mut file = unwrap!(File::create(unwrap!(chunk_store.file_path(&checksummed_key))));
+        unwrap!(file.write_all(&sha256::hash(&serialised_value).0));
+        unwrap!(file.write_all(&serialised_value));
+        let mut file = unwrap!(File::create(unwrap!(chunk_store.file_path(&corrupted_key))));
+        unwrap!(file.write_all(&[0xff; 50]));
+    }
+    unwrap!(fs::remove_file(storedir.join(FORMAT_FILE_NAME)));
+
+    let chunk_store = unwrap!(open_store());
+    assert_eq!(bare_data, unwrap!(chunk_store.get(&bare_key)));
+    assert_eq!(checksummed_data, unwrap!(chunk_store.get(&checksummed_key)));
+    assert_err!(chunk_store.get(&corrupted_key), Error::Corrupted);
+    assert_eq!(unwrap!(chunk_store.stored_size(&bare_key)), chunk_file_size(&bare_data));
+}
@@ -0,0 +1,314 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use super::{ChunkStorage, CompressionStats, Error, ReservedSpace, SnapshotStats};
+use maidsafe_utilities::serialisation;
+use rustc_serialize::{Decodable, Encodable};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+/// The score a chunk needs to be promoted to the hot tier.  A chunk's score is the number of reads
+/// since the last rebalancing plus half its previous score, so this is reached by a few reads in
+/// quick succession or by steady reads over a longer time.
+const PROMOTION_SCORE: u64 = 4;
+/// The maximum number of chunks moved between the tiers in one call to `rebalance`.
+const MAX_MOVES_PER_REBALANCE: usize = 16;
+
+/// `TieredChunkStore` keeps the chunks in one of two underlying stores: a small, fast hot tier and
+/// a large, slow cold tier.
+///
+/// New chunks are placed in the cold tier.  The chunks which are read often are promoted to the hot
+/// tier by `rebalance`, which also demotes the hot chunks which haven't been read for a while.
+pub struct TieredChunkStore<Key, Value> {
+    hot: Box<ChunkStorage<Key, Value>>,
+    cold: Box<ChunkStorage<Key, Value>>,
+    /// The serialised keys of the chunks held in the hot tier.
+    hot_keys: HashSet<Vec<u8>>,
+    /// The number of reads of each chunk since the last rebalancing, by serialised key.
+    reads: RefCell<HashMap<Vec<u8>, u64>>,
+    /// The decaying read scores of the chunks which have been read recently, by serialised key.
+    scores: HashMap<Vec<u8>, u64>,
+    reserved_space: ReservedSpace,
+}
+
+impl<Key, Value> TieredChunkStore<Key, Value>
+    where Key: Decodable + Encodable,
+          Value: Decodable + Encodable
+{
+    /// Creates a `TieredChunkStore` from the given stores, which may already hold chunks.  Chunks
+    /// found in both tiers, e.g. after a move was interrupted, are removed from the cold tier.
+    pub fn new(hot: Box<ChunkStorage<Key, Value>>,
+               mut cold: Box<ChunkStorage<Key, Value>>)
+               -> Result<TieredChunkStore<Key, Value>, Error> {
+        let mut hot_keys = HashSet::new();
+        for key in hot.keys() {
+            if cold.has(&key) {
+                cold.delete(&key)?;
+            }
+            let _ = hot_keys.insert(serialisation::serialise(&key)?);
+        }
+        Ok(TieredChunkStore {
+            hot: hot,
+            cold: cold,
+            hot_keys: hot_keys,
+            reads: RefCell::new(HashMap::new()),
+            scores: HashMap::new(),
+            reserved_space: ReservedSpace::default(),
+        })
+    }
+
+    /// Moves a chunk from the hot to the cold tier, or the other way round.
+    fn move_chunk(&mut self, serialised_key: &[u8], to_hot: bool) -> Result<(), Error> {
+        let key = serialisation::deserialise::<Key>(serialised_key)?;
+        if to_hot {
+            let value = self.cold.get(&key)?;
+            self.hot.put(&key, &value)?;
+            let _ = self.hot_keys.insert(serialised_key.to_vec());
+            self.cold.delete(&key)
+        } else {
+            let value = self.hot.get(&key)?;
+            self.cold.put(&key, &value)?;
+            let _ = self.hot_keys.remove(serialised_key);
+            self.hot.delete(&key)
+        }
+    }
+
+    /// Promotes a chunk, making room for it by demoting the hot chunk with the lowest score if
+    /// that is below `score`.  Returns the number of chunks moved.
+    fn promote(&mut self, serialised_key: &[u8], score: u64) -> Result<usize, Error> {
+        match self.move_chunk(serialised_key, true) {
+            Err(Error::NotEnoughSpace) => (),
+            result => return result.map(|()| 1),
+        }
+        let coldest = self.hot_keys
+            .iter()
+            .map(|hot_key| (self.scores.get(hot_key).cloned().unwrap_or(0), hot_key))
+            .min()
+            .and_then(|(hot_score, hot_key)| if hot_score < score {
+                Some(hot_key.clone())
+            } else {
+                None
+            });
+        match coldest {
+            Some(hot_key) => {
+                self.move_chunk(&hot_key, false)?;
+                match self.move_chunk(serialised_key, true) {
+                    Ok(()) => Ok(2),
+                    Err(Error::NotEnoughSpace) => Ok(1),
+                    Err(error) => Err(error),
+                }
+            }
+            None => Ok(0),
+        }
+    }
+}
+
+impl<Key, Value> ChunkStorage<Key, Value> for TieredChunkStore<Key, Value>
+    where Key: Decodable + Encodable,
+          Value: Decodable + Encodable
+{
+    /// Replaces an existing chunk in its tier, and places a new one in the cold tier if it has
+    /// room, else in the hot tier.  If a hot chunk's new version doesn't fit in the hot tier, it is
+    /// moved to the cold tier, and the old version is only deleted once the new one is written.
+    fn put(&mut self, key: &Key, value: &Value) -> Result<(), Error> {
+        let serialised_key = serialisation::serialise(key)?;
+        if self.hot_keys.contains(&serialised_key) {
+            match self.hot.put(key, value) {
+                Err(Error::NotEnoughSpace) => (),
+                result => return result,
+            }
+            self.cold.put(key, value)?;
+            let _ = self.hot_keys.remove(&serialised_key);
+            return self.hot.delete(key);
+        }
+        match self.cold.put(key, value) {
+            Err(Error::NotEnoughSpace) => {
+                self.hot.put(key, value)?;
+                let _ = self.hot_keys.insert(serialised_key);
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    fn delete(&mut self, key: &Key) -> Result<(), Error> {
+        let serialised_key = serialisation::serialise(key)?;
+        let _ = self.scores.remove(&serialised_key);
+        let _ = self.reads.borrow_mut().remove(&serialised_key);
+        if self.hot_keys.remove(&serialised_key) {
+            self.hot.delete(key)
+        } else {
+            self.cold.delete(key)
+        }
+    }
+
+    /// Reads the chunk from the tier holding it, and counts the read towards its promotion.
+    fn get(&self, key: &Key) -> Result<Value, Error> {
+        let serialised_key = serialisation::serialise(key)?;
+        let value = if self.hot_keys.contains(&serialised_key) {
+            self.hot.get(key)?
+        } else {
+            self.cold.get(key)?
+        };
+        *self.reads.borrow_mut().entry(serialised_key).or_insert(0) += 1;
+        Ok(value)
+    }
+
+    fn has(&self, key: &Key) -> bool {
+        serialisation::serialise(key)
+            .map(|serialised_key| self.hot_keys.contains(&serialised_key))
+            .unwrap_or(false) || self.cold.has(key)
+    }
+
+    fn keys(&self) -> Vec<Key> {
+        self.iter_keys().collect()
+    }
+
+    fn iter_keys<'a>(&'a self) -> Box<Iterator<Item = Key> + 'a>
+        where Key: 'a
+    {
+        Box::new(self.hot.iter_keys().chain(self.cold.iter_keys()))
+    }
+
+    fn verify(&self, key: &Key) -> Result<(), Error> {
+        if self.hot_keys.contains(&serialisation::serialise(key)?) {
+            self.hot.verify(key)
+        } else {
+            self.cold.verify(key)
+        }
+    }
+
+    fn quarantine(&mut self, key: &Key) -> Result<(), Error> {
+        if self.hot_keys.remove(&serialisation::serialise(key)?) {
+            self.hot.quarantine(key)
+        } else {
+            self.cold.quarantine(key)
+        }
+    }
+
+    fn max_space(&self) -> u64 {
+        self.hot.max_space() + self.cold.max_space()
+    }
+
+    fn used_space(&self) -> u64 {
+        self.hot.used_space() + self.cold.used_space()
+    }
+
+    /// Asks the tier holding the chunk, or else the cold tier new chunks are placed in.
+    fn space_needed(&self, key: &Key, value: &Value) -> Result<u64, Error> {
+        if self.hot_keys.contains(&serialisation::serialise(key)?) {
+            self.hot.space_needed(key, value)
+        } else {
+            self.cold.space_needed(key, value)
+        }
+    }
+
+    fn reserved(&self) -> &ReservedSpace {
+        &self.reserved_space
+    }
+
+    fn compression_stats(&self) -> CompressionStats {
+        let hot_stats = self.hot.compression_stats();
+        let cold_stats = self.cold.compression_stats();
+        CompressionStats {
+            uncompressed_bytes: hot_stats.uncompressed_bytes + cold_stats.uncompressed_bytes,
+            stored_bytes: hot_stats.stored_bytes + cold_stats.stored_bytes,
+        }
+    }
+
+    fn check_health(&self) -> Result<(), Error> {
+        self.hot.check_health()?;
+        self.cold.check_health()
+    }
+
+    fn refresh_free_space(&mut self) {
+        self.hot.refresh_free_space();
+        self.cold.refresh_free_space();
+    }
+
+    fn snapshot_stores(&self, dest: &Path, stats: &mut Vec<SnapshotStats>) -> Result<(), Error> {
+        self.cold.snapshot_stores(dest, stats)?;
+        self.hot.snapshot_stores(dest, stats)
+    }
+
+    fn take_lost_keys(&mut self) -> Vec<Key> {
+        let mut lost_keys = self.hot.take_lost_keys();
+        lost_keys.extend(self.cold.take_lost_keys());
+        for key in &lost_keys {
+            if let Ok(serialised_key) = serialisation::serialise(key) {
+                let _ = self.hot_keys.remove(&serialised_key);
+            }
+        }
+        lost_keys
+    }
+
+    /// Updates the read scores, then demotes the hot chunks which haven't been read recently and
+    /// promotes the cold chunks with the highest scores.  Chunks which fail to be moved are left
+    /// where they are, and the first error is returned once the other moves are done.
+    fn rebalance(&mut self) -> Result<(), Error> {
+        let reads = self.reads.borrow_mut().drain().collect::<HashMap<_, _>>();
+        let mut scores = HashMap::new();
+        for (serialised_key, score) in self.scores.drain() {
+            if score / 2 > 0 {
+                let _ = scores.insert(serialised_key, score / 2);
+            }
+        }
+        for (serialised_key, count) in reads {
+            *scores.entry(serialised_key).or_insert(0) += count;
+        }
+        self.scores = scores;
+
+        let demotions = self.hot_keys
+            .iter()
+            .filter(|serialised_key| !self.scores.contains_key(*serialised_key))
+            .take(MAX_MOVES_PER_REBALANCE)
+            .cloned()
+            .collect::<Vec<_>>();
+        let mut moves = demotions.len();
+        let mut result = Ok(());
+        for serialised_key in demotions {
+            if let Err(error) = self.move_chunk(&serialised_key, false) {
+                warn!("Failed to demote a chunk to the cold tier: {}", error);
+                result = result.and(Err(error));
+            }
+        }
+
+        let mut promotions = self.scores
+            .iter()
+            .filter(|&(serialised_key, score)| {
+                *score >= PROMOTION_SCORE && !self.hot_keys.contains(serialised_key)
+            })
+            .map(|(serialised_key, score)| (*score, serialised_key.clone()))
+            .collect::<Vec<_>>();
+        promotions.sort_by(|lhs, rhs| rhs.0.cmp(&lhs.0));
+        for (score, serialised_key) in promotions {
+            if moves >= MAX_MOVES_PER_REBALANCE {
+                break;
+            }
+            match self.promote(&serialised_key, score) {
+                Ok(0) => break,
+                Ok(moved) => moves += moved,
+                Err(error) => {
+                    warn!("Failed to promote a chunk to the hot tier: {}", error);
+                    result = result.and(Err(error));
+                }
+            }
+        }
+        result
+    }
+}
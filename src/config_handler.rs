@@ -15,10 +15,32 @@
 // Please review the Licences for the specific language governing permissions and limitations
 // relating to use of the SAFE Network Software.
 
+use chunk_store::Compression;
 use config_file_handler::{self, FileHandler};
 use error::InternalError;
 use routing::XorName;
+use rust_sodium::crypto::{auth, secretbox};
 use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+
+/// The kind of storage used to hold the chunks a vault is responsible for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, RustcDecodable, RustcEncodable)]
+pub enum StorageBackend {
+    /// Chunks are stored as files below `chunk_store_root`.
+    Disk,
+    /// Chunks are held in memory only, and are lost when the vault stops.
+    Memory,
+}
+
+/// A chunk store directory with its own storage limit, e.g. on a separate disk.
+#[derive(Clone, Debug, RustcDecodable, RustcEncodable)]
+pub struct ChunkStoreRoot {
+    /// The directory the chunk store is created in.
+    pub path: String,
+    /// Upper limit for the storage used in this directory, in bytes.
+    pub max_capacity: Option<u64>,
+}
 
 /// Lets a vault configure a wallet address and storage limit.
 #[derive(Clone, Debug, Default, RustcDecodable, RustcEncodable)]
@@ -29,6 +51,76 @@ pub struct Config {
     pub max_capacity: Option<u64>, // measured by Bytes
     /// root directory for chunk_store directories
     pub chunk_store_root: Option<String>,
+    /// Whether stored chunks are kept across vault restarts.  By default the chunk store is
+    /// cleared on startup.
+    pub persistent_chunk_store: Option<bool>,
+    /// Number of nested subdirectory levels the chunk files are spread across.  Existing chunks are
+    /// moved when a persistent chunk store is reopened with a different depth.  At most 8.
+    pub chunk_store_dir_depth: Option<usize>,
+    /// Storage backend for the chunk store.  Defaults to `Disk`.
+    pub chunk_store_backend: Option<StorageBackend>,
+    /// Compression algorithm for newly stored chunks on disk.  Defaults to `None`.
+    pub chunk_store_compression: Option<Compression>,
+    /// Whether chunks on disk are encrypted with a key generated on first use and kept next to the
+    /// config file.
+    pub encrypt_chunk_store: Option<bool>,
+    /// Whether chunk file names are derived from a secret key, so they don't reveal which data the
+    /// vault holds.  The key is kept next to the config file, too.
+    pub hash_chunk_file_names: Option<bool>,
+    /// Several chunk store directories, e.g. one per disk, to be used instead of
+    /// `chunk_store_root` and `max_capacity`.  Chunks lost to a failed disk are fetched again from
+    /// the network.
+    pub chunk_store_roots: Option<Vec<ChunkStoreRoot>>,
+    /// Size in bytes up to which chunks are appended to shared pack files instead of getting a file
+    /// of their own.  Defaults to 0, which disables packing.
+    pub chunk_store_pack_threshold: Option<u64>,
+    /// Space in bytes each chunk store leaves free on the filesystem holding it, even if its
+    /// capacity hasn't been reached yet.  Defaults to 100 MiB.
+    pub chunk_store_free_space_margin: Option<u64>,
+    /// A chunk store directory on fast storage, with its own capacity, holding the chunks which
+    /// are read most often.  The other chunk stores then only hold the remaining chunks.  Only
+    /// used with the `Disk` backend.
+    pub chunk_store_hot_tier: Option<ChunkStoreRoot>,
+    /// Whether the chunks passing through this vault are cached to answer later
+    /// requests for them.  Defaults to `true`.
+    pub use_cache: Option<bool>,
+    /// Upper limit for the total size of the cached chunks, in bytes.
+    pub cache_max_bytes: Option<u64>,
+    /// Upper limit for the number of cached chunks.
+    pub cache_max_entries: Option<usize>,
+    /// Number of seconds after which chunks which haven't been requested are dropped from the
+    /// cache.
+    pub cache_expiry_secs: Option<u64>,
+    /// Directory the chunks evicted from the in-memory cache are moved to, so they can still be
+    /// served, even after a restart.  It must not be shared with a chunk store.  By default,
+    /// chunks are only cached in memory.
+    pub cache_dir: Option<String>,
+    /// Upper limit for the size of the chunks in `cache_dir`, in bytes.
+    pub cache_dir_max_bytes: Option<u64>,
+    /// Number of seconds for which `StructuredData` and appendable data passing through this vault
+    /// are cached.  Zero disables caching them.
+    pub cache_mutable_expiry_secs: Option<u64>,
+    /// Directory in which the client accounts managed by this vault are kept across restarts.
+    /// By default they are only held in memory.
+    pub account_store_root: Option<String>,
+    /// Whether the refreshes between the managers of a client account carry the bytes stored.
+    /// Vaults which only count chunks can't decode these, so this should only be enabled once all
+    /// vaults have been upgraded.  By default, only the chunk counts are sent, in the old encoding,
+    /// and `Put`s are only charged by chunk count.
+    pub send_account_bytes: Option<bool>,
+    /// Whether `GetAccountInfo` requests are answered with the bytes stored and available instead
+    /// of the chunk counts.  The response only has room for two values, so by default it carries
+    /// the chunk counts existing clients expect.  This only takes effect together with
+    /// `send_account_bytes`, as the bytes aren't charged otherwise.
+    pub report_account_bytes: Option<bool>,
+}
+
+/// The secret keys protecting the contents of the chunk store.
+pub struct ChunkStoreKeys {
+    /// The key chunk files are encrypted with.
+    pub encryption_key: secretbox::Key,
+    /// The key chunk file names are derived from.
+    pub file_name_key: auth::Key,
 }
 
 /// Reads the default vault config file.
@@ -50,8 +142,6 @@ pub fn read_config_file() -> Result<Config, InternalError> {
 #[allow(dead_code)]
 pub fn write_config_file(config: Config) -> Result<::std::path::PathBuf, InternalError> {
     use rustc_serialize::json;
-    use std::fs::File;
-    use std::io::Write;
 
     let mut config_path = config_file_handler::current_bin_dir()?;
     config_path.push(get_file_name()?);
@@ -61,6 +151,67 @@ pub fn write_config_file(config: Config) -> Result<::std::path::PathBuf, Interna
     Ok(config_path)
 }
 
+/// Reads the chunk store keys file, or generates new keys and writes them to it if it doesn't exist
+/// yet.  The file is kept in the `current_bin_dir()`.  New keys are written to a temporary file
+/// first, so a crash can't leave an incomplete keys file behind.
+pub fn read_or_create_chunk_store_keys() -> Result<ChunkStoreKeys, InternalError> {
+    let mut name = config_file_handler::exe_file_stem()?;
+    name.push(".chunk_store.keys");
+    let dir = config_file_handler::current_bin_dir()?;
+    let path = dir.join(&name);
+    name.push(".tmp");
+    let temp_path = dir.join(name);
+
+    let mut file = match File::open(&path) {
+        Ok(file) => file,
+        Err(ref error) if error.kind() == io::ErrorKind::NotFound => {
+            let keys = ChunkStoreKeys {
+                encryption_key: secretbox::gen_key(),
+                file_name_key: auth::gen_key(),
+            };
+            let mut options = OpenOptions::new();
+            let _ = options.write(true).create(true).truncate(true);
+            restrict_to_owner(&mut options);
+            let mut file = options.open(&temp_path)?;
+            file.write_all(&keys.encryption_key.0)?;
+            file.write_all(&keys.file_name_key.0)?;
+            file.sync_all()?;
+            fs::rename(&temp_path, &path)?;
+            // Persist the rename.  Directories can't be opened like this on every platform, so
+            // errors are ignored.
+            let _ = File::open(&dir).and_then(|dir| dir.sync_all());
+            info!("Generated new chunk store keys in {}.", path.display());
+            return Ok(keys);
+        }
+        Err(error) => return Err(From::from(error)),
+    };
+
+    let mut contents = Vec::new();
+    let _ = file.read_to_end(&mut contents)?;
+    if contents.len() != secretbox::KEYBYTES + auth::KEYBYTES {
+        return Err(InternalError::InvalidChunkStoreKeys);
+    }
+    let (encryption_key, file_name_key) = contents.split_at(secretbox::KEYBYTES);
+    match (secretbox::Key::from_slice(encryption_key), auth::Key::from_slice(file_name_key)) {
+        (Some(encryption_key), Some(file_name_key)) => {
+            Ok(ChunkStoreKeys {
+                encryption_key: encryption_key,
+                file_name_key: file_name_key,
+            })
+        }
+        _ => Err(InternalError::InvalidChunkStoreKeys),
+    }
+}
+
+#[cfg(unix)]
+fn restrict_to_owner(options: &mut OpenOptions) {
+    use std::os::unix::fs::OpenOptionsExt;
+    let _ = options.mode(0o600);
+}
+
+#[cfg(not(unix))]
+fn restrict_to_owner(_options: &mut OpenOptions) {}
+
 fn get_file_name() -> Result<OsString, InternalError> {
     let mut name = config_file_handler::exe_file_stem()?;
     name.push(".vault.config");
@@ -57,5 +57,12 @@ quick_error! {
         UnknownRequestType(request: Request)
         UnknownResponseType(response: Response)
         InvalidMessage
+        InvalidChunkStoreKeys
+        InvalidArchive
+        ChunkStoreNotEmpty
+        ChunkStoreNotPersistent
+        ChunkStoreNotOnDisk
+        SnapshotFailed(message: String)
+        SnapshotTimeout
     }
 }
@@ -0,0 +1,235 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! Offline inspection of the chunk stores of a vault which isn't running.
+
+use chunk_store::{ChunkStorage, ChunkStore};
+use config_handler;
+use error::InternalError;
+use personas::data_manager::DataManager;
+use routing::{Data, DataIdentifier};
+use rust_sodium;
+use rustc_serialize::hex::ToHex;
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::path::PathBuf;
+use vault;
+
+/// The total size and identifiers of the chunks of one type.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ChunkCount {
+    /// The size the chunks take up on disk, in bytes.
+    pub bytes: u64,
+    /// The identifiers of the chunks.
+    pub data_ids: Vec<DataIdentifier>,
+}
+
+impl ChunkCount {
+    fn add(&mut self, data_id: DataIdentifier, bytes: u64) {
+        self.bytes += bytes;
+        self.data_ids.push(data_id);
+    }
+
+    fn write(&self, formatter: &mut Formatter, type_name: &str) -> fmt::Result {
+        writeln!(formatter,
+                 "  {}: {} chunks, {} bytes",
+                 type_name,
+                 self.data_ids.len(),
+                 self.bytes)?;
+        let mut names = self.data_ids.iter().map(chunk_name).collect::<Vec<_>>();
+        names.sort();
+        for name in names {
+            writeln!(formatter, "    {}", name)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the full hex name of the chunk, with the type tag or kind where the name alone doesn't
+/// identify it.
+fn chunk_name(data_id: &DataIdentifier) -> String {
+    match *data_id {
+        DataIdentifier::Immutable(ref name) => name.0[..].to_hex(),
+        DataIdentifier::Structured(ref name, type_tag) => {
+            format!("{} (type tag {})", name.0[..].to_hex(), type_tag)
+        }
+        DataIdentifier::PubAppendable(ref name) => format!("{} (public)", name.0[..].to_hex()),
+        DataIdentifier::PrivAppendable(ref name) => format!("{} (private)", name.0[..].to_hex()),
+    }
+}
+
+/// The contents of a chunk store, and the problems found in it.
+#[derive(Clone, Debug, Default)]
+pub struct ChunkStoreReport {
+    /// The chunk store directory.
+    pub root: PathBuf,
+    /// The `ImmutableData` chunks.
+    pub immutable_data: ChunkCount,
+    /// The `StructuredData` chunks.
+    pub structured_data: ChunkCount,
+    /// The `PubAppendableData` and `PrivAppendableData` chunks.
+    pub appendable_data: ChunkCount,
+    /// The total size of the store, including the chunks which couldn't be read.
+    pub used_space: u64,
+    /// The chunks which couldn't be read or deserialised, with the reason.
+    pub invalid_chunks: Vec<(DataIdentifier, String)>,
+    /// The files which don't belong to any chunk.
+    pub stray_files: Vec<PathBuf>,
+    /// Whether the invalid chunks and stray files were deleted.
+    pub deleted: bool,
+}
+
+impl Display for ChunkStoreReport {
+    fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
+        writeln!(formatter, "Chunk store {}", self.root.display())?;
+        self.immutable_data.write(formatter, "ImmutableData")?;
+        self.structured_data.write(formatter, "StructuredData")?;
+        self.appendable_data.write(formatter, "AppendableData")?;
+        writeln!(formatter, "  Total: {} bytes", self.used_space)?;
+        let action = if self.deleted { "deleted" } else { "found" };
+        writeln!(formatter,
+                 "  {} invalid chunks, {} stray files {}",
+                 self.invalid_chunks.len(),
+                 self.stray_files.len(),
+                 action)?;
+        for &(ref data_id, ref reason) in &self.invalid_chunks {
+            writeln!(formatter, "    {:?}: {}", data_id, reason)?;
+        }
+        for path in &self.stray_files {
+            writeln!(formatter, "    {}", path.display())?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks every chunk store configured in the vault config file.
+///
+/// The stores are opened read-only unless `delete_invalid` is set, in which case the chunks which
+/// can't be read and the files which don't belong to any chunk are deleted.  Like starting the
+/// vault, opening a store for writing first migrates it to the current format version and moves
+/// chunk files into the configured directory layout.  This fails if the vault is running.
+pub fn inspect_chunk_stores(delete_invalid: bool) -> Result<Vec<ChunkStoreReport>, InternalError> {
+    rust_sodium::init();
+    let config = config_handler::read_config_file()?;
+    let mut reports = Vec::new();
+    for (root, capacity) in vault::all_chunk_store_roots(&config) {
+        let chunk_store = DataManager::chunk_store_builder(&config, root.clone(), capacity)?
+            .persistent(true)
+            .read_only(!delete_invalid)
+            .create()?;
+        reports.push(inspect_chunk_store(root, chunk_store, delete_invalid)?);
+    }
+    Ok(reports)
+}
+
+fn inspect_chunk_store(root: PathBuf,
+                       mut chunk_store: ChunkStore<DataIdentifier, Data>,
+                       delete_invalid: bool)
+                       -> Result<ChunkStoreReport, InternalError> {
+    let mut report = ChunkStoreReport {
+        root: root,
+        used_space: chunk_store.used_space(),
+        stray_files: chunk_store.stray_files()?,
+        ..ChunkStoreReport::default()
+    };
+    for data_id in chunk_store.keys() {
+        if let Err(error) = chunk_store.get(&data_id) {
+            report.invalid_chunks.push((data_id, format!("{}", error)));
+            continue;
+        }
+        let bytes = chunk_store.stored_size(&data_id)?;
+        match data_id {
+            DataIdentifier::Immutable(_) => report.immutable_data.add(data_id, bytes),
+            DataIdentifier::Structured(..) => report.structured_data.add(data_id, bytes),
+            DataIdentifier::PubAppendable(..) |
+            DataIdentifier::PrivAppendable(..) => report.appendable_data.add(data_id, bytes),
+        }
+    }
+    if delete_invalid {
+        for &(ref data_id, _) in &report.invalid_chunks {
+            chunk_store.delete(data_id)?;
+        }
+        for path in &report.stray_files {
+            fs::remove_file(path)?;
+        }
+        report.deleted = true;
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use chunk_store::{ChunkStorage, ChunkStore, ChunkStoreBuilder};
+    use maidsafe_utilities::serialisation;
+    use routing::{Data, DataIdentifier, ImmutableData};
+    use rustc_serialize::hex::ToHex;
+    use std::fs::{File, OpenOptions};
+    use std::io::{Seek, SeekFrom, Write};
+    use std::path::Path;
+    use super::inspect_chunk_store;
+    use tempdir::TempDir;
+
+    fn open_chunk_store(root: &Path, read_only: bool) -> ChunkStore<DataIdentifier, Data> {
+        unwrap!(ChunkStoreBuilder::new(root.to_path_buf(), 1024 * 1024)
+            .persistent(true)
+            .dir_depth(0)
+            .read_only(read_only)
+            .create())
+    }
+
+    #[test]
+    fn invalid_chunks_and_stray_files() {
+        let tempdir = unwrap!(TempDir::new("test"));
+        let root = tempdir.path().join("store");
+        let valid_data = Data::Immutable(ImmutableData::new(vec![1; 100]));
+        let corrupted_data = Data::Immutable(ImmutableData::new(vec![2; 100]));
+        let stray_file_path = root.join("not_a_chunk");
+        {
+            let mut chunk_store = open_chunk_store(&root, false);
+            unwrap!(chunk_store.put(&valid_data.identifier(), &valid_data));
+            unwrap!(chunk_store.put(&corrupted_data.identifier(), &corrupted_data));
+        }
+        let file_name = unwrap!(serialisation::serialise(&corrupted_data.identifier())).to_hex();
+        let mut file = unwrap!(OpenOptions::new().write(true).open(root.join(file_name)));
+        let _ = unwrap!(file.seek(SeekFrom::End(-1)));
+        unwrap!(file.write_all(&[0xff]));
+        let _ = unwrap!(File::create(&stray_file_path));
+
+        // Inspecting a read-only store only reports the problems.
+        let chunk_store = open_chunk_store(&root, true);
+        let valid_size = unwrap!(chunk_store.stored_size(&valid_data.identifier()));
+        let report = unwrap!(inspect_chunk_store(root.clone(), chunk_store, false));
+        assert_eq!(report.immutable_data.data_ids, vec![valid_data.identifier()]);
+        assert_eq!(report.immutable_data.bytes, valid_size);
+        assert!(report.structured_data.data_ids.is_empty());
+        assert_eq!(report.invalid_chunks.len(), 1);
+        assert_eq!(report.invalid_chunks[0].0, corrupted_data.identifier());
+        assert_eq!(report.stray_files, vec![stray_file_path.clone()]);
+        assert!(!report.deleted);
+
+        // With `delete_invalid`, the corrupted chunk and the stray file are removed.
+        let chunk_store = open_chunk_store(&root, false);
+        let report = unwrap!(inspect_chunk_store(root.clone(), chunk_store, true));
+        assert_eq!(report.invalid_chunks.len(), 1);
+        assert_eq!(report.stray_files, vec![stray_file_path.clone()]);
+        assert!(report.deleted);
+        assert!(!stray_file_path.exists());
+        let chunk_store = open_chunk_store(&root, true);
+        assert_eq!(chunk_store.keys(), vec![valid_data.identifier()]);
+        assert!(unwrap!(chunk_store.stray_files()).is_empty());
+    }
+}
@@ -42,13 +42,15 @@
 //! close to it in the network address space.  Each such account is managed by a group of
 //! [`GROUP_SIZE`][4] Vaults.
 //!
-//! A Client's account contains details of how many chunks of data have been put to the network by
-//! that Client and how many new chunks can still be put.  If a Client's account indicates that no
-//! more chunks can be put to the network, the Client Managers for that Client disallow any further
-//! `Put` requests, responding with a `LowBalance` error.
+//! A Client's account contains details of how many chunks of data, and how many bytes, have been
+//! put to the network by that Client and how many can still be put.  If a Client's account
+//! indicates that a new chunk can't be put to the network, the Client Managers for that Client
+//! disallow any further `Put` requests, responding with a `LowBalance` error.
 //!
 //! Clients can retrieve their account balances by sending a specific request to their Managers,
-//! namely a `GetAccountInfo` request.
+//! namely a `GetAccountInfo` request.  Its response only has room for two values: the chunk counts,
+//! or the bytes if the Vault is configured with `report_account_bytes` and `send_account_bytes`.
+//! The bytes are only charged if the Vaults send them to each other with `send_account_bytes`.
 //!
 //! ### Churn
 //!
@@ -85,6 +87,12 @@
 //! popular chunks will end up being cached by many more Vaults than the close group, hence the
 //! close group will be protected from excessive numbers of Get requests.
 //!
+//! ### Scrubbing
+//!
+//! Each chunk is stored together with a checksum.  The Data Manager gradually checks all of its
+//! chunks against their checksums; a corrupted chunk is moved aside and a fresh copy is retrieved
+//! from the other Data Managers in the chunk's close group.
+//!
 //! ### Churn
 //!
 //! In a similar way to the Client Manager, the Data Manager will clear out records and send refresh
@@ -177,7 +185,11 @@
 //!    * there isn't an existing `StructuredData` chunk with the same name
 //!    * the new chunk isn't a valid successor of the existing one
 //!    * the chunkstore can't delete the existing chunk
-//! 1. if deleting the chunk succeeds, `DataManager` responds with success to the Client
+//! 1. if deleting the chunk succeeds, `DataManager` responds with success to the Client and sends
+//!    a refund to the `MaidManager` group of the Client which put the chunk, with the serialised
+//!    size it was charged for the `Put`, as recorded by `DataManager` when storing it
+//! 1. once a quorum of the `DataManager` group has sent the refund, `MaidManager` credits the
+//!    account with it
 //!
 //!
 //! [0]: http://docs.maidsafe.net/routing/master/routing/index.html "Documentation for Routing"
@@ -215,10 +227,10 @@
 #![cfg_attr(feature="clippy", allow(use_debug, similar_names))] // "mpid" and "maid" are similar.
 
 extern crate accumulator;
+extern crate flate2;
 extern crate fs2;
 #[macro_use]
 extern crate log;
-extern crate lru_time_cache;
 extern crate itertools;
 extern crate kademlia_routing_table;
 #[macro_use]
@@ -240,18 +252,26 @@ extern crate tempdir;
 #[macro_use]
 extern crate unwrap;
 
+mod archive;
 mod cache;
 mod chunk_store;
 mod config_handler;
 mod error;
+mod inspect;
 /// For integration tests only
 #[cfg(feature = "use-mock-crust")]
 pub mod test_utils;
 mod personas;
+mod snapshot;
 mod utils;
 mod vault;
 /// For integration tests only
 #[cfg(feature = "use-mock-crust")]
 pub mod mock_crust_detail;
-pub use config_handler::Config;
+pub use archive::{ArchiveReport, export_chunk_stores, import_chunk_stores};
+pub use cache::CacheStats;
+pub use chunk_store::{Compression, SnapshotStats};
+pub use config_handler::{ChunkStoreRoot, Config, StorageBackend};
+pub use inspect::{ChunkCount, ChunkStoreReport, inspect_chunk_stores};
+pub use snapshot::{restore_chunk_stores, snapshot_chunk_stores};
 pub use vault::Vault;
@@ -16,7 +16,7 @@
 // relating to use of the SAFE Network Software.
 
 
-use config_handler::Config;
+use config_handler::{Config, StorageBackend};
 use kademlia_routing_table::RoutingTable;
 
 use personas::data_manager::IdAndVersion;
@@ -53,21 +53,12 @@ impl TestNode {
             .take(8)
             .collect::<Vec<u8>>()
             .to_hex());
-        let vault_config = match config {
-            Some(config) => {
-                Config {
-                    wallet_address: config.wallet_address,
-                    max_capacity: config.max_capacity,
-                    chunk_store_root: Some(format!("{}", chunk_store_root.display())),
-                }
-            }
-            None => {
-                Config {
-                    wallet_address: None,
-                    max_capacity: None,
-                    chunk_store_root: Some(format!("{}", chunk_store_root.display())),
-                }
-            }
+        let config = config.unwrap_or_default();
+        let vault_config = Config {
+            chunk_store_root: Some(format!("{}", chunk_store_root.display())),
+            chunk_store_backend: Some(config.chunk_store_backend
+                .unwrap_or(StorageBackend::Memory)),
+            ..config
         };
         let vault = mock_crust::make_current(&handle, || {
             unwrap!(Vault::new_with_config(first_node, use_cache, vault_config))
@@ -109,6 +100,11 @@ impl TestNode {
         self.vault.get_maid_manager_put_count(client_name)
     }
 
+    /// return the number of bytes stored and available in the account of the given client
+    pub fn get_maid_manager_bytes_stored(&self, client_name: &XorName) -> Option<(u64, u64)> {
+        self.vault.get_maid_manager_bytes_stored(client_name)
+    }
+
     /// Resend all unacknowledged messages.
     pub fn resend_unacknowledged(&self) -> bool {
         self.vault.resend_unacknowledged()
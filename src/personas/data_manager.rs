@@ -17,11 +17,16 @@
 
 
 use accumulator::Accumulator;
-use chunk_store::ChunkStore;
+use chunk_store::{ChunkStorage, ChunkStore, ChunkStoreBuilder, CompositeChunkStore, Compression,
+                  MemoryChunkStore, NameIndex, SnapshotStats, TieredChunkStore};
+use chunk_store::Error as ChunkStoreError;
+use config_handler::{self, Config, StorageBackend};
 use error::InternalError;
 use itertools::Itertools;
 use kademlia_routing_table::RoutingTable;
 use maidsafe_utilities::{self, serialisation};
+use maidsafe_utilities::serialisation::SerialisationError;
+use personas::maid_manager::Refund;
 use routing::{AppendWrapper, Authority, Data, DataIdentifier, GROUP_SIZE, MessageId,
               StructuredData, XorName};
 use routing::client_errors::{GetError, MutationError};
@@ -29,10 +34,10 @@ use std::collections::{HashMap, HashSet, VecDeque};
 use std::convert::From;
 use std::fmt::{self, Debug, Formatter};
 use std::ops::Add;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use std::time::{Duration, Instant};
-use vault::RoutingNode;
+use vault::{self, RoutingNode};
 
 const MAX_FULL_PERCENT: u64 = 50;
 /// The quorum for accumulating refresh messages.
@@ -45,6 +50,8 @@ const PENDING_WRITE_TIMEOUT_SECS: u64 = 60;
 const GET_FROM_DATA_HOLDER_TIMEOUT_SECS: u64 = 60;
 /// The interval for print status log.
 const STATUS_LOG_INTERVAL: u64 = 120;
+/// The number of chunks checked for corruption on each tick.
+const SCRUB_CHUNKS_PER_TICK: usize = 10;
 
 /// Specification of a particular version of a data chunk. For immutable data, the `u64` is always
 /// 0; for structured and appendable data, it specifies the version.
@@ -60,6 +67,8 @@ struct PendingWrite {
     dst: Authority,
     message_id: MessageId,
     mutate_type: PendingMutationType,
+    /// The chunk store space reserved for this write, to be released when it is stored or dropped.
+    reserved: u64,
 }
 
 enum PendingMutationType {
@@ -108,8 +117,16 @@ impl Cache {
                 let _ = self.ongoing_gets.insert(src, (timestamp, expected_idv));
             }
         }
+        // Lost chunks are requested as version 0, so drop the records of all versions up to the
+        // received one, not only those of the exact version.
         for (_, data_idvs) in &mut self.data_holders {
-            let _ = data_idvs.remove(&(*data_id, version));
+            let outdated = data_idvs.iter()
+                .filter(|&&(ref id, ref idv_version)| id == data_id && *idv_version <= version)
+                .cloned()
+                .collect_vec();
+            for data_idv in outdated {
+                let _ = data_idvs.remove(&data_idv);
+            }
         }
     }
 
@@ -312,7 +329,8 @@ impl Cache {
                             mutate_type: PendingMutationType,
                             src: Authority,
                             dst: Authority,
-                            msg_id: MessageId)
+                            msg_id: MessageId,
+                            reserved: u64)
                             -> Option<RefreshData> {
         let hash = maidsafe_utilities::big_endian_sip_hash(&data);
         let (data_id, version) = id_and_version_of(&data);
@@ -324,6 +342,7 @@ impl Cache {
             dst: dst,
             message_id: msg_id,
             mutate_type: mutate_type,
+            reserved: reserved,
         };
         let mut result = None;
         self.pending_writes
@@ -344,7 +363,7 @@ impl Cache {
 
 
 pub struct DataManager {
-    chunk_store: ChunkStore<DataIdentifier, Data>,
+    chunk_store: Box<ChunkStorage<DataIdentifier, Data>>,
     routing_node: Rc<RoutingNode>,
     /// Accumulates refresh messages and the peers we received them from.
     refresh_accumulator: Accumulator<IdAndVersion, XorName>,
@@ -354,9 +373,188 @@ pub struct DataManager {
     appendable_data_count: u64,
     client_get_requests: u64,
     logging_time: Instant,
+    /// Chunks still to be checked for corruption in the current pass of the scrubber.
+    scrub_queue: VecDeque<DataIdentifier>,
+    /// The identifiers, versions and sizes of all chunks in the chunk store.
+    chunk_index: ChunkIndex,
+}
+
+/// What the `ChunkIndex` records about a chunk.  The type of the chunk is given by its identifier.
+#[derive(Clone, Copy)]
+struct ChunkInfo {
+    version: u64,
+    /// The serialised size of the chunk.
+    size: u64,
+    /// For `StructuredData` put while we were in its group, the client which paid for it and the
+    /// serialised size it was charged for, which is refunded when the data is deleted.
+    charge: Option<(XorName, u64)>,
+}
+
+/// An index of the chunks in the chunk store, so that churn handling doesn't need to read and
+/// deserialise them.  All changes to the chunk store go through it to keep the two consistent.
+#[derive(Default)]
+struct ChunkIndex {
+    chunks: HashMap<DataIdentifier, ChunkInfo>,
+    /// The identifiers of all chunks, sorted by name, so that churn handling only needs to look at
+    /// the chunks close to the joining or leaving node.
+    names: NameIndex<DataIdentifier>,
+    /// The total serialised size of the chunks.
+    total_size: u64,
+}
+
+impl ChunkIndex {
+    /// Indexes the chunks already in the chunk store, reading each of them once.
+    fn load(chunk_store: &ChunkStorage<DataIdentifier, Data>) -> ChunkIndex {
+        let mut index = ChunkIndex::default();
+        for data_id in chunk_store.iter_keys() {
+            let info = match chunk_store.get(&data_id).map(|data| chunk_info_of(&data)) {
+                Ok(Ok(info)) => info,
+                Ok(Err(error)) => {
+                    warn!("Failed to serialise {:?}: {:?}", data_id, error);
+                    continue;
+                }
+                Err(error) => {
+                    warn!("Failed to read {:?} from chunk store: {:?}", data_id, error);
+                    continue;
+                }
+            };
+            index.insert(data_id, info);
+        }
+        index
+    }
+
+    /// Stores the data in the chunk store and records its version and size.  If the data is put by
+    /// the client `payer`, its size is recorded as the charge to refund, otherwise the charge for
+    /// the previous version is kept.
+    fn put(&mut self,
+           chunk_store: &mut ChunkStorage<DataIdentifier, Data>,
+           data: &Data,
+           payer: Option<XorName>)
+           -> Result<(), ChunkStoreError> {
+        let mut info = chunk_info_of(data)?;
+        let data_id = data.identifier();
+        info.charge = match payer {
+            Some(payer) => Some((payer, info.size)),
+            None => self.charge(&data_id),
+        };
+        chunk_store.put(&data_id, data)?;
+        self.insert(data_id, info);
+        Ok(())
+    }
+
+    /// Deletes the data from the chunk store and forgets its version.
+    fn delete(&mut self,
+              chunk_store: &mut ChunkStorage<DataIdentifier, Data>,
+              data_id: &DataIdentifier) {
+        let _ = chunk_store.delete(data_id);
+        self.remove(data_id);
+    }
+
+    /// Moves a corrupted chunk to quarantine and forgets its version.
+    fn quarantine(&mut self,
+                  chunk_store: &mut ChunkStorage<DataIdentifier, Data>,
+                  data_id: &DataIdentifier)
+                  -> Result<(), ChunkStoreError> {
+        chunk_store.quarantine(data_id)?;
+        self.remove(data_id);
+        Ok(())
+    }
+
+    fn insert(&mut self, data_id: DataIdentifier, info: ChunkInfo) {
+        self.total_size += info.size;
+        if let Some(old_info) = self.chunks.insert(data_id, info) {
+            self.total_size -= old_info.size;
+        }
+        self.names.insert(*data_id.name(), data_id);
+    }
+
+    /// Forgets a chunk, e.g. one which has been lost from the chunk store.
+    fn remove(&mut self, data_id: &DataIdentifier) {
+        if let Some(info) = self.chunks.remove(data_id) {
+            self.total_size -= info.size;
+        }
+        self.names.remove(data_id.name(), data_id);
+    }
+
+    fn version(&self, data_id: &DataIdentifier) -> Option<u64> {
+        self.chunks.get(data_id).map(|info| info.version)
+    }
+
+    /// Returns the client which paid for the data and the size it was charged for, if known.
+    fn charge(&self, data_id: &DataIdentifier) -> Option<(XorName, u64)> {
+        self.chunks.get(data_id).and_then(|info| info.charge)
+    }
+
+    /// Returns the `IdAndVersion` for the given data identifier, or `None` if not stored.
+    fn id_and_version(&self, data_id: DataIdentifier) -> Option<IdAndVersion> {
+        match self.chunks.get(&data_id) {
+            Some(info) => Some((data_id, info.version)),
+            None => {
+                error!("Failed to get {:?} from chunk store.", data_id);
+                None
+            }
+        }
+    }
+
+    /// Returns the identifiers and versions of all chunks.
+    fn all(&self) -> Vec<IdAndVersion> {
+        self.chunks.iter().map(|(data_id, info)| (*data_id, info.version)).collect()
+    }
+
+    /// Returns the chunks whose close group may include the given joining or leaving node.
+    ///
+    /// The chunks are grouped into the buckets relative to `node_name` (see `NameIndex`).  All
+    /// nodes which share more leading bits with a chunk's name than `node_name` does are closer to
+    /// it.  So if the close group of one chunk in bucket `i` consists only of such nodes, i.e. of
+    /// nodes in `node_name`'s own bucket `i`, none of the chunks in that bucket are affected.
+    fn affected_by(&self,
+                   our_name: &XorName,
+                   node_name: &XorName,
+                   routing_table: &RoutingTable<XorName>)
+                   -> Vec<IdAndVersion> {
+        let mut affected = Vec::new();
+        for (bucket_index, data_ids) in self.names.buckets(node_name) {
+            let unaffected = data_ids.first()
+                .and_then(|data_id| {
+                    routing_table.other_close_nodes(data_id.name(), GROUP_SIZE)
+                        .map(|close_group| (*data_id.name(), close_group))
+                })
+                .map_or(false, |(name, close_group)| {
+                    close_group.len() + 1 >= GROUP_SIZE &&
+                    close_group.iter()
+                        .chain(Some(our_name))
+                        .all(|member| common_prefix_len(member, &name) > bucket_index)
+                });
+            if !unaffected {
+                affected.extend(data_ids.into_iter()
+                    .filter_map(|data_id| self.id_and_version(*data_id)));
+            }
+        }
+        affected
+    }
+}
+
+/// Returns the number of leading bits in which the two names agree.
+fn common_prefix_len(lhs: &XorName, rhs: &XorName) -> usize {
+    for (index, (lhs_byte, rhs_byte)) in lhs.0.iter().zip(rhs.0.iter()).enumerate() {
+        if lhs_byte != rhs_byte {
+            return index * 8 + (lhs_byte ^ rhs_byte).leading_zeros() as usize;
+        }
+    }
+    lhs.0.len() * 8
+}
+
+/// Returns the version and serialised size of the given data.
+fn chunk_info_of(data: &Data) -> Result<ChunkInfo, SerialisationError> {
+    Ok(ChunkInfo {
+        version: id_and_version_of(data).1,
+        size: serialisation::serialise(data)?.len() as u64,
+        charge: None,
+    })
 }
 
-fn id_and_version_of(data: &Data) -> IdAndVersion {
+/// Returns the identifier and version of the given data.  Immutable data always has version 0.
+pub fn id_and_version_of(data: &Data) -> IdAndVersion {
     (data.identifier(),
      match *data {
         Data::Structured(ref sd) => sd.get_version(),
@@ -370,22 +568,26 @@ impl Debug for DataManager {
     fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
         write!(formatter,
                "Stats : Client Get requests received {} ; Data stored - ID {} - SD {} - AD {} - \
-               total {} bytes",
+               total {} bytes ({} bytes serialised) ; Compression ratio {:.2}",
                self.client_get_requests,
                self.immutable_data_count,
                self.structured_data_count,
                self.appendable_data_count,
-               self.chunk_store.used_space())
+               self.chunk_store.used_space(),
+               self.chunk_index.total_size,
+               self.chunk_store.compression_stats().ratio())
     }
 }
 
 impl DataManager {
+    /// Creates a `DataManager` storing its chunks in the given roots, each with its own capacity.
     pub fn new(routing_node: Rc<RoutingNode>,
-               chunk_store_root: PathBuf,
-               capacity: u64)
+               config: &Config,
+               chunk_store_roots: Vec<(PathBuf, u64)>)
                -> Result<DataManager, InternalError> {
-        Ok(DataManager {
-            chunk_store: ChunkStore::new(chunk_store_root, capacity)?,
+        let chunk_store = Self::create_chunk_store(config, chunk_store_roots)?;
+        let mut data_manager = DataManager {
+            chunk_store: chunk_store,
             refresh_accumulator:
                 Accumulator::with_duration(ACCUMULATOR_QUORUM,
                                            Duration::from_secs(ACCUMULATOR_TIMEOUT_SECS)),
@@ -396,7 +598,101 @@ impl DataManager {
             appendable_data_count: 0,
             client_get_requests: 0,
             logging_time: Instant::now(),
-        })
+            scrub_queue: VecDeque::new(),
+            chunk_index: ChunkIndex::default(),
+        };
+        data_manager.chunk_index = ChunkIndex::load(&*data_manager.chunk_store);
+        for (data_id, _) in data_manager.chunk_index.all() {
+            data_manager.count_added_data(&data_id);
+        }
+        if data_manager.chunk_store.used_space() > 0 {
+            info!("Recovered chunk store. {:?}", data_manager);
+        }
+        Ok(data_manager)
+    }
+
+    /// Creates the chunk store for the given roots, each with its own capacity, as configured in
+    /// `config`.
+    pub fn create_chunk_store(config: &Config,
+                              chunk_store_roots: Vec<(PathBuf, u64)>)
+                              -> Result<Box<ChunkStorage<DataIdentifier, Data>>, InternalError> {
+        let chunk_store: Box<ChunkStorage<DataIdentifier, Data>> =
+            match config.chunk_store_backend.unwrap_or(StorageBackend::Disk) {
+                StorageBackend::Disk if chunk_store_roots.len() == 1 => {
+                    let (root, capacity) = chunk_store_roots[0].clone();
+                    Box::new(Self::create_disk_store(config, root, capacity)?)
+                }
+                StorageBackend::Disk => {
+                    let mut stores: Vec<Box<ChunkStorage<DataIdentifier, Data>>> = Vec::new();
+                    let mut last_error = None;
+                    for (root, capacity) in chunk_store_roots {
+                        // A single failed disk shouldn't stop the vault.
+                        match Self::create_disk_store(config, root.clone(), capacity) {
+                            Ok(store) => stores.push(Box::new(store)),
+                            Err(error) => {
+                                error!("Failed to create chunk store in {}: {:?}",
+                                       root.display(),
+                                       error);
+                                last_error = Some(error);
+                            }
+                        }
+                    }
+                    if let (true, Some(error)) = (stores.is_empty(), last_error) {
+                        return Err(error);
+                    }
+                    Box::new(CompositeChunkStore::new(stores)?)
+                }
+                StorageBackend::Memory => {
+                    let capacity = chunk_store_roots.iter()
+                        .fold(0, |total, &(_, capacity)| total + capacity);
+                    Box::new(MemoryChunkStore::new(capacity))
+                }
+            };
+        match (config.chunk_store_backend, vault::hot_tier_root(config)) {
+            (Some(StorageBackend::Memory), _) |
+            (_, None) => Ok(chunk_store),
+            (_, Some((root, capacity))) => {
+                let hot_store = Self::create_disk_store(config, root, capacity)?;
+                Ok(Box::new(TieredChunkStore::new(Box::new(hot_store), chunk_store)?))
+            }
+        }
+    }
+
+    fn create_disk_store(config: &Config,
+                         root: PathBuf,
+                         capacity: u64)
+                         -> Result<ChunkStore<DataIdentifier, Data>, InternalError> {
+        Ok(Self::chunk_store_builder(config, root, capacity)?.create()?)
+    }
+
+    /// Returns a builder for a disk-based chunk store in `root`, set up as given in `config`.
+    pub fn chunk_store_builder(config: &Config,
+                               root: PathBuf,
+                               capacity: u64)
+                               -> Result<ChunkStoreBuilder, InternalError> {
+        let mut builder = ChunkStoreBuilder::new(root, capacity)
+            .persistent(config.persistent_chunk_store.unwrap_or(false))
+            .compression(config.chunk_store_compression.unwrap_or(Compression::None));
+        if config.encrypt_chunk_store.unwrap_or(false) ||
+           config.hash_chunk_file_names.unwrap_or(false) {
+            let keys = config_handler::read_or_create_chunk_store_keys()?;
+            if config.encrypt_chunk_store.unwrap_or(false) {
+                builder = builder.encryption_key(keys.encryption_key);
+            }
+            if config.hash_chunk_file_names.unwrap_or(false) {
+                builder = builder.file_name_key(keys.file_name_key);
+            }
+        }
+        if let Some(dir_depth) = config.chunk_store_dir_depth {
+            builder = builder.dir_depth(dir_depth);
+        }
+        if let Some(pack_threshold) = config.chunk_store_pack_threshold {
+            builder = builder.pack_threshold(pack_threshold);
+        }
+        if let Some(free_space_margin) = config.chunk_store_free_space_margin {
+            builder = builder.free_space_margin(free_space_margin);
+        }
+        Ok(builder)
     }
 
     pub fn handle_get(&mut self,
@@ -412,10 +708,19 @@ impl DataManager {
                 info!("{:?}", self);
             }
         }
-        if let Ok(data) = self.chunk_store.get(&data_id) {
-            trace!("As {:?} sending data {:?} to {:?}", dst, data, src);
-            let _ = self.routing_node.send_get_success(dst, src, data, message_id);
-            return Ok(());
+        match self.chunk_store.get(&data_id) {
+            Ok(data) => {
+                trace!("As {:?} sending data {:?} to {:?}", dst, data, src);
+                let _ = self.routing_node.send_get_success(dst, src, data, message_id);
+                return Ok(());
+            }
+            Err(ChunkStoreError::Corrupted) => {
+                // The client still gets its failure response if handling the corruption fails.
+                if let Err(error) = self.handle_corrupted_chunk(data_id) {
+                    warn!("Failed to handle corrupted chunk {:?}: {:?}", data_id, error);
+                }
+            }
+            Err(_) => (),
         }
         trace!("DM sending get_failure of {:?}", data_id);
         let error = GetError::NoSuchData;
@@ -708,7 +1013,7 @@ impl DataManager {
 
         self.clean_chunk_store();
         // chunk_store::put() deletes the old data automatically.
-        self.chunk_store.put(&data_id, &data)?;
+        self.put_chunk(&data, None)?;
         if got_new_data {
             self.count_added_data(&data_id);
             if self.logging_time.elapsed().as_secs() > STATUS_LOG_INTERVAL {
@@ -742,31 +1047,16 @@ impl DataManager {
             if let Some(holders) = self.refresh_accumulator.add(data_idv, src).cloned() {
                 self.refresh_accumulator.delete(&data_idv);
                 let (ref data_id, ref version) = data_idv;
+                // If we don't have the data, we need to retrieve it.
+                let stored_version = self.chunk_index.version(data_id);
                 let data_needed = match *data_id {
-                    DataIdentifier::Immutable(..) => !self.chunk_store.has(data_id),
+                    DataIdentifier::Immutable(..) => stored_version.is_none(),
                     DataIdentifier::Structured(..) => {
-                        match self.chunk_store.get(data_id) {
-                            // We don't have the data, so we need to retrieve it.
-                            Err(_) => true,
-                            Ok(Data::Structured(sd)) => sd.get_version() < *version,
-                            Ok(_) => unreachable!(),
-                        }
-                    }
-                    DataIdentifier::PubAppendable(..) => {
-                        match self.chunk_store.get(data_id) {
-                            // We don't have the data, so we need to retrieve it.
-                            Err(_) => true,
-                            Ok(Data::PubAppendable(ad)) => ad.get_version() <= *version,
-                            Ok(_) => unreachable!(),
-                        }
+                        stored_version.map_or(true, |stored_version| stored_version < *version)
                     }
+                    DataIdentifier::PubAppendable(..) |
                     DataIdentifier::PrivAppendable(..) => {
-                        match self.chunk_store.get(data_id) {
-                            // We don't have the data, so we need to retrieve it.
-                            Err(_) => true,
-                            Ok(Data::PrivAppendable(ad)) => ad.get_version() <= *version,
-                            Ok(_) => unreachable!(),
-                        }
+                        stored_version.map_or(true, |stored_version| stored_version <= *version)
                     }
                 };
                 if !data_needed {
@@ -782,16 +1072,29 @@ impl DataManager {
     pub fn handle_group_refresh(&mut self, serialised_refresh: &[u8]) -> Result<(), InternalError> {
         let RefreshData((data_id, version), refresh_hash) =
             serialisation::deserialise(serialised_refresh)?;
-        for PendingWrite { data, mutate_type, src, dst, message_id, hash, .. } in self.cache
-            .take_pending_writes(&data_id) {
+        for PendingWrite { data, mutate_type, src, dst, message_id, hash, reserved, .. } in
+            self.cache.take_pending_writes(&data_id) {
+            self.chunk_store.release(reserved);
             if hash == refresh_hash {
                 let already_existed = self.chunk_store.has(&data_id);
-                if let Err(error) = self.chunk_store.put(&data_id, &data) {
+                // Only deleting structured data is refunded, so only its payer is recorded.
+                let payer = match (&mutate_type, data_id) {
+                    (&PendingMutationType::Put, DataIdentifier::Structured(..)) => {
+                        Some(*src.name())
+                    }
+                    _ => None,
+                };
+                if let Err(error) = self.put_chunk(&data, payer) {
                     trace!("DM failed to store {:?} in chunkstore: {:?}",
                            data_id,
                            error);
-                    let error = MutationError::NetworkOther(format!("Failed to store chunk: {:?}",
-                                                                    error));
+                    let error = match error {
+                        ChunkStoreError::NotEnoughSpace => MutationError::NetworkFull,
+                        error => {
+                            MutationError::NetworkOther(format!("Failed to store chunk: {:?}",
+                                                                error))
+                        }
+                    };
                     self.send_failure(mutate_type, src, dst, data_id, message_id, error)?;
                 } else {
                     trace!("DM updated for: {:?}", data_id);
@@ -817,6 +1120,7 @@ impl DataManager {
                             self.routing_node.send_put_success(dst, src, data_id, message_id)
                         }
                         PendingMutationType::Delete => {
+                            let _ = self.send_refund(data_id, version);
                             trace!("DM sending DeleteSuccess for data {:?}", data_id);
                             self.routing_node.send_delete_success(dst, src, data_id, message_id)
                         }
@@ -865,16 +1169,35 @@ impl DataManager {
                              dst: Authority,
                              message_id: MessageId)
                              -> Result<(), InternalError> {
-        for PendingWrite { mutate_type, src, dst, data, message_id, .. } in self.cache
+        for PendingWrite { mutate_type, src, dst, data, message_id, reserved, .. } in self.cache
             .remove_expired_writes() {
+            self.chunk_store.release(reserved);
             let data_id = data.identifier();
             let error = MutationError::NetworkOther("Request expired.".to_owned());
             trace!("{:?} did not accumulate. Sending failure", data_id);
             self.send_failure(mutate_type, src, dst, data_id, message_id, error)?;
         }
+        // Reserve the space for the new version of the chunk up front, so that concurrent writes
+        // can't all be accepted and then fail to be stored.  Deletions don't need any.
+        let reserved = match mutate_type {
+            PendingMutationType::Delete => 0,
+            _ => self.chunk_store.space_needed(&data.identifier(), &data)?,
+        };
+        if let Err(error) = self.chunk_store.reserve(reserved) {
+            let data_id = data.identifier();
+            trace!("DM failed to reserve space for {:?}: {:?}", data_id, error);
+            let mutation_error = match error {
+                ChunkStoreError::NotEnoughSpace => MutationError::NetworkFull,
+                ref error => {
+                    MutationError::NetworkOther(format!("Failed to reserve space: {:?}", error))
+                }
+            };
+            self.send_failure(mutate_type, src, dst, data_id, message_id, mutation_error)?;
+            return Err(From::from(error));
+        }
         let data_name = *data.name();
         if let Some(refresh_data) = self.cache
-            .insert_pending_write(data, mutate_type, src, dst, message_id) {
+            .insert_pending_write(data, mutate_type, src, dst, message_id, reserved) {
             let _ = self.send_group_refresh(data_name, refresh_data, message_id);
         }
         Ok(())
@@ -912,10 +1235,8 @@ impl DataManager {
         if self.cache.prune_ongoing_gets(routing_table) {
             let _ = self.send_gets_for_needed_data();
         }
-        let data_idvs = self.cache.chain_records_in_cache(self.chunk_store
-            .keys()
-            .into_iter()
-            .filter_map(|data_id| self.to_id_and_version(data_id)));
+        let data_idvs = self.cache
+            .chain_records_in_cache(self.chunks_affected_by(node_name, routing_table));
         let mut has_pruned_data = false;
         // Only retain data for which we're still in the close group.
         let mut data_list = Vec::new();
@@ -923,15 +1244,7 @@ impl DataManager {
             match routing_table.other_close_nodes(data_id.name(), GROUP_SIZE) {
                 None => {
                     trace!("No longer a DM for {:?}", data_id);
-                    if self.chunk_store.has(&data_id) && !self.cache.is_in_unneeded(&data_id) {
-                        self.count_removed_data(&data_id);
-                        has_pruned_data = true;
-                        if let DataIdentifier::Immutable(..) = data_id {
-                            self.cache.add_as_unneeded(data_id);
-                        } else {
-                            let _ = self.chunk_store.delete(&data_id);
-                        }
-                    }
+                    has_pruned_data |= self.remove_unneeded_data(data_id);
                 }
                 Some(close_group) => {
                     if close_group.contains(node_name) {
@@ -949,7 +1262,48 @@ impl DataManager {
         }
     }
 
-    /// Get all names and hashes of all data. // [TODO]: Can be optimised - 2016-04-23 09:11pm
+    /// Announces the chunks held in our chunk store to the other members of their close groups.
+    ///
+    /// This is needed after a restart with a persistent chunk store: the recovered chunks are sent
+    /// through the normal refresh path, and those we are no longer responsible for are dropped.
+    pub fn handle_connected(&mut self) {
+        let our_name = match self.routing_node.name() {
+            Ok(name) => name,
+            Err(_) => return,
+        };
+        let data_idvs = self.chunk_store
+            .iter_keys()
+            .filter_map(|data_id| self.to_id_and_version(data_id))
+            .collect_vec();
+        let mut data_lists: HashMap<XorName, Vec<IdAndVersion>> = HashMap::new();
+        for (data_id, version) in data_idvs {
+            match self.routing_node.close_group(*data_id.name()) {
+                Ok(Some(close_group)) => {
+                    for node_name in close_group.into_iter().filter(|name| *name != our_name) {
+                        data_lists.entry(node_name)
+                            .or_insert_with(Vec::new)
+                            .push((data_id, version));
+                    }
+                }
+                Ok(None) => {
+                    trace!("Not a DM for recovered chunk {:?}", data_id);
+                    let _ = self.remove_unneeded_data(data_id);
+                }
+                // Keep the chunk if we can't tell whether we're responsible for it: later churn
+                // events remove it if we aren't.
+                Err(error) => {
+                    warn!("Failed to get the close group for recovered chunk {:?}: {:?}",
+                          data_id,
+                          error);
+                }
+            }
+        }
+        for (node_name, data_list) in data_lists {
+            let _ = self.send_refresh(Authority::ManagedNode(node_name), data_list);
+        }
+    }
+
+    /// Get all names and hashes of all data.
     /// Send to all members of group of data.
     pub fn handle_node_lost(&mut self,
                             node_name: &XorName,
@@ -967,11 +1321,8 @@ impl DataManager {
             let _ = self.send_gets_for_needed_data();
         }
 
-        let data_idvs = self.cache.chain_records_in_cache(self.chunk_store
-            .keys()
-            .into_iter()
-            .filter_map(|data_id| self.to_id_and_version(data_id))
-            .collect_vec());
+        let data_idvs = self.cache
+            .chain_records_in_cache(self.chunks_affected_by(node_name, routing_table));
         let mut data_lists: HashMap<XorName, Vec<IdAndVersion>> = HashMap::new();
         for data_idv in data_idvs {
             match routing_table.other_close_nodes(data_idv.0.name(), GROUP_SIZE) {
@@ -997,10 +1348,82 @@ impl DataManager {
         }
     }
 
+    /// Returns the identifiers and versions of the stored chunks whose close group may include the
+    /// given joining or leaving node, taken from the chunk index without reading the chunks.
+    fn chunks_affected_by(&self,
+                          node_name: &XorName,
+                          routing_table: &RoutingTable<XorName>)
+                          -> Vec<IdAndVersion> {
+        match self.routing_node.name() {
+            Ok(our_name) => self.chunk_index.affected_by(&our_name, node_name, routing_table),
+            Err(_) => self.chunk_index.all(),
+        }
+    }
+
+    /// Writes a snapshot of each chunk store to a subdirectory of the existing directory `dest`,
+    /// in the order of `vault::all_chunk_store_roots`.
+    pub fn snapshot_chunk_stores(&self, dest: &Path) -> Result<Vec<SnapshotStats>, InternalError> {
+        let mut stats = Vec::new();
+        self.chunk_store.snapshot_stores(dest, &mut stats)?;
+        Ok(stats)
+    }
+
     pub fn check_timeouts(&mut self) {
         let _ = self.send_gets_for_needed_data();
     }
 
+    /// Fetches the chunks lost to storage failures again, moves chunks between the storage tiers
+    /// and checks the next few chunks for corruption.  Once all chunks have been checked, the next
+    /// pass starts over.
+    pub fn scrub(&mut self) -> Result<(), InternalError> {
+        for data_id in self.chunk_store.take_lost_keys() {
+            self.handle_lost_chunk(data_id)?;
+        }
+        self.chunk_store.refresh_free_space();
+        if let Err(error) = self.chunk_store.rebalance() {
+            warn!("Failed to move chunks between storage tiers: {:?}", error);
+        }
+        if self.scrub_queue.is_empty() {
+            self.scrub_queue = self.chunk_store.iter_keys().collect();
+        }
+        for _ in 0..SCRUB_CHUNKS_PER_TICK {
+            let data_id = match self.scrub_queue.pop_front() {
+                Some(data_id) => data_id,
+                None => break,
+            };
+            if let Err(ChunkStoreError::Corrupted) = self.chunk_store.verify(&data_id) {
+                self.handle_corrupted_chunk(data_id)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Quarantines a corrupted chunk and, if we are still responsible for it, requests a fresh copy
+    /// from the other members of its close group.
+    fn handle_corrupted_chunk(&mut self, data_id: DataIdentifier) -> Result<(), InternalError> {
+        warn!("Chunk {:?} is corrupted. Moving it to quarantine.", data_id);
+        self.chunk_index.quarantine(&mut *self.chunk_store, &data_id)?;
+        self.handle_lost_chunk(data_id)
+    }
+
+    /// Forgets a chunk which is no longer in the chunk store and, if we are still responsible for
+    /// it, requests a fresh copy from the other members of its close group.
+    fn handle_lost_chunk(&mut self, data_id: DataIdentifier) -> Result<(), InternalError> {
+        self.chunk_index.remove(&data_id);
+        if self.cache.is_in_unneeded(&data_id) {
+            return Ok(());
+        }
+        self.count_removed_data(&data_id);
+        let our_name = self.routing_node.name()?;
+        if let Ok(Some(close_group)) = self.routing_node.close_group(*data_id.name()) {
+            // We can't trust the version of lost or corrupted mutable data, so we ask for version
+            // 0, and accept any version we receive.
+            let holders = close_group.into_iter().filter(|name| *name != our_name).collect();
+            self.cache.add_records((data_id, 0), holders);
+        }
+        self.send_gets_for_needed_data()
+    }
+
     #[cfg(feature = "use-mock-crust")]
     pub fn get_stored_names(&self) -> Vec<IdAndVersion> {
         let (front, back) = self.cache.unneeded_chunks.as_slices();
@@ -1014,22 +1437,18 @@ impl DataManager {
 
     /// Returns the `IdAndVersion` for the given data identifier, or `None` if not stored.
     fn to_id_and_version(&self, data_id: DataIdentifier) -> Option<IdAndVersion> {
-        match data_id {
-            DataIdentifier::Immutable(_) => Some((data_id, 0)),
-            DataIdentifier::Structured(..) |
-            DataIdentifier::PrivAppendable(..) |
-            DataIdentifier::PubAppendable(..) => {
-                match self.chunk_store.get(&data_id) {
-                    Ok(Data::Structured(data)) => Some((data_id, data.get_version())),
-                    Ok(Data::PubAppendable(data)) => Some((data_id, data.get_version())),
-                    Ok(Data::PrivAppendable(data)) => Some((data_id, data.get_version())),
-                    _ => {
-                        error!("Failed to get {:?} from chunk store.", data_id);
-                        None
-                    }
-                }
-            }
-        }
+        self.chunk_index.id_and_version(data_id)
+    }
+
+    /// Stores the data in the chunk store and records its version, and the client which paid for
+    /// it if it is being put.
+    fn put_chunk(&mut self, data: &Data, payer: Option<XorName>) -> Result<(), ChunkStoreError> {
+        self.chunk_index.put(&mut *self.chunk_store, data, payer)
+    }
+
+    /// Deletes the data from the chunk store and forgets its version.
+    fn delete_chunk(&mut self, data_id: &DataIdentifier) {
+        self.chunk_index.delete(&mut *self.chunk_store, data_id)
     }
 
     fn count_added_data(&mut self, data_id: &DataIdentifier) {
@@ -1050,9 +1469,27 @@ impl DataManager {
         }
     }
 
-    /// Returns whether our data uses more than `MAX_FULL_PERCENT` percent of available space.
+    /// Drops a chunk we are no longer responsible for: immutable data is kept as unneeded until the
+    /// space is required, other data is deleted.  Returns whether the chunk was counted as stored.
+    fn remove_unneeded_data(&mut self, data_id: DataIdentifier) -> bool {
+        if !self.chunk_store.has(&data_id) || self.cache.is_in_unneeded(&data_id) {
+            return false;
+        }
+        self.count_removed_data(&data_id);
+        if let DataIdentifier::Immutable(..) = data_id {
+            self.cache.add_as_unneeded(data_id);
+        } else {
+            self.delete_chunk(&data_id);
+        }
+        true
+    }
+
+    /// Returns whether our data uses more than `MAX_FULL_PERCENT` percent of available space.  This
+    /// takes the free space left on disk into account, so a vault on a nearly full disk reports
+    /// `NetworkFull` instead of failing to write.
     fn chunk_store_full(&self) -> bool {
-        self.chunk_store.used_space() > (self.chunk_store.max_space() / 100) * MAX_FULL_PERCENT
+        self.chunk_store.used_space() + self.chunk_store.reserved_space() >
+        (self.chunk_store.max_space() / 100) * MAX_FULL_PERCENT
     }
 
     /// Removes data chunks we are no longer responsible for until the chunk store is not full
@@ -1060,13 +1497,30 @@ impl DataManager {
     fn clean_chunk_store(&mut self) {
         while self.chunk_store_full() {
             if let Some(data_id) = self.cache.pop_unneeded_chunk() {
-                let _ = self.chunk_store.delete(&data_id);
+                self.delete_chunk(&data_id);
             } else {
                 break;
             }
         }
     }
 
+    /// Notifies the `ClientManager` group of the client which paid for the deleted data, so it can
+    /// refund the amount charged for the `Put` once a quorum of this group has done so.  Nothing
+    /// is sent if we didn't see the data being put, e.g. because we joined the group later.
+    fn send_refund(&self, data_id: DataIdentifier, version: u64) -> Result<(), InternalError> {
+        let (payer, size) = match self.chunk_index.charge(&data_id) {
+            Some(charge) => charge,
+            None => return Ok(()),
+        };
+        let src = Authority::ManagedNode(self.routing_node.name()?);
+        let dst = Authority::ClientManager(payer);
+        let serialised_refund = serialisation::serialise(&Refund::new(data_id, version, size))?;
+        trace!("DM sending refund of {} bytes for {:?} to {:?}.", size, data_id, dst);
+        let _ = self.routing_node
+            .send_refresh_request(src, dst, serialised_refund, MessageId::new());
+        Ok(())
+    }
+
     fn send_refresh(&self,
                     dst: Authority,
                     data_list: Vec<IdAndVersion>)
@@ -1118,3 +1572,242 @@ struct RefreshDataList(Vec<IdAndVersion>);
 /// quorum of group members approves.
 #[derive(RustcEncodable, RustcDecodable, PartialEq, Eq, Debug, Copy, Clone)]
 struct RefreshData(IdAndVersion, u64);
+
+#[cfg(test)]
+mod tests {
+    use chunk_store::{ChunkStorage, Error, MemoryChunkStore, ReservedSpace};
+    use kademlia_routing_table::RoutingTable;
+    use rand::{self, Rng};
+    use routing::{Data, DataIdentifier, GROUP_SIZE, ImmutableData, StructuredData, XorName};
+    use rust_sodium::crypto::sign;
+    use std::cell::Cell;
+    use std::collections::{BTreeSet, HashSet};
+    use std::iter;
+    use maidsafe_utilities::serialisation;
+    use super::{Cache, ChunkIndex, ChunkInfo, id_and_version_of};
+
+    /// A chunk store which counts how often chunks are read from it.
+    struct CountingChunkStore {
+        store: MemoryChunkStore<DataIdentifier, Data>,
+        reads: Cell<usize>,
+    }
+
+    impl ChunkStorage<DataIdentifier, Data> for CountingChunkStore {
+        fn put(&mut self, key: &DataIdentifier, value: &Data) -> Result<(), Error> {
+            self.store.put(key, value)
+        }
+
+        fn delete(&mut self, key: &DataIdentifier) -> Result<(), Error> {
+            self.store.delete(key)
+        }
+
+        fn get(&self, key: &DataIdentifier) -> Result<Data, Error> {
+            self.reads.set(self.reads.get() + 1);
+            self.store.get(key)
+        }
+
+        fn has(&self, key: &DataIdentifier) -> bool {
+            self.store.has(key)
+        }
+
+        fn keys(&self) -> Vec<DataIdentifier> {
+            self.store.keys()
+        }
+
+        fn max_space(&self) -> u64 {
+            self.store.max_space()
+        }
+
+        fn used_space(&self) -> u64 {
+            self.store.used_space()
+        }
+
+        fn space_needed(&self, key: &DataIdentifier, value: &Data) -> Result<u64, Error> {
+            self.store.space_needed(key, value)
+        }
+
+        fn reserved(&self) -> &ReservedSpace {
+            self.store.reserved()
+        }
+    }
+
+    fn structured_data(name: XorName, version: u64) -> Data {
+        let owners = iter::once(sign::gen_keypair().0).collect::<BTreeSet<_>>();
+        let value = rand::thread_rng().gen_iter().take(10).collect();
+        Data::Structured(unwrap!(StructuredData::new(12345, name, version, value, owners)))
+    }
+
+    /// Returns a routing table for `our_name` and the names of the nodes in it.
+    fn routing_table(our_name: XorName,
+                     node_count: usize)
+                     -> (RoutingTable<XorName>, Vec<XorName>) {
+        let mut routing_table = RoutingTable::new(our_name);
+        let mut node_names = Vec::new();
+        for _ in 0..node_count {
+            let node_name = rand::random();
+            if routing_table.add(node_name).is_some() {
+                node_names.push(node_name);
+            }
+        }
+        (routing_table, node_names)
+    }
+
+    /// Returns the chunks in whose close group, among us and `node_names`, `node_name` is, by
+    /// checking every single chunk.
+    fn full_scan(index: &ChunkIndex,
+                 our_name: &XorName,
+                 node_name: &XorName,
+                 node_names: &[XorName])
+                 -> HashSet<DataIdentifier> {
+        index.all()
+            .into_iter()
+            .map(|(data_id, _)| data_id)
+            .filter(|data_id| {
+                node_names.iter()
+                    .chain(Some(our_name))
+                    .filter(|other| data_id.name().closer(other, node_name))
+                    .count() < GROUP_SIZE
+            })
+            .collect()
+    }
+
+    /// Asserts that all `expected` chunks are reported as affected by the churn event.
+    fn assert_affected(index: &ChunkIndex,
+                       our_name: &XorName,
+                       node_name: &XorName,
+                       routing_table: &RoutingTable<XorName>,
+                       expected: HashSet<DataIdentifier>) {
+        let affected = index.affected_by(our_name, node_name, routing_table)
+            .into_iter()
+            .map(|(data_id, _)| data_id)
+            .collect::<HashSet<_>>();
+        assert!(expected.is_subset(&affected),
+                "Missing affected chunks: {:?}",
+                expected.difference(&affected).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn index_is_used_for_churn_and_stays_consistent() {
+        let mut chunk_store = CountingChunkStore {
+            store: MemoryChunkStore::new(1024 * 1024),
+            reads: Cell::new(0),
+        };
+        let mut index = ChunkIndex::default();
+        let mut immutable_ids = Vec::new();
+        let mut structured_names = Vec::new();
+        let payer = rand::random();
+        for _ in 0..10 {
+            let data = Data::Immutable(ImmutableData::new(rand::thread_rng()
+                .gen_iter()
+                .take(100)
+                .collect()));
+            unwrap!(index.put(&mut chunk_store, &data, None));
+            immutable_ids.push(data.identifier());
+            let name = rand::random();
+            unwrap!(index.put(&mut chunk_store, &structured_data(name, 0), Some(payer)));
+            structured_names.push(name);
+        }
+        let posted_id = DataIdentifier::Structured(structured_names[1], 12345);
+        let charge = index.charge(&posted_id);
+        assert_eq!(Some(payer), charge.map(|(payer, _)| payer));
+        // Post: update some structured data to a new version.  The charge of the `Put` is kept.
+        for name in &structured_names[..5] {
+            unwrap!(index.put(&mut chunk_store, &structured_data(*name, 1), None));
+        }
+        assert_eq!(charge, index.charge(&posted_id));
+        // Delete and quarantine some of each type.
+        index.delete(&mut chunk_store, &immutable_ids[0]);
+        index.delete(&mut chunk_store, &DataIdentifier::Structured(structured_names[0], 12345));
+        unwrap!(index.quarantine(&mut chunk_store, &immutable_ids[1]));
+        unwrap!(index.quarantine(&mut chunk_store,
+                                 &DataIdentifier::Structured(structured_names[6], 12345)));
+
+        // Churn handling takes the refreshed chunks and their versions from the index alone.
+        let our_name = rand::random();
+        let (routing_table, _) = routing_table(our_name, 50);
+        let node_name = rand::random();
+        let refreshed = index.affected_by(&our_name, &node_name, &routing_table);
+        assert_eq!(0, chunk_store.reads.get());
+
+        let stored = chunk_store.keys()
+            .into_iter()
+            .map(|data_id| id_and_version_of(&unwrap!(chunk_store.get(&data_id))))
+            .collect::<HashSet<_>>();
+        assert_eq!(16, stored.len());
+        assert_eq!(stored, index.all().into_iter().collect());
+        assert!(refreshed.iter().all(|data_idv| stored.contains(data_idv)));
+        let named = index.names
+            .buckets(&node_name)
+            .into_iter()
+            .flat_map(|(_, data_ids)| data_ids)
+            .cloned()
+            .collect::<HashSet<_>>();
+        assert_eq!(stored.iter().map(|&(data_id, _)| data_id).collect::<HashSet<_>>(),
+                   named);
+        let loaded = ChunkIndex::load(&chunk_store);
+        assert_eq!(stored, loaded.all().into_iter().collect());
+        assert!(loaded.chunks.values().all(|info| info.charge.is_none()));
+        let total_size = chunk_store.keys()
+            .into_iter()
+            .map(|data_id| unwrap!(serialisation::serialise(&unwrap!(chunk_store.get(&data_id)))))
+            .fold(0, |total_size, serialised| total_size + serialised.len() as u64);
+        assert_eq!(total_size, index.total_size);
+        assert_eq!(total_size, loaded.total_size);
+    }
+
+    #[test]
+    fn get_success_clears_records_up_to_received_version() {
+        let mut cache = Cache::default();
+        let data_id = DataIdentifier::Structured(rand::random(), 10000);
+        let other_data_id = DataIdentifier::Structured(rand::random(), 10000);
+        let holders = (0..3).map(|_| rand::random()).collect::<HashSet<_>>();
+        let holder = *unwrap!(holders.iter().next());
+        // A lost chunk is requested as version 0, a refresh announces version 2 and 5.
+        for data_idv in &[(data_id, 0), (data_id, 2), (data_id, 5), (other_data_id, 0)] {
+            cache.add_records(*data_idv, holders.clone());
+        }
+
+        cache.handle_get_success(holder, &data_id, 2);
+        for data_idvs in cache.data_holders.values() {
+            assert!(!data_idvs.contains(&(data_id, 0)));
+            assert!(!data_idvs.contains(&(data_id, 2)));
+            assert!(data_idvs.contains(&(data_id, 5)));
+            assert!(data_idvs.contains(&(other_data_id, 0)));
+        }
+    }
+
+    #[test]
+    fn affected_chunks_match_full_scan() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..20 {
+            let our_name = rng.gen();
+            let node_count = rng.gen_range(GROUP_SIZE, 100);
+            let (mut routing_table, mut node_names) = routing_table(our_name, node_count);
+            let mut index = ChunkIndex::default();
+            for _ in 0..200 {
+                index.insert(DataIdentifier::Immutable(rng.gen()),
+                             ChunkInfo {
+                                 version: 0,
+                                 size: 0,
+                                 charge: None,
+                             });
+            }
+
+            // A node joins: it is in the close groups of the new routing table.
+            let added_name = rng.gen();
+            if routing_table.add(added_name).is_some() {
+                node_names.push(added_name);
+                let expected = full_scan(&index, &our_name, &added_name, &node_names);
+                assert_affected(&index, &our_name, &added_name, &routing_table, expected);
+            }
+
+            // A node leaves: it was in the close groups of the old routing table.
+            let lost_index = rng.gen_range(0, node_names.len());
+            let lost_name = node_names[lost_index];
+            let expected = full_scan(&index, &our_name, &lost_name, &node_names);
+            let _ = node_names.swap_remove(lost_index);
+            let _ = routing_table.remove(&lost_name);
+            assert_affected(&index, &our_name, &lost_name, &routing_table, expected);
+        }
+    }
+}
@@ -16,6 +16,9 @@
 // relating to use of the SAFE Network Software.
 
 
+use accumulator::Accumulator;
+use chunk_store::{ChunkStorage, ChunkStore, ChunkStoreBuilder};
+use config_handler::Config;
 use error::InternalError;
 use itertools::Itertools;
 use kademlia_routing_table::RoutingTable;
@@ -23,29 +26,72 @@ use maidsafe_utilities::serialisation;
 use routing::{Authority, Data, DataIdentifier, GROUP_SIZE, ImmutableData, MessageId,
               StructuredData, TYPE_TAG_SESSION_PACKET, XorName};
 use routing::client_errors::{GetError, MutationError};
+use std::cmp;
 use std::collections::HashMap;
 use std::collections::hash_map::Entry;
 use std::convert::From;
+use std::path::PathBuf;
 use std::rc::Rc;
+use std::time::Duration;
 use utils;
-use vault::RoutingNode;
+use vault::{self, RoutingNode};
 
 // 500 units, max 100MB for immutable_data (1MB per chunk)
 #[cfg(not(feature = "use-mock-crust"))]
 const DEFAULT_ACCOUNT_SIZE: u64 = 500;
 #[cfg(feature = "use-mock-crust")]
 const DEFAULT_ACCOUNT_SIZE: u64 = 100;
-
+/// The total serialised size of the data a client may store, in bytes.
+const DEFAULT_ACCOUNT_BYTES: u64 = DEFAULT_ACCOUNT_SIZE * 1024 * 1024;
+/// The capacity of the account store.  Each account takes less than 100 bytes.
+const ACCOUNT_STORE_CAPACITY: u64 = 1024 * 1024 * 1024;
+/// The quorum of `DataManager`s needed to refund deleted data.
+const REFUND_QUORUM: usize = GROUP_SIZE / 2 + 1;
+/// The timeout for accumulating refunds.
+const REFUND_TIMEOUT_SECS: u64 = 180;
+
+/// New variants must only be appended, so the encoding of the existing ones doesn't change.
 #[derive(RustcEncodable, RustcDecodable, PartialEq, Eq, Debug, Clone)]
 enum Refresh {
-    Update(XorName, Account),
+    /// An account sent by a vault which doesn't track the bytes stored.
+    Update(XorName, LegacyAccount),
     Delete(XorName),
+    UpdateAccount(XorName, Account),
+}
+
+/// A notification from a `DataManager` that data was deleted, so the serialised size it was
+/// charged for when it was put can be refunded to the account which paid for it.
+#[derive(RustcEncodable, RustcDecodable, PartialEq, Eq, Hash, Debug, Clone)]
+pub struct Refund {
+    data_id: DataIdentifier,
+    version: u64,
+    size: u64,
+}
+
+impl Refund {
+    pub fn new(data_id: DataIdentifier, version: u64, size: u64) -> Refund {
+        Refund {
+            data_id: data_id,
+            version: version,
+            size: size,
+        }
+    }
+}
+
+/// The account fields known to vaults which only count chunks.
+#[derive(RustcEncodable, RustcDecodable, PartialEq, Eq, Debug, Clone)]
+struct LegacyAccount {
+    data_stored: u64,
+    space_available: u64,
+    version: u64,
 }
 
 #[derive(RustcEncodable, RustcDecodable, PartialEq, Eq, Debug, Clone)]
 pub struct Account {
     data_stored: u64,
     space_available: u64,
+    bytes_stored: u64,
+    bytes_available: u64,
     version: u64,
 }
 
@@ -54,44 +100,199 @@ impl Default for Account {
         Account {
             data_stored: 0,
             space_available: DEFAULT_ACCOUNT_SIZE,
+            bytes_stored: 0,
+            bytes_available: DEFAULT_ACCOUNT_BYTES,
             version: 0,
         }
     }
 }
 
 impl Account {
-    fn add_entry(&mut self) -> Result<(), MutationError> {
-        if self.space_available < 1 {
+    /// Charges the account for a chunk with the given serialised size.
+    fn add_entry(&mut self, size: u64) -> Result<(), MutationError> {
+        if self.space_available < 1 || self.bytes_available < size {
             return Err(MutationError::LowBalance);
         }
         self.data_stored += 1;
         self.space_available -= 1;
+        self.bytes_stored += size;
+        self.bytes_available -= size;
         self.version += 1;
         Ok(())
     }
 
-    fn remove_entry(&mut self) {
-        self.data_stored -= 1;
-        self.space_available += 1;
+    /// Refunds a chunk with the given serialised size.
+    fn remove_entry(&mut self, size: u64) {
+        // Accounts received from vaults which don't track bytes may hold less than was charged.
+        let size = cmp::min(size, self.bytes_stored);
+        if self.data_stored > 0 {
+            self.data_stored -= 1;
+            self.space_available += 1;
+        }
+        self.bytes_stored -= size;
+        self.bytes_available += size;
         self.version += 1;
     }
+
+    /// Takes the chunk counts and version from an account sent by a vault which doesn't track
+    /// bytes, keeping the byte counts.
+    fn update_counts(&mut self, legacy_account: LegacyAccount) {
+        self.data_stored = legacy_account.data_stored;
+        self.space_available = legacy_account.space_available;
+        self.version = legacy_account.version;
+    }
+
+    /// Returns the fields known to vaults which don't track bytes.
+    fn legacy(&self) -> LegacyAccount {
+        LegacyAccount {
+            data_stored: self.data_stored,
+            space_available: self.space_available,
+            version: self.version,
+        }
+    }
 }
 
+/// The client accounts managed by this vault, optionally kept across restarts.
+struct Accounts {
+    /// The accounts confirmed by the group.  Only these can be charged for `Put`s.
+    verified: HashMap<XorName, Account>,
+    /// Accounts loaded from the account store which the group hasn't confirmed yet.  They are
+    /// only used once a `Refresh` for them has been received.
+    unverified: HashMap<XorName, Account>,
+    /// Keeps the accounts across restarts, if configured.
+    store: Option<ChunkStore<XorName, Account>>,
+}
+
+impl Accounts {
+    /// Opens the account store in `root`, if given, and loads the accounts in it as unverified.
+    fn new(root: Option<PathBuf>) -> Result<Accounts, InternalError> {
+        let mut unverified = HashMap::new();
+        let store = match root {
+            Some(root) => {
+                let store: ChunkStore<XorName, Account> =
+                    ChunkStoreBuilder::new(root, ACCOUNT_STORE_CAPACITY).persistent(true).create()?;
+                for maid_name in store.keys() {
+                    match store.get(&maid_name) {
+                        Ok(account) => {
+                            let _ = unverified.insert(maid_name, account);
+                        }
+                        Err(error) => warn!("Failed to load account {:?}: {:?}", maid_name, error),
+                    }
+                }
+                if !unverified.is_empty() {
+                    info!("Loaded {} client accounts.", unverified.len());
+                }
+                Some(store)
+            }
+            None => None,
+        };
+        Ok(Accounts {
+            verified: HashMap::new(),
+            unverified: unverified,
+            store: store,
+        })
+    }
+
+    /// Returns whether the client has an account, even one which hasn't been confirmed yet.
+    fn contains(&self, maid_name: &XorName) -> bool {
+        self.verified.contains_key(maid_name) || self.unverified.contains_key(maid_name)
+    }
 
+    /// Returns our latest copy of the client's account, even one which hasn't been confirmed yet.
+    fn latest(&self, maid_name: &XorName) -> Option<&Account> {
+        self.verified.get(maid_name).or_else(|| self.unverified.get(maid_name))
+    }
+
+    /// Takes the account sent by the group, which confirms it, unless our copy is more recent.
+    /// The account store is only written to if the account changed.
+    fn confirm(&mut self, maid_name: XorName, account: Account) {
+        // The loaded version isn't needed any more.
+        let loaded = self.unverified.remove(&maid_name);
+        let account_count = self.verified.len();
+        let changed = match self.verified.entry(maid_name) {
+            Entry::Vacant(entry) => {
+                let changed = loaded.as_ref() != Some(&account);
+                let _ = entry.insert(account);
+                info!("Stats - {} client accounts.", account_count + 1);
+                changed
+            }
+            Entry::Occupied(mut entry) => {
+                if entry.get().version < account.version {
+                    trace!("Client account {:?}: {:?}", maid_name, account);
+                    let _ = entry.insert(account);
+                    true
+                } else {
+                    false
+                }
+            }
+        };
+        if changed {
+            self.save(&maid_name);
+        }
+    }
+
+    /// Removes the client's account, whether confirmed or not.
+    fn remove(&mut self, maid_name: &XorName) {
+        let _ = self.verified.remove(maid_name);
+        let _ = self.unverified.remove(maid_name);
+        self.save(maid_name);
+    }
+
+    /// Writes the account to the account store, or removes it from there if it was deleted.
+    fn save(&mut self, maid_name: &XorName) {
+        let store = match self.store {
+            Some(ref mut store) => store,
+            None => return,
+        };
+        let account = match self.verified.get(maid_name) {
+            Some(account) => Some(account),
+            None => self.unverified.get(maid_name),
+        };
+        let result = match account {
+            Some(account) => store.put(maid_name, account),
+            None if store.has(maid_name) => store.delete(maid_name),
+            None => Ok(()),
+        };
+        if let Err(error) = result {
+            warn!("Failed to save account {:?}: {:?}", maid_name, error);
+        }
+    }
+}
 
 pub struct MaidManager {
     routing_node: Rc<RoutingNode>,
-    accounts: HashMap<XorName, Account>,
-    request_cache: HashMap<MessageId, (Authority, Authority)>,
+    accounts: Accounts,
+    /// The forwarded `Put` requests, with the serialised size of their data.
+    request_cache: HashMap<MessageId, (Authority, Authority, u64)>,
+    /// The refunds for each client received from the `DataManager`s, by sender.
+    refund_accumulator: Accumulator<(XorName, Refund), XorName>,
+    /// Whether refreshes carry the whole account instead of only the fields older vaults know.
+    /// Otherwise, the group's vaults wouldn't agree on the bytes stored, so `Put`s aren't charged
+    /// any bytes.
+    send_account_bytes: bool,
+    /// Whether `GetAccountInfo` is answered with the byte counts instead of the chunk counts.
+    /// This requires `send_account_bytes`.
+    report_account_bytes: bool,
 }
 
 impl MaidManager {
-    pub fn new(routing_node: Rc<RoutingNode>) -> MaidManager {
-        MaidManager {
+    /// Creates a `MaidManager`, loading the accounts from the account store if one is
+    /// configured.  The loaded accounts are unverified until the group confirms them.
+    pub fn new(routing_node: Rc<RoutingNode>,
+               config: &Config)
+               -> Result<MaidManager, InternalError> {
+        let send_account_bytes = config.send_account_bytes.unwrap_or(false);
+        Ok(MaidManager {
             routing_node: routing_node,
-            accounts: HashMap::new(),
+            accounts: Accounts::new(vault::account_store_root(config))?,
             request_cache: HashMap::new(),
-        }
+            refund_accumulator:
+                Accumulator::with_duration(REFUND_QUORUM,
+                                           Duration::from_secs(REFUND_TIMEOUT_SECS)),
+            send_account_bytes: send_account_bytes,
+            report_account_bytes: send_account_bytes &&
+                                  config.report_account_bytes.unwrap_or(false),
+        })
     }
 
     pub fn handle_put(&mut self,
@@ -128,11 +329,14 @@ impl MaidManager {
                               msg_id: MessageId)
                               -> Result<(), InternalError> {
         match self.request_cache.remove(&msg_id) {
-            Some((src, dst)) => {
+            Some((src, dst, _)) => {
                 // Send success response back to client
                 let client_name = utils::client_name(&src);
                 self.send_refresh(&client_name,
-                                  self.accounts.get(&client_name).expect("Account not found."),
+                                  self.accounts
+                                      .verified
+                                      .get(&client_name)
+                                      .expect("Account not found."),
                                   MessageId::zero());
                 let _ = self.routing_node.send_put_success(dst, src, data_id, msg_id);
                 Ok(())
@@ -147,15 +351,19 @@ impl MaidManager {
                               external_error_indicator: &[u8])
                               -> Result<(), InternalError> {
         match self.request_cache.remove(&msg_id) {
-            Some((src, dst)) => {
+            Some((src, dst, size)) => {
                 // Refund account
-                match self.accounts.get_mut(&utils::client_name(&src)) {
-                    Some(account) => account.remove_entry(),
+                match self.accounts.verified.get_mut(&utils::client_name(&src)) {
+                    Some(account) => account.remove_entry(size),
                     None => return Ok(()),
                 }
                 let client_name = utils::client_name(&src);
+                self.accounts.save(&client_name);
                 self.send_refresh(&client_name,
-                                  self.accounts.get(&client_name).expect("Account not found."),
+                                  self.accounts
+                                      .verified
+                                      .get(&client_name)
+                                      .expect("Account not found."),
                                   MessageId::zero());
                 // Send failure response back to client
                 let error = match (data_id,
@@ -164,7 +372,7 @@ impl MaidManager {
                      MutationError::DataExists) => {
                         // We wouldn't have forwarded two `Put` requests for the same account, so
                         // it must have been created via another client manager.
-                        let _ = self.accounts.remove(&client_name);
+                        self.accounts.remove(&client_name);
                         let refresh = Refresh::Delete(client_name);
                         if let Ok(serialised_refresh) = serialisation::serialise(&refresh) {
                             trace!("MM sending delete refresh for account {}", src.name());
@@ -187,12 +395,14 @@ impl MaidManager {
                                    msg_id: MessageId)
                                    -> Result<(), InternalError> {
         let client_name = utils::client_name(&src);
-        if let Some(account) = self.accounts.get(&client_name) {
-            let _ = self.routing_node.send_get_account_info_success(dst,
-                                                                    src,
-                                                                    account.data_stored,
-                                                                    account.space_available,
-                                                                    msg_id);
+        if let Some(account) = self.accounts.verified.get(&client_name) {
+            let (stored, available) = if self.report_account_bytes {
+                (account.bytes_stored, account.bytes_available)
+            } else {
+                (account.data_stored, account.space_available)
+            };
+            let _ = self.routing_node
+                .send_get_account_info_success(dst, src, stored, available, msg_id);
         } else {
             let external_error_indicator = serialisation::serialise(&GetError::NoSuchAccount)?;
             let _ = self.routing_node
@@ -202,44 +412,87 @@ impl MaidManager {
     }
 
     pub fn handle_refresh(&mut self, serialised_msg: &[u8]) -> Result<(), InternalError> {
-        match serialisation::deserialise::<Refresh>(serialised_msg)? {
-            Refresh::Update(maid_name, account) => {
-                match self.routing_node.close_group(maid_name) {
-                    Ok(None) | Err(_) => return Ok(()),
-                    Ok(Some(_)) => (),
-                }
-                let account_count = self.accounts.len();
-                match self.accounts.entry(maid_name) {
-                    Entry::Vacant(entry) => {
-                        let _ = entry.insert(account);
-                        info!("Stats - {} client accounts.", account_count + 1);
-                    }
-                    Entry::Occupied(mut entry) => {
-                        if entry.get().version < account.version {
-                            trace!("Client account {:?}: {:?}", maid_name, account);
-                            let _ = entry.insert(account);
-                        }
-                    }
-                }
+        let (maid_name, account) = match serialisation::deserialise::<Refresh>(serialised_msg)? {
+            Refresh::UpdateAccount(maid_name, account) => (maid_name, account),
+            Refresh::Update(maid_name, legacy_account) => {
+                let mut account =
+                    self.accounts.latest(&maid_name).cloned().unwrap_or_else(Account::default);
+                account.update_counts(legacy_account);
+                (maid_name, account)
             }
             Refresh::Delete(maid_name) => {
-                let _ = self.accounts.remove(&maid_name);
-                info!("Stats - {} client accounts.", self.accounts.len());
+                self.accounts.remove(&maid_name);
+                info!("Stats - {} client accounts.", self.accounts.verified.len());
+                return Ok(());
+            }
+        };
+        match self.routing_node.close_group(maid_name) {
+            Ok(None) | Err(_) => return Ok(()),
+            Ok(Some(_)) => (),
+        }
+        self.accounts.confirm(maid_name, account);
+        Ok(())
+    }
+
+    /// Handles a refund for deleted data sent by the `DataManager` `src`.  Once a quorum of the
+    /// data's `NaeManager` group has sent the same refund, the client's account is credited with
+    /// the chunk and the serialised size it was charged for when it put the data.
+    pub fn handle_refund(&mut self,
+                         src: XorName,
+                         client_name: XorName,
+                         serialised_refund: &[u8])
+                         -> Result<(), InternalError> {
+        let refund = serialisation::deserialise::<Refund>(serialised_refund)?;
+        let key = (client_name, refund);
+        if self.refund_accumulator.add(key.clone(), src).is_none() {
+            return Ok(());
+        }
+        self.refund_accumulator.delete(&key);
+        let (client_name, refund) = key;
+        // Like `Put`s, refunds only carry bytes if the refreshes do.
+        let size = if self.send_account_bytes {
+            refund.size
+        } else {
+            0
+        };
+        match self.accounts.verified.get_mut(&client_name) {
+            Some(account) => {
+                account.remove_entry(size);
+                trace!("Client account {:?} refunded for {:?} version {}: {:?}",
+                       client_name,
+                       refund.data_id,
+                       refund.version,
+                       account);
             }
+            None => return Ok(()),
         }
+        self.accounts.save(&client_name);
+        self.send_refresh(&client_name,
+                          self.accounts.verified.get(&client_name).expect("Account not found."),
+                          MessageId::zero());
         Ok(())
     }
 
+    /// Asks the group to confirm the accounts loaded from the account store.
+    pub fn handle_connected(&mut self) {
+        for (maid_name, account) in &self.accounts.unverified {
+            self.send_refresh(maid_name, account, MessageId::zero());
+        }
+    }
+
     pub fn handle_node_added(&mut self,
                              node_name: &XorName,
                              routing_table: &RoutingTable<XorName>) {
         // Remove all accounts which we are no longer responsible for.
         let not_close = |name: &&XorName| !routing_table.is_close(*name, GROUP_SIZE);
-        let accounts_to_delete = self.accounts.keys().filter(not_close).cloned().collect_vec();
+        let accounts_to_delete =
+            self.accounts.verified.keys().filter(&not_close).cloned().collect_vec();
+        let unverified_accounts_to_delete =
+            self.accounts.unverified.keys().filter(&not_close).cloned().collect_vec();
         // Remove all requests from the cache that we are no longer responsible for.
         let msg_ids_to_delete = self.request_cache
             .iter()
-            .filter(|&(_, &(ref src, _))| accounts_to_delete.contains(src.name()))
+            .filter(|&(_, &(ref src, _, _))| accounts_to_delete.contains(src.name()))
             .map(|(msg_id, _)| *msg_id)
             .collect_vec();
         for msg_id in msg_ids_to_delete {
@@ -247,27 +500,32 @@ impl MaidManager {
         }
         if !accounts_to_delete.is_empty() {
             info!("Stats - {} client accounts.",
-                  self.accounts.len() - accounts_to_delete.len());
+                  self.accounts.verified.len() - accounts_to_delete.len());
         }
-        for maid_name in accounts_to_delete {
+        for maid_name in accounts_to_delete.into_iter().chain(unverified_accounts_to_delete) {
             trace!("No longer a MM for {}", maid_name);
-            let _ = self.accounts.remove(&maid_name);
+            self.accounts.remove(&maid_name);
         }
-        // Send refresh messages for the remaining accounts.
-        for (maid_name, account) in &self.accounts {
+        // Send refresh messages for the remaining accounts, including the unverified ones, so the
+        // group can confirm them.
+        for (maid_name, account) in self.accounts.verified.iter().chain(&self.accounts.unverified) {
             self.send_refresh(maid_name, account, MessageId::from_added_node(*node_name));
         }
     }
 
     pub fn handle_node_lost(&mut self, node_name: &XorName) {
-        for (maid_name, account) in &self.accounts {
+        for (maid_name, account) in self.accounts.verified.iter().chain(&self.accounts.unverified) {
             self.send_refresh(maid_name, account, MessageId::from_lost_node(*node_name));
         }
     }
 
     fn send_refresh(&self, maid_name: &XorName, account: &Account, msg_id: MessageId) {
         let src = Authority::ClientManager(*maid_name);
-        let refresh = Refresh::Update(*maid_name, account.clone());
+        let refresh = if self.send_account_bytes {
+            Refresh::UpdateAccount(*maid_name, account.clone())
+        } else {
+            Refresh::Update(*maid_name, account.legacy())
+        };
         if let Ok(serialised_refresh) = serialisation::serialise(&refresh) {
             trace!("MM sending refresh for account {}", src.name());
             let _ = self.routing_node
@@ -304,15 +562,16 @@ impl MaidManager {
                 self.reply_with_put_failure(src, dst, data.identifier(), msg_id, &error)?;
                 return Err(From::from(error));
             }
-            if self.accounts.contains_key(&client_name) {
+            // An account which hasn't been confirmed yet mustn't be replaced by a new one.
+            if self.accounts.contains(&client_name) {
                 let error = MutationError::AccountExists;
                 self.reply_with_put_failure(src, dst, data.identifier(), msg_id, &error)?;
                 return Err(From::from(error));
             }
 
             // Create the account, the SD incurs charge later on
-            let _ = self.accounts.insert(client_name, Account::default());
-            info!("Stats - {} client accounts.", self.accounts.len());
+            let _ = self.accounts.verified.insert(client_name, Account::default());
+            info!("Stats - {} client accounts.", self.accounts.verified.len());
         }
         let structured_data = Data::Structured(data);
         self.forward_put_request(src, dst, client_name, structured_data, msg_id)
@@ -326,11 +585,17 @@ impl MaidManager {
                            msg_id: MessageId)
                            -> Result<(), InternalError> {
         // Account must already exist to Put Data.
+        let size = if self.send_account_bytes {
+            serialisation::serialise(&data)?.len() as u64
+        } else {
+            0
+        };
         let result = self.accounts
+            .verified
             .get_mut(&client_name)
             .ok_or(MutationError::NoSuchAccount)
             .and_then(|account| {
-                let result = account.add_entry();
+                let result = account.add_entry(size);
                 trace!("Client account {:?}: {:?}", client_name, account);
                 result
             });
@@ -341,6 +606,7 @@ impl MaidManager {
             self.reply_with_put_failure(src, dst, data.identifier(), msg_id, &error)?;
             return Err(From::from(error));
         }
+        self.accounts.save(&client_name);
         {
             // forwarding data_request to NAE Manager
             let src = dst;
@@ -349,7 +615,8 @@ impl MaidManager {
             let _ = self.routing_node.send_put_request(src, dst, data, msg_id);
         }
 
-        if let Some((prior_src, prior_dst)) = self.request_cache.insert(msg_id, (src, dst)) {
+        if let Some((prior_src, prior_dst, _)) =
+               self.request_cache.insert(msg_id, (src, dst, size)) {
             error!("Overwrote existing cached request with {:?} from {:?} to {:?}",
                    msg_id,
                    prior_src,
@@ -374,13 +641,26 @@ impl MaidManager {
 
     #[cfg(feature = "use-mock-crust")]
     pub fn get_put_count(&self, client_name: &XorName) -> Option<u64> {
-        self.accounts.get(client_name).map(|account| account.data_stored)
+        self.accounts.verified.get(client_name).map(|account| account.data_stored)
+    }
+
+    #[cfg(feature = "use-mock-crust")]
+    pub fn get_bytes_stored(&self, client_name: &XorName) -> Option<(u64, u64)> {
+        self.accounts
+            .verified
+            .get(client_name)
+            .map(|account| (account.bytes_stored, account.bytes_available))
     }
 }
 
 #[cfg(test)]
 mod test {
-    use super::*;
+    use chunk_store::ChunkStorage;
+    use maidsafe_utilities::serialisation;
+    use rand;
+    use routing::XorName;
+    use super::{Account, Accounts, LegacyAccount, Refresh};
+    use tempdir::TempDir;
 
     #[test]
     fn account_struct_normal_updates() {
@@ -389,13 +669,13 @@ mod test {
         assert_eq!(0, account.data_stored);
         assert_eq!(super::DEFAULT_ACCOUNT_SIZE, account.space_available);
         for _ in 0..super::DEFAULT_ACCOUNT_SIZE {
-            assert!(account.add_entry().is_ok());
+            assert!(account.add_entry(1).is_ok());
         }
         assert_eq!(super::DEFAULT_ACCOUNT_SIZE, account.data_stored);
         assert_eq!(0, account.space_available);
 
         for _ in 0..super::DEFAULT_ACCOUNT_SIZE {
-            account.remove_entry();
+            account.remove_entry(1);
         }
         assert_eq!(0, account.data_stored);
         assert_eq!(super::DEFAULT_ACCOUNT_SIZE, account.space_available);
@@ -408,13 +688,121 @@ mod test {
         assert_eq!(0, account.data_stored);
         assert_eq!(super::DEFAULT_ACCOUNT_SIZE, account.space_available);
         for _ in 0..super::DEFAULT_ACCOUNT_SIZE {
-            assert!(account.add_entry().is_ok());
+            assert!(account.add_entry(1).is_ok());
         }
         assert_eq!(super::DEFAULT_ACCOUNT_SIZE, account.data_stored);
         assert_eq!(0, account.space_available);
-        assert!(account.add_entry().is_err());
+        assert!(account.add_entry(1).is_err());
         assert_eq!(super::DEFAULT_ACCOUNT_SIZE, account.data_stored);
         assert_eq!(0, account.space_available);
     }
 
+    #[test]
+    fn account_struct_byte_updates() {
+        let mut account = Account::default();
+        let size = super::DEFAULT_ACCOUNT_BYTES / 2;
+
+        assert!(account.add_entry(size).is_ok());
+        assert!(account.add_entry(size + 1).is_err());
+        assert!(account.add_entry(size).is_ok());
+        assert_eq!(2, account.data_stored);
+        assert_eq!(super::DEFAULT_ACCOUNT_BYTES, account.bytes_stored);
+        assert_eq!(0, account.bytes_available);
+        assert!(account.add_entry(1).is_err());
+
+        account.remove_entry(size);
+        assert_eq!(1, account.data_stored);
+        assert_eq!(size, account.bytes_stored);
+        assert_eq!(size, account.bytes_available);
+    }
+
+    #[test]
+    fn legacy_refresh_encoding() {
+        // The `Refresh` message sent by vaults which only count chunks.
+        #[derive(RustcEncodable)]
+        enum OldRefresh {
+            Update(XorName, LegacyAccount),
+        }
+
+        let legacy_account = LegacyAccount {
+            data_stored: 3,
+            space_available: 7,
+            version: 5,
+        };
+        let name = rand::random();
+        let serialised = unwrap!(serialisation::serialise(&OldRefresh::Update(name,
+                                                                         legacy_account.clone())));
+        let refresh = unwrap!(serialisation::deserialise::<Refresh>(&serialised));
+        assert_eq!(refresh, Refresh::Update(name, legacy_account.clone()));
+
+        let mut account = Account::default();
+        unwrap!(account.add_entry(100));
+        account.update_counts(legacy_account.clone());
+        assert_eq!(3, account.data_stored);
+        assert_eq!(7, account.space_available);
+        assert_eq!(100, account.bytes_stored);
+        assert_eq!(5, account.version);
+        assert_eq!(legacy_account, account.legacy());
+    }
+
+    #[test]
+    fn accounts_are_reloaded_unverified() {
+        let tempdir = unwrap!(TempDir::new("test"));
+        let root = tempdir.path().join("accounts");
+        let maid_name = rand::random();
+        let mut account = Account::default();
+        unwrap!(account.add_entry(100));
+        {
+            let mut accounts = unwrap!(Accounts::new(Some(root.clone())));
+            accounts.confirm(maid_name, account.clone());
+            assert_eq!(Some(&account), accounts.verified.get(&maid_name));
+        }
+
+        // After a restart, the account still exists, but can't be charged until it is confirmed.
+        let mut accounts = unwrap!(Accounts::new(Some(root)));
+        assert!(accounts.contains(&maid_name));
+        assert_eq!(Some(&account), accounts.latest(&maid_name));
+        assert!(accounts.verified.get_mut(&maid_name).is_none());
+
+        // Confirming an unchanged account doesn't write it to the store again.
+        unwrap!(unwrap!(accounts.store.as_mut()).delete(&maid_name));
+        accounts.confirm(maid_name, account.clone());
+        assert!(!unwrap!(accounts.store.as_ref()).has(&maid_name));
+
+        let mut group_account = account.clone();
+        unwrap!(group_account.add_entry(50));
+        accounts.confirm(maid_name, group_account.clone());
+        assert!(accounts.unverified.is_empty());
+        assert_eq!(Some(&group_account), accounts.verified.get(&maid_name));
+        assert_eq!(group_account,
+                   unwrap!(unwrap!(accounts.store.as_ref()).get(&maid_name)));
+    }
+
+    #[test]
+    fn removed_accounts_are_deleted_from_the_store() {
+        let tempdir = unwrap!(TempDir::new("test"));
+        let root = tempdir.path().join("accounts");
+        let deleted_name = rand::random();
+        let unconfirmed_name = rand::random();
+        {
+            let mut accounts = unwrap!(Accounts::new(Some(root.clone())));
+            accounts.confirm(deleted_name, Account::default());
+            accounts.confirm(unconfirmed_name, Account::default());
+        }
+
+        // Remove one account after the group confirmed it, and the other one before.
+        {
+            let mut accounts = unwrap!(Accounts::new(Some(root.clone())));
+            assert_eq!(2, accounts.unverified.len());
+            accounts.confirm(deleted_name, Account::default());
+            accounts.remove(&deleted_name);
+            accounts.remove(&unconfirmed_name);
+            assert!(!accounts.contains(&deleted_name));
+            assert!(!accounts.contains(&unconfirmed_name));
+        }
+
+        let accounts = unwrap!(Accounts::new(Some(root)));
+        assert!(accounts.unverified.is_empty());
+        assert!(unwrap!(accounts.store.as_ref()).keys().is_empty());
+    }
 }
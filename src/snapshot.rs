@@ -0,0 +1,247 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! Point-in-time snapshots of all chunk stores of a vault.  Each store is written to a
+//! subdirectory of the snapshot directory named after its position in the config.
+//!
+//! The stores of a vault which isn't running are opened read-only and snapshotted directly.  A
+//! running vault holds the locks of its stores, so it is asked to write the snapshot itself: the
+//! destination is written to a request file next to the executable, which the vault picks up on
+//! its next tick.  The vault handles one event at a time, so no chunks are written until the
+//! snapshot is complete, and the outcome is written to a result file the requester waits for.
+//! Restoring a snapshot always requires the vault to be stopped.
+
+use chunk_store::{self, ChunkStorage, ChunkStore, SnapshotStats};
+use config_file_handler;
+use config_handler;
+use error::InternalError;
+use personas::data_manager::DataManager;
+use routing::{Data, DataIdentifier};
+use rust_sodium;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+use vault;
+
+/// The extension of the file asking a running vault for a snapshot.
+const REQUEST_FILE_EXTENSION: &'static str = "snapshot_request";
+/// The extension of the file with the outcome of a snapshot written by a running vault.
+const RESULT_FILE_EXTENSION: &'static str = "snapshot_result";
+/// How long to wait for a running vault to write a requested snapshot.
+const REQUEST_TIMEOUT_SECS: u64 = 300;
+/// How often to check whether a running vault has written a requested snapshot.
+const POLL_INTERVAL_MS: u64 = 100;
+/// The prefix of a result file reporting a failed snapshot.
+const ERROR_PREFIX: &'static str = "error: ";
+
+/// Writes a snapshot of every chunk store configured in the vault config file to the new
+/// directory `dest`, hardlinking the chunk files where possible.  If the vault is running, it is
+/// asked to write the snapshot, pausing its writes meanwhile; otherwise the stores are opened
+/// read-only.
+pub fn snapshot_chunk_stores(dest: &Path) -> Result<Vec<SnapshotStats>, InternalError> {
+    rust_sodium::init();
+    let config = config_handler::read_config_file()?;
+    let roots = vault::all_chunk_store_roots(&config);
+    if roots.iter().any(|&(ref root, _)| chunk_store::is_in_use(root)) {
+        return request_snapshot(dest);
+    }
+    create_snapshot_dir(dest)?;
+    let mut stats = Vec::new();
+    for (index, (root, capacity)) in roots.into_iter().enumerate() {
+        let builder = DataManager::chunk_store_builder(&config, root, capacity)?;
+        let chunk_store: ChunkStore<DataIdentifier, Data> =
+            builder.persistent(true).read_only(true).create()?;
+        stats.push(chunk_store.snapshot(&dest.join(index.to_string()))?);
+    }
+    Ok(stats)
+}
+
+/// Writes the snapshot requested from the running vault, if there is a request.  The outcome is
+/// written to the result file; failures to access the request and result files themselves are
+/// only logged.
+pub fn handle_snapshot_request(data_manager: &DataManager) {
+    let (request_path, result_path) =
+        match (file_path(REQUEST_FILE_EXTENSION), file_path(RESULT_FILE_EXTENSION)) {
+            (Ok(request_path), Ok(result_path)) => (request_path, result_path),
+            _ => return,
+        };
+    let mut dest = String::new();
+    match File::open(&request_path) {
+        Ok(mut file) => {
+            if let Err(error) = file.read_to_string(&mut dest) {
+                warn!("Failed to read snapshot request: {}", error);
+                return;
+            }
+        }
+        Err(_) => return,
+    }
+    if let Err(error) = fs::remove_file(&request_path) {
+        warn!("Failed to remove snapshot request: {}", error);
+        return;
+    }
+    let dest = Path::new(dest.trim_right_matches('\n'));
+    let result = create_snapshot_dir(dest)
+        .and_then(|()| data_manager.snapshot_chunk_stores(dest));
+    let contents = match result {
+        Ok(stats) => {
+            stats.iter().map(|stats| format!("{} {}\n", stats.files, stats.bytes)).collect()
+        }
+        Err(error) => format!("{}{:?}\n", ERROR_PREFIX, error),
+    };
+    if let Err(error) = write_file(&result_path, contents.as_bytes()) {
+        warn!("Failed to write snapshot result: {}", error);
+    }
+}
+
+/// Asks the running vault to write a snapshot to `dest` and waits for the outcome.
+fn request_snapshot(dest: &Path) -> Result<Vec<SnapshotStats>, InternalError> {
+    let request_path = file_path(REQUEST_FILE_EXTENSION)?;
+    let result_path = file_path(RESULT_FILE_EXTENSION)?;
+    remove_if_exists(&result_path)?;
+    // The vault may run in a different working directory.
+    let dest = env::current_dir()?.join(dest);
+    write_file(&request_path, format!("{}\n", dest.display()).as_bytes())?;
+    let start = Instant::now();
+    while !result_path.exists() {
+        if start.elapsed() > Duration::from_secs(REQUEST_TIMEOUT_SECS) {
+            remove_if_exists(&request_path)?;
+            return Err(InternalError::SnapshotTimeout);
+        }
+        thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+    }
+    let mut contents = String::new();
+    let _ = File::open(&result_path)?.read_to_string(&mut contents)?;
+    fs::remove_file(&result_path)?;
+    parse_result(&contents)
+}
+
+/// Parses the result file written by `handle_snapshot_request`.
+fn parse_result(contents: &str) -> Result<Vec<SnapshotStats>, InternalError> {
+    if contents.starts_with(ERROR_PREFIX) {
+        let message = contents[ERROR_PREFIX.len()..].trim_right();
+        return Err(InternalError::SnapshotFailed(message.to_owned()));
+    }
+    contents.lines()
+        .map(|line| {
+            let mut fields = line.split(' ').map(|field| field.parse::<u64>().ok());
+            match (fields.next(), fields.next(), fields.next()) {
+                (Some(Some(files)), Some(Some(bytes)), None) => {
+                    Ok(SnapshotStats {
+                        files: files,
+                        bytes: bytes,
+                    })
+                }
+                _ => Err(InternalError::SnapshotFailed(format!("Invalid result: {}", line))),
+            }
+        })
+        .collect()
+}
+
+/// Creates the new directory `dest` and its missing parents.
+fn create_snapshot_dir(dest: &Path) -> Result<(), InternalError> {
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    Ok(fs::create_dir(dest)?)
+}
+
+/// Returns the path of the request or result file with the given extension, which is kept in
+/// the `current_bin_dir()`.
+fn file_path(extension: &str) -> Result<PathBuf, InternalError> {
+    let mut name = config_file_handler::exe_file_stem()?;
+    name.push(".");
+    name.push(extension);
+    Ok(config_file_handler::current_bin_dir()?.join(name))
+}
+
+/// Writes `contents` to a temporary file which is then renamed to `path`, so the file is never
+/// seen half-written.
+fn write_file(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut temp_path = path.as_os_str().to_os_string();
+    temp_path.push(".tmp");
+    let mut file = File::create(&temp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&temp_path, path)
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(ref error) if error.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(error) => Err(error),
+    }
+}
+
+/// Replaces the contents of the chunk stores configured in the vault config file, which must be
+/// persistent, with the snapshot in `snapshot_dir`.  Returns the number of chunks restored.  This
+/// fails if the vault is running, or if the snapshot was made with a different number of stores.
+/// The snapshots of all stores are checked before any store is replaced, so an incomplete snapshot
+/// leaves all of them untouched.
+pub fn restore_chunk_stores(snapshot_dir: &Path) -> Result<u64, InternalError> {
+    rust_sodium::init();
+    let config = config_handler::read_config_file()?;
+    if !config.persistent_chunk_store.unwrap_or(false) {
+        return Err(InternalError::ChunkStoreNotPersistent);
+    }
+    let roots = vault::all_chunk_store_roots(&config);
+    let store_count = fs::read_dir(snapshot_dir)?.count();
+    if store_count != roots.len() {
+        return Err(From::from(chunk_store::Error::InvalidSnapshot));
+    }
+    for index in 0..store_count {
+        chunk_store::check_snapshot(&snapshot_dir.join(index.to_string()))?;
+    }
+    let mut chunks = 0;
+    for (index, (root, capacity)) in roots.into_iter().enumerate() {
+        let builder = DataManager::chunk_store_builder(&config, root, capacity)?;
+        let chunk_store: ChunkStore<DataIdentifier, Data> =
+            builder.restore_from(snapshot_dir.join(index.to_string())).create()?;
+        chunks += chunk_store.iter_keys().count() as u64;
+    }
+    Ok(chunks)
+}
+
+#[cfg(test)]
+mod tests {
+    use chunk_store::SnapshotStats;
+    use error::InternalError;
+    use super::parse_result;
+
+    #[test]
+    fn snapshot_results() {
+        let stats = unwrap!(parse_result("3 1000\n0 0\n"));
+        assert_eq!(stats,
+                   vec![SnapshotStats {
+                            files: 3,
+                            bytes: 1000,
+                        },
+                        SnapshotStats::default()]);
+        assert!(unwrap!(parse_result("")).is_empty());
+        match parse_result("error: Io(NotFound)\n") {
+            Err(InternalError::SnapshotFailed(message)) => assert_eq!(message, "Io(NotFound)"),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        match parse_result("3\n") {
+            Err(InternalError::SnapshotFailed(_)) => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+}
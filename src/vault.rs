@@ -16,7 +16,7 @@
 // relating to use of the SAFE Network Software.
 
 
-use cache::Cache;
+use cache::{Cache, CacheStats};
 use config_handler::{self, Config};
 use error::InternalError;
 use kademlia_routing_table::RoutingTable;
@@ -27,28 +27,74 @@ use personas::maid_manager::MaidManager;
 
 use routing::{Authority, Data, NodeBuilder, Request, Response, XorName};
 use rust_sodium;
+use snapshot;
+use std::cell::Cell;
 use std::env;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use std::sync::mpsc::{self, Receiver};
 
 pub const CHUNK_STORE_DIR: &'static str = "safe_vault_chunk_store";
+pub const ACCOUNT_STORE_DIR: &'static str = "safe_vault_accounts";
 const DEFAULT_MAX_CAPACITY: u64 = 2 * 1024 * 1024 * 1024;
 
 pub use routing::Event;
 pub use routing::Node as RoutingNode;
 
+/// Returns the chunk store directories configured in `config`, with their capacities.
+pub fn chunk_store_roots(config: &Config) -> Vec<(PathBuf, u64)> {
+    match config.chunk_store_roots {
+        Some(ref roots) if !roots.is_empty() => {
+            roots.iter()
+                .map(|root| {
+                    (Path::new(&root.path).join(CHUNK_STORE_DIR),
+                     root.max_capacity.unwrap_or(DEFAULT_MAX_CAPACITY))
+                })
+                .collect()
+        }
+        _ => {
+            let mut chunk_store_root = match config.chunk_store_root {
+                Some(ref path_str) => Path::new(path_str).to_path_buf(),
+                None => env::temp_dir(),
+            };
+            chunk_store_root.push(CHUNK_STORE_DIR);
+            vec![(chunk_store_root, config.max_capacity.unwrap_or(DEFAULT_MAX_CAPACITY))]
+        }
+    }
+}
+
+/// Returns the directory and capacity of the hot tier chunk store, if one is configured.
+pub fn hot_tier_root(config: &Config) -> Option<(PathBuf, u64)> {
+    config.chunk_store_hot_tier.as_ref().map(|root| {
+        (Path::new(&root.path).join(CHUNK_STORE_DIR),
+         root.max_capacity.unwrap_or(DEFAULT_MAX_CAPACITY))
+    })
+}
+
+/// Returns the directories and capacities of all chunk stores, including the hot tier.
+pub fn all_chunk_store_roots(config: &Config) -> Vec<(PathBuf, u64)> {
+    chunk_store_roots(config).into_iter().chain(hot_tier_root(config)).collect()
+}
+
+/// Returns the directory of the client account store, if one is configured.
+pub fn account_store_root(config: &Config) -> Option<PathBuf> {
+    config.account_store_root.as_ref().map(|path| Path::new(path).join(ACCOUNT_STORE_DIR))
+}
+
 /// Main struct to hold all personas and Routing instance
 pub struct Vault {
     maid_manager: MaidManager,
     data_manager: DataManager,
     _routing_node: Rc<RoutingNode>,
     routing_receiver: Receiver<Event>,
+    cache_stats: Option<Rc<Cell<CacheStats>>>,
+    /// Whether the stored chunks and accounts have been announced to the network.
+    announced: bool,
 }
 
 impl Vault {
-    /// Creates a network Vault instance.
-    pub fn new(first_vault: bool, use_cache: bool) -> Result<Self, InternalError> {
+    /// Creates a network Vault instance.  Whether it caches chunks is set in the config file.
+    pub fn new(first_vault: bool) -> Result<Self, InternalError> {
         let config = match config_handler::read_config_file() {
             Ok(cfg) => cfg,
             Err(InternalError::FileHandler(e)) => {
@@ -58,6 +104,7 @@ impl Vault {
             Err(e) => return Err(From::from(e)),
         };
         let builder = RoutingNode::builder().first(first_vault).deny_other_local_nodes();
+        let use_cache = config.use_cache.unwrap_or(true);
         match Self::vault_with_config(builder, use_cache, config.clone()) {
             Ok(vault) => Ok(vault),
             Err(InternalError::ChunkStore(e)) => {
@@ -86,27 +133,25 @@ impl Vault {
                          -> Result<Self, InternalError> {
         rust_sodium::init();
 
-        let mut chunk_store_root = match config.chunk_store_root {
-            Some(path_str) => Path::new(&path_str).to_path_buf(),
-            None => env::temp_dir(),
-        };
-        chunk_store_root.push(CHUNK_STORE_DIR);
+        let chunk_store_roots = chunk_store_roots(&config);
 
         let (routing_sender, routing_receiver) = mpsc::channel();
-        let routing_node = Rc::new(if use_cache {
-            builder.cache(Box::new(Cache::new())).create(routing_sender)
+        let (routing_node, cache_stats) = if use_cache {
+            let cache = Cache::new(&config)?;
+            let cache_stats = cache.stats();
+            (builder.cache(Box::new(cache)).create(routing_sender)?, Some(cache_stats))
         } else {
-            builder.create(routing_sender)
-        }?);
+            (builder.create(routing_sender)?, None)
+        };
+        let routing_node = Rc::new(routing_node);
 
         Ok(Vault {
-            maid_manager: MaidManager::new(routing_node.clone()),
-            data_manager: DataManager::new(routing_node.clone(),
-                                           chunk_store_root,
-                                           config.max_capacity
-                                               .unwrap_or(DEFAULT_MAX_CAPACITY))?,
+            maid_manager: MaidManager::new(routing_node.clone(), &config)?,
+            data_manager: DataManager::new(routing_node.clone(), &config, chunk_store_roots)?,
             _routing_node: routing_node.clone(),
             routing_receiver: routing_receiver,
+            cache_stats: cache_stats,
+            announced: false,
         })
 
     }
@@ -122,6 +167,11 @@ impl Vault {
         Ok(true)
     }
 
+    /// Returns the statistics of the routing cache, if it is enabled.
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        self.cache_stats.as_ref().map(|cache_stats| cache_stats.get())
+    }
+
     /// Non-blocking call to process any events in the event queue, returning true if
     /// any received, otherwise returns false.
     #[cfg(feature = "use-mock-crust")]
@@ -148,6 +198,12 @@ impl Vault {
         self.maid_manager.get_put_count(client_name)
     }
 
+    /// Get the number of bytes stored and available in the account of the given client.
+    #[cfg(feature = "use-mock-crust")]
+    pub fn get_maid_manager_bytes_stored(&self, client_name: &XorName) -> Option<(u64, u64)> {
+        self.maid_manager.get_bytes_stored(client_name)
+    }
+
     /// Resend all unacknowledged messages.
     #[cfg(feature = "use-mock-crust")]
     pub fn resend_unacknowledged(&self) -> bool {
@@ -193,7 +249,18 @@ impl Vault {
                 ret = Some(true);
                 Ok(())
             }
-            Event::Connected | Event::Tick => Ok(()),
+            Event::Connected => {
+                if !self.announced {
+                    self.announced = true;
+                    self.maid_manager.handle_connected();
+                    self.data_manager.handle_connected();
+                }
+                Ok(())
+            }
+            Event::Tick => {
+                snapshot::handle_snapshot_request(&self.data_manager);
+                self.data_manager.scrub()
+            }
         } {
             debug!("Failed to handle event: {:?}", error);
         }
@@ -252,6 +319,11 @@ impl Vault {
              Request::Refresh(serialised_msg, _)) => {
                 self.maid_manager.handle_refresh(&serialised_msg)
             }
+            (Authority::ManagedNode(src_name),
+             Authority::ClientManager(client_name),
+             Request::Refresh(serialised_msg, _)) => {
+                self.maid_manager.handle_refund(src_name, client_name, &serialised_msg)
+            }
             (Authority::ManagedNode(src_name),
              Authority::ManagedNode(_),
              Request::Refresh(serialised_msg, _)) |
@@ -19,19 +19,31 @@
 // https://github.com/maidsafe/QA/blob/master/Documentation/Rust%20Lint%20Checks.md
 
 use itertools::Itertools;
+use maidsafe_utilities::serialisation;
 use rand::Rng;
 use rand::distributions::{IndependentSample, Range};
 use routing::{Data, GROUP_SIZE, ImmutableData, StructuredData, TYPE_TAG_SESSION_PACKET, XorName};
 use routing::client_errors::{GetError, MutationError};
 use routing::mock_crust::{self, Network};
-use rust_sodium::crypto::box_;
+use rust_sodium::crypto::{box_, sign};
+use safe_vault::Config;
 use safe_vault::mock_crust_detail::{self, poll, test_node};
+use safe_vault::mock_crust_detail::test_node::TestNode;
 use safe_vault::mock_crust_detail::test_client::TestClient;
 use safe_vault::test_utils;
 use std::collections::BTreeSet;
+use std::iter;
 
 const TEST_NET_SIZE: usize = 20;
 
+/// A vault config which charges `Put`s by size and synchronises the bytes stored.
+fn account_bytes_config() -> Config {
+    Config {
+        send_account_bytes: Some(true),
+        ..Config::default()
+    }
+}
+
 #[test]
 fn handle_put_without_account() {
     let network = Network::new(None);
@@ -110,7 +122,8 @@ fn put_oversized_data() {
 fn handle_put_with_account() {
     let network = Network::new(None);
     let node_count = TEST_NET_SIZE;
-    let mut nodes = test_node::create_nodes(&network, node_count, None, true);
+    let mut nodes =
+        test_node::create_nodes(&network, node_count, Some(account_bytes_config()), true);
     let config = mock_crust::Config::with_contacts(&[nodes[0].endpoint()]);
     let mut client = TestClient::new(&network, Some(config));
     let mut rng = network.new_rng();
@@ -127,7 +140,18 @@ fn handle_put_with_account() {
     assert_eq!(unwrap!(client.get_account_info_response(&mut nodes)),
                (expected_data_stored, expected_space_available));
 
+    let client_name = *client.name();
+    let bytes_stored = |nodes: &[TestNode]| {
+        nodes.iter()
+            .filter_map(|node| node.get_maid_manager_bytes_stored(&client_name))
+            .collect_vec()
+    };
+    let initial_bytes_stored = bytes_stored(&nodes);
+    assert_eq!(initial_bytes_stored.len(), GROUP_SIZE);
+
     let immutable_data = ImmutableData::new(rng.gen_iter().take(1024).collect());
+    let size = unwrap!(serialisation::serialise(&Data::Immutable(immutable_data.clone())))
+        .len() as u64;
     client.put(Data::Immutable(immutable_data.clone()));
     let event_count = poll::poll_and_resend_unacknowledged(&mut nodes, &mut client);
     trace!("Processed {} events.", event_count);
@@ -145,6 +169,12 @@ fn handle_put_with_account() {
     expected_space_available = default_account_size - expected_data_stored;
     assert_eq!(unwrap!(client.get_account_info_response(&mut nodes)),
                (expected_data_stored, expected_space_available));
+
+    // The account is charged the serialised size of the data.
+    let (initial_stored, initial_available) = initial_bytes_stored[0];
+    assert!(bytes_stored(&nodes)
+        .iter()
+        .all(|&bytes| bytes == (initial_stored + size, initial_available - size)));
 }
 
 #[test]
@@ -206,6 +236,70 @@ fn create_account_twice() {
     assert_eq!(client1.get_account_info_response(&mut nodes), acct_err);
 }
 
+#[test]
+fn refund_on_structured_data_delete() {
+    let network = Network::new(None);
+    let node_count = TEST_NET_SIZE;
+    let mut nodes =
+        test_node::create_nodes(&network, node_count, Some(account_bytes_config()), true);
+    let config = mock_crust::Config::with_contacts(&[nodes[0].endpoint()]);
+    let mut client = TestClient::new(&network, Some(config));
+    let mut rng = network.new_rng();
+
+    client.ensure_connected(&mut nodes);
+    client.create_account(&mut nodes);
+    let default_account_size = 100;
+
+    let client_name = *client.name();
+    let bytes_stored = |nodes: &[TestNode]| {
+        nodes.iter()
+            .filter_map(|node| node.get_maid_manager_bytes_stored(&client_name))
+            .collect_vec()
+    };
+    let initial_bytes_stored = bytes_stored(&nodes);
+    assert_eq!(initial_bytes_stored.len(), GROUP_SIZE);
+
+    let (pub_key, priv_key) = sign::gen_keypair();
+    let owners = iter::once(pub_key).collect::<BTreeSet<_>>();
+    let mut sd = unwrap!(StructuredData::new(100000,
+                                             rng.gen(),
+                                             0,
+                                             rng.gen_iter().take(10).collect(),
+                                             owners.clone()));
+    let _ = sd.add_signature(&(pub_key, priv_key.clone()));
+    unwrap!(client.put_and_verify(Data::Structured(sd.clone()), &mut nodes));
+    assert_eq!(unwrap!(client.get_account_info_response(&mut nodes)),
+               (2, default_account_size - 2));
+
+    // Posting a larger version is free, so it mustn't increase the refund.
+    let mut posted_sd = unwrap!(StructuredData::new(100000,
+                                                    *sd.name(),
+                                                    1,
+                                                    rng.gen_iter().take(1000).collect(),
+                                                    owners.clone()));
+    let _ = posted_sd.add_signature(&(pub_key, priv_key.clone()));
+    match client.post_response(Data::Structured(posted_sd.clone()), &mut nodes) {
+        Ok(data_id) => assert_eq!(data_id, sd.identifier()),
+        unexpected => panic!("Got unexpected response: {:?}", unexpected),
+    }
+
+    // Deleting the data refunds exactly what the Put was charged.
+    let mut deleted_sd = unwrap!(StructuredData::new(100000,
+                                                     *sd.name(),
+                                                     2,
+                                                     posted_sd.get_data().clone(),
+                                                     owners));
+    let _ = deleted_sd.add_signature(&(pub_key, priv_key));
+    match client.delete_response(Data::Structured(deleted_sd), &mut nodes) {
+        Ok(data_id) => assert_eq!(data_id, sd.identifier()),
+        unexpected => panic!("Got unexpected response: {:?}", unexpected),
+    }
+    let _ = poll::poll_and_resend_unacknowledged(&mut nodes, &mut client);
+    assert_eq!(unwrap!(client.get_account_info_response(&mut nodes)),
+               (1, default_account_size - 1));
+    assert_eq!(bytes_stored(&nodes), initial_bytes_stored);
+}
+
 #[test]
 #[should_panic] // TODO Look at using std::panic::catch_unwind (1.9)
 fn invalid_put_for_previously_created_account() {
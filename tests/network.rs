@@ -35,6 +35,7 @@ mod test {
             wallet_address: None,
             max_capacity: Some(2000),
             chunk_store_root: None,
+            ..Config::default()
         };
         // Use 8 nodes to avoid the case where four target nodes are full: In that case neither the
         // PutSuccess nor the PutFailure accumulates and client.put_and_verify() would hang.